@@ -19,7 +19,7 @@ use crate::controller::spot_balance::{
 use crate::controller::spot_position::update_spot_balances_and_cumulative_deposits;
 use crate::error::{DriftResult, ErrorCode};
 use crate::get_then_update_id;
-use crate::math::bankruptcy::is_user_bankrupt;
+use crate::math::bankruptcy::{is_isolated_perp_position_bankrupt, is_user_bankrupt};
 use crate::math::casting::Cast;
 use crate::math::constants::{
     LIQUIDATION_FEE_PRECISION_U128, LIQUIDATION_PCT_PRECISION, QUOTE_PRECISION,
@@ -128,20 +128,38 @@ pub fn liquidate_perp(
         now,
     )?;
 
+    // isolated positions are liquidated against their own collateral
+    let is_isolated_position = user
+        .get_perp_position(market_index)
+        .map_or(false, |perp_position| perp_position.is_isolated());
+
+    let margin_context = if is_isolated_position {
+        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .isolated_perp_position(market_index)
+    } else {
+        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+    }
+    .track_market_margin_requirement(MarketIdentifier::perp(market_index))?;
+
     let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
         user,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio)
-            .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
+        margin_context,
     )?;
 
-    if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
+    let is_being_liquidated = if is_isolated_position {
+        user.is_isolated_perp_position_being_liquidated(market_index)
+    } else {
+        user.is_being_liquidated()
+    };
+
+    if !is_being_liquidated && margin_calculation.meets_margin_requirement() {
         msg!("margin calculation: {:?}", margin_calculation);
         return Err(ErrorCode::SufficientCollateral);
-    } else if user.is_being_liquidated() && margin_calculation.can_exit_liquidation()? {
-        user.exit_liquidation();
+    } else if is_being_liquidated && margin_calculation.can_exit_liquidation()? {
+        exit_perp_liquidation(user, market_index, is_isolated_position);
         return Ok(());
     }
 
@@ -163,7 +181,11 @@ pub fn liquidate_perp(
             e
         })?;
 
-    let liquidation_id = user.enter_liquidation(slot)?;
+    let liquidation_id = if is_isolated_position {
+        user.enter_isolated_perp_position_liquidation(market_index, slot)?
+    } else {
+        user.enter_liquidation(slot)?
+    };
     let mut margin_freed = 0_u64;
 
     let position_index = get_position_index(&user.perp_positions, market_index)?;
@@ -174,6 +196,12 @@ pub fn liquidate_perp(
         ErrorCode::PositionDoesntHaveOpenPositionOrOrders
     )?;
 
+    let (market_type_to_cancel, market_index_to_cancel) = if is_isolated_position {
+        (Some(MarketType::Perp), Some(market_index))
+    } else {
+        (None, None)
+    };
+
     let canceled_order_ids = orders::cancel_orders(
         user,
         user_key,
@@ -184,9 +212,10 @@ pub fn liquidate_perp(
         now,
         slot,
         OrderActionExplanation::Liquidation,
+        market_type_to_cancel,
+        market_index_to_cancel,
         None,
-        None,
-        None,
+        !is_isolated_position,
     )?;
 
     let mut market = perp_market_map.get_ref_mut(&market_index)?;
@@ -240,8 +269,7 @@ pub fn liquidate_perp(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::liquidation(liquidation_margin_buffer_ratio)
-                    .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
+                margin_context,
             )?;
 
         let initial_margin_shortage = margin_calculation.margin_shortage()?;
@@ -273,7 +301,7 @@ pub fn liquidate_perp(
                 ..LiquidationRecord::default()
             });
 
-            user.exit_liquidation();
+            exit_perp_liquidation(user, market_index, is_isolated_position);
             return Ok(());
         }
 
@@ -507,15 +535,15 @@ pub fn liquidate_perp(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        margin_context,
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_for_perp_position)?;
     user.increment_margin_freed(margin_freed_for_perp_position)?;
 
     if base_asset_amount >= base_asset_amount_to_cover_margin_shortage {
-        user.exit_liquidation();
-    } else if is_user_bankrupt(user) {
+        exit_perp_liquidation(user, market_index, is_isolated_position);
+    } else if !is_isolated_position && is_user_bankrupt(user) {
         user.enter_bankruptcy();
     }
 
@@ -616,6 +644,8 @@ pub fn liquidate_perp(
         maker_order_cumulative_base_asset_amount_filled: Some(base_asset_amount),
         maker_order_cumulative_quote_asset_amount_filled: Some(base_asset_value),
         oracle_price,
+        taker_client_order_id: None,
+        maker_client_order_id: None,
     };
     emit!(fill_record);
 
@@ -860,6 +890,7 @@ pub fn liquidate_spot(
         None,
         None,
         None,
+        true,
     )?;
 
     // check if user exited liquidation territory
@@ -1121,7 +1152,7 @@ pub fn liquidate_spot(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        MarginContext::liquidation(liquidation_margin_buffer_ratio),
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -1223,13 +1254,23 @@ pub fn liquidate_borrow_for_perp_pnl(
 
     drop(liability_spot_market);
 
-    user.get_perp_position(perp_market_index).map_err(|e| {
-        msg!(
-            "User does not have a position for perp market {}",
-            perp_market_index
-        );
-        e
-    })?;
+    let is_isolated_position = user
+        .get_perp_position(perp_market_index)
+        .map_err(|e| {
+            msg!(
+                "User does not have a position for perp market {}",
+                perp_market_index
+            );
+            e
+        })?
+        .is_isolated();
+
+    validate!(
+        !is_isolated_position,
+        ErrorCode::InvalidIsolatedPerpPosition,
+        "pnl of isolated perp position in market {} cant be transferred to cross margin",
+        perp_market_index
+    )?;
 
     user.get_spot_position(liability_market_index)
         .map_err(|_| {
@@ -1394,6 +1435,7 @@ pub fn liquidate_borrow_for_perp_pnl(
         None,
         None,
         None,
+        true,
     )?;
 
     // check if user exited liquidation territory
@@ -1585,7 +1627,7 @@ pub fn liquidate_borrow_for_perp_pnl(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        MarginContext::liquidation(liquidation_margin_buffer_ratio),
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -1692,13 +1734,23 @@ pub fn liquidate_perp_pnl_for_deposit(
 
     drop(perp_market);
 
-    user.get_perp_position(perp_market_index).map_err(|e| {
-        msg!(
-            "User does not have a position for perp market {}",
-            perp_market_index
-        );
-        e
-    })?;
+    let is_isolated_position = user
+        .get_perp_position(perp_market_index)
+        .map_err(|e| {
+            msg!(
+                "User does not have a position for perp market {}",
+                perp_market_index
+            );
+            e
+        })?
+        .is_isolated();
+
+    validate!(
+        !is_isolated_position,
+        ErrorCode::InvalidIsolatedPerpPosition,
+        "pnl of isolated perp position in market {} cant be transferred to cross margin",
+        perp_market_index
+    )?;
 
     user.get_spot_position(asset_market_index).map_err(|_| {
         msg!(
@@ -1870,6 +1922,7 @@ pub fn liquidate_perp_pnl_for_deposit(
         None,
         None,
         None,
+        true,
     )?;
 
     let (safest_tier_spot_liability, safest_tier_perp_liability) =
@@ -2080,7 +2133,7 @@ pub fn liquidate_perp_pnl_for_deposit(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        liquidation_margin_buffer_ratio,
+        MarginContext::liquidation(liquidation_margin_buffer_ratio),
         margin_shortage,
     )?;
     margin_freed = margin_freed.safe_add(margin_freed_from_liability)?;
@@ -2142,15 +2195,27 @@ pub fn resolve_perp_bankruptcy(
     now: i64,
     insurance_fund_vault_balance: u64,
 ) -> DriftResult<u64> {
-    if !user.is_bankrupt() && is_user_bankrupt(user) {
-        user.enter_bankruptcy();
-    }
+    let is_isolated_position = user
+        .get_perp_position(market_index)
+        .map_or(false, |perp_position| perp_position.is_isolated());
 
-    validate!(
-        user.is_bankrupt(),
-        ErrorCode::UserNotBankrupt,
-        "user not bankrupt",
-    )?;
+    if is_isolated_position {
+        validate!(
+            is_isolated_perp_position_bankrupt(user.get_perp_position(market_index)?),
+            ErrorCode::UserNotBankrupt,
+            "isolated perp position not bankrupt",
+        )?;
+    } else {
+        if !user.is_bankrupt() && is_user_bankrupt(user) {
+            user.enter_bankruptcy();
+        }
+
+        validate!(
+            user.is_bankrupt(),
+            ErrorCode::UserNotBankrupt,
+            "user not bankrupt",
+        )?;
+    }
 
     validate!(
         !liquidator.is_being_liquidated(),
@@ -2203,7 +2268,12 @@ pub fn resolve_perp_bankruptcy(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::standard(MarginRequirementType::Maintenance),
+        if is_isolated_position {
+            MarginContext::standard(MarginRequirementType::Maintenance)
+                .isolated_perp_position(market_index)
+        } else {
+            MarginContext::standard(MarginRequirementType::Maintenance)
+        },
     )?;
 
     // spot market's insurance fund draw attempt here (before social loss)
@@ -2326,7 +2396,9 @@ pub fn resolve_perp_bankruptcy(
     }
 
     // exit bankruptcy
-    if !is_user_bankrupt(user) {
+    if is_isolated_position {
+        user.exit_isolated_perp_position_liquidation(market_index);
+    } else if !is_user_bankrupt(user) {
         user.exit_bankruptcy();
     }
 
@@ -2509,12 +2581,20 @@ pub fn resolve_spot_bankruptcy(
     if_payment.cast()
 }
 
+fn exit_perp_liquidation(user: &mut User, market_index: u16, is_isolated_position: bool) {
+    if is_isolated_position {
+        user.exit_isolated_perp_position_liquidation(market_index);
+    } else {
+        user.exit_liquidation();
+    }
+}
+
 pub fn calculate_margin_freed(
     user: &User,
     perp_market_map: &PerpMarketMap,
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
-    liquidation_margin_buffer_ratio: u32,
+    margin_context: MarginContext,
     initial_margin_shortage: u128,
 ) -> DriftResult<u64> {
     let margin_calculation_after =
@@ -2523,7 +2603,7 @@ pub fn calculate_margin_freed(
             perp_market_map,
             spot_market_map,
             oracle_map,
-            MarginContext::liquidation(liquidation_margin_buffer_ratio),
+            margin_context,
         )?;
 
     let new_margin_shortage = margin_calculation_after.margin_shortage()?;
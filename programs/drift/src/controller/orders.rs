@@ -1,4 +1,5 @@
 use std::cell::RefMut;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::ops::DerefMut;
 use std::u64;
@@ -39,8 +40,10 @@ use crate::math::fulfillment::{
 };
 use crate::math::liquidation::validate_user_not_being_liquidated;
 use crate::math::matching::{
-    are_orders_same_market_but_different_sides, calculate_fill_for_matched_orders,
-    calculate_filler_multiplier_for_matched_orders, do_orders_cross, is_maker_for_taker,
+    are_orders_same_market_but_different_sides, calculate_batch_auction_clearing_price,
+    calculate_fill_for_matched_orders, calculate_filler_multiplier_for_matched_orders,
+    calculate_pro_rata_maker_allocations, do_orders_cross, get_self_trade_prevention_mode,
+    is_maker_for_taker,
 };
 use crate::math::oracle;
 use crate::math::oracle::{is_oracle_valid_for_action, DriftAction, OracleValidity};
@@ -49,7 +52,8 @@ use crate::math::spot_balance::{get_signed_token_amount, get_token_amount};
 use crate::math::stats::calculate_new_twap;
 use crate::math::{amm, fees, margin::*, orders::*};
 use crate::state::order_params::{
-    ModifyOrderParams, ModifyOrderPolicy, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    ModifyOrderByIdParams, ModifyOrderParams, ModifyOrderPolicy, OrderParams, PlaceOrderOptions,
+    PostOnlyParam,
 };
 
 use crate::math::amm::calculate_amm_available_liquidity;
@@ -63,11 +67,11 @@ use crate::state::events::{
 use crate::state::events::{OrderAction, OrderActionExplanation};
 use crate::state::fill_mode::FillMode;
 use crate::state::fulfillment::{PerpFulfillmentMethod, SpotFulfillmentMethod};
-use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
 use crate::state::oracle::{OraclePriceData, StrictOraclePrice};
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::PerpOperation;
-use crate::state::perp_market::{AMMLiquiditySplit, MarketStatus, PerpMarket};
+use crate::state::perp_market::{AMMLiquiditySplit, MarketStatus, MatchingPolicy, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_fulfillment_params::{ExternalSpotFill, SpotFulfillmentParams};
 use crate::state::spot_market::{SpotBalanceType, SpotMarket};
@@ -76,7 +80,8 @@ use crate::state::state::FeeStructure;
 use crate::state::state::*;
 use crate::state::traits::Size;
 use crate::state::user::{
-    AssetType, Order, OrderStatus, OrderTriggerCondition, OrderType, UserStats,
+    AssetType, Order, OrderStatus, OrderTriggerCondition, OrderType, SelfTradePreventionMode,
+    TrailingStopType, UserStats,
 };
 use crate::state::user::{MarketType, User};
 use crate::state::user_map::{UserMap, UserStatsMap};
@@ -156,6 +161,16 @@ pub fn place_perp_order(
         }
     }
 
+    if let Some(client_order_id) = params.client_order_id.filter(|id| *id != 0) {
+        validate!(
+            user.get_order_index_by_client_order_id(client_order_id)
+                .is_err(),
+            ErrorCode::ClientOrderIdAlreadyInUse,
+            "client_order_id is already in use {}",
+            client_order_id
+        )?;
+    }
+
     let market_index = params.market_index;
     let market = &perp_market_map.get_ref(&market_index)?;
     let force_reduce_only = market.is_reduce_only()?;
@@ -172,9 +187,34 @@ pub fn place_perp_order(
         "Market is in settlement mode",
     )?;
 
+    validate!(
+        !user.is_market_maker_protection_cooling_down(market_index, slot),
+        ErrorCode::MarketMakerProtectionCoolingDown,
+        "Market maker protection cooling down for market {}",
+        market_index
+    )?;
+
+    validate!(
+        !user.is_isolated_perp_position_being_liquidated(market_index),
+        ErrorCode::UserIsBeingLiquidated,
+        "Isolated perp position in market {} is being liquidated",
+        market_index
+    )?;
+
     let position_index = get_position_index(&user.perp_positions, market_index)
         .or_else(|_| add_new_position(&mut user.perp_positions, market_index))?;
 
+    // close position orders are sized from the position rather than the params
+    let close_position_percentage = params.close_position_percentage.unwrap_or(0);
+    if close_position_percentage != 0 {
+        params.base_asset_amount = calculate_close_position_base_asset_amount(
+            user.perp_positions[position_index].base_asset_amount,
+            close_position_percentage,
+            market.amm.order_step_size,
+        )?;
+        params.reduce_only = true;
+    }
+
     // Increment open orders for existing position
     let (existing_position_direction, order_base_asset_amount) = {
         validate!(
@@ -210,15 +250,42 @@ pub fn place_perp_order(
 
     let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
 
-    // updates auction params for crossing limit orders w/out auction duration
-    params.update_perp_auction_params(market, oracle_price_data.price)?;
+    if market.is_batch_auction_market() {
+        // batch auctions clear resting limit orders at a single price, so orders don't get their own auction.
+        // twap slices rest at the twap's oracle offset until a batch clears them
+        validate!(
+            matches!(
+                params.order_type,
+                OrderType::Limit | OrderType::TriggerLimit | OrderType::Twap
+            ) && params.auction_duration.unwrap_or(0) == 0,
+            ErrorCode::InvalidBatchAuction,
+            "Batch auction market {} only takes limit and twap orders without an auction",
+            market_index
+        )?;
+
+        validate!(
+            params.order_type != OrderType::Twap || params.oracle_price_offset.unwrap_or(0) != 0,
+            ErrorCode::InvalidBatchAuction,
+            "Twap orders on batch auction market {} need an oracle price offset",
+            market_index
+        )?;
+    } else {
+        // updates auction params for crossing limit orders w/out auction duration
+        params.update_perp_auction_params(market, oracle_price_data.price)?;
+    }
 
-    let (auction_start_price, auction_end_price, auction_duration) = get_auction_params(
-        &params,
-        oracle_price_data,
-        market.amm.order_tick_size,
-        state.min_perp_auction_duration,
-    )?;
+    let (auction_start_price, auction_end_price, auction_duration) =
+        if market.is_batch_auction_market() && params.order_type == OrderType::Twap {
+            let oracle_price_offset = params.oracle_price_offset.unwrap_or(0).cast::<i64>()?;
+            (oracle_price_offset, oracle_price_offset, 0)
+        } else {
+            get_auction_params(
+                &params,
+                oracle_price_data,
+                market.amm.order_tick_size,
+                state.min_perp_auction_duration,
+            )?
+        };
 
     let max_ts = match params.max_ts {
         Some(max_ts) => max_ts,
@@ -235,13 +302,22 @@ pub fn place_perp_order(
         return Ok(());
     }
 
+    let time_in_force = params.get_time_in_force();
+    let max_slot = params.max_slot.unwrap_or(0);
+    if max_slot != 0 && max_slot < slot {
+        msg!("max_slot ({}) < slot ({}), skipping order", max_slot, slot);
+        return Ok(());
+    }
+
     validate!(
         params.market_type == MarketType::Perp,
         ErrorCode::InvalidOrderMarketType,
         "must be perp order"
     )?;
 
-    let new_order = Order {
+    let trigger_market = params.trigger_market.unwrap_or_default();
+
+    let mut new_order = Order {
         status: OrderStatus::Open,
         order_type: params.order_type,
         market_type: params.market_type,
@@ -269,14 +345,48 @@ pub fn place_perp_order(
         trigger_condition: params.trigger_condition,
         post_only: params.post_only != PostOnlyParam::None,
         oracle_price_offset: params.oracle_price_offset.unwrap_or(0),
-        immediate_or_cancel: params.immediate_or_cancel,
+        padding0: 0,
         auction_start_price,
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        twap_slices: params.twap_slices.unwrap_or(0),
+        twap_slice_interval: params.twap_slice_interval.unwrap_or(0),
+        group_id: options.group_id,
+        trailing_stop_offset: params.trailing_stop_offset.unwrap_or(0),
+        trailing_stop_type: params.trailing_stop_type.unwrap_or(TrailingStopType::None),
+        self_trade_prevention_mode: params
+            .self_trade_prevention_mode
+            .unwrap_or(SelfTradePreventionMode::None),
+        time_in_force,
+        close_position_percentage,
+        trigger_price_source: params.trigger_price_source.unwrap_or_default(),
+        padding1: [0; 3],
+        iceberg_display_size: params.iceberg_display_size.unwrap_or(0),
+        iceberg_reserve_base_asset_amount: 0,
+        max_slot,
+        trigger_market_index: trigger_market.market_index,
+        trigger_market_type: trigger_market.market_type,
+        has_trigger_market: params.trigger_market.is_some(),
+        max_oracle_slippage_bps: params.max_oracle_slippage_bps.unwrap_or(0),
+        padding2: [0; 2],
+        client_order_id: params.client_order_id.unwrap_or(0),
+        padding: [0; 8],
     };
 
+    // trailing stops start following the trigger price source as soon as they're placed
+    if new_order.trailing_stop_type != TrailingStopType::None {
+        update_trailing_stop_trigger_price(
+            &mut new_order,
+            get_perp_trigger_source_price(
+                new_order.trigger_price_source,
+                market,
+                oracle_price_data.price,
+            )?,
+            market.amm.order_tick_size,
+        )?;
+    }
+
     let valid_oracle_price = Some(oracle_map.get_price_data(&market.amm.oracle)?.price);
     match validate_order(&new_order, market, valid_oracle_price, slot) {
         Ok(()) => {}
@@ -296,6 +406,9 @@ pub fn place_perp_order(
         user.perp_positions[position_index].open_asks,
     )?;
 
+    // open bids/asks include the iceberg reserve, so margin covers the full order size
+    new_order.hide_iceberg_reserve()?;
+
     user.increment_open_orders(new_order.has_auction());
     user.orders[new_order_index] = new_order;
     user.perp_positions[position_index].open_orders += 1;
@@ -309,18 +422,25 @@ pub fn place_perp_order(
 
     options.update_risk_increasing(risk_increasing);
 
-    // when orders are placed in bulk, only need to check margin on last place
-    if options.enforce_margin_check {
+    // when orders are placed in bulk, only need to check margin on last place. isolated positions
+    // are checked against their own collateral so can't wait for the last place
+    let is_isolated_position = user.perp_positions[position_index].is_isolated();
+    if options.enforce_margin_check || is_isolated_position {
         meets_place_order_margin_requirement(
             user,
             perp_market_map,
             spot_market_map,
             oracle_map,
-            options.risk_increasing,
+            if is_isolated_position {
+                risk_increasing
+            } else {
+                options.risk_increasing
+            },
+            Some(market_index),
         )?;
     }
 
-    if force_reduce_only {
+    if force_reduce_only || new_order.is_close_position_order() {
         validate_order_for_force_reduce_only(
             &user.orders[new_order_index],
             user.perp_positions[position_index].base_asset_amount,
@@ -397,7 +517,7 @@ fn get_auction_params(
 ) -> DriftResult<(i64, i64, u8)> {
     if !matches!(
         params.order_type,
-        OrderType::Market | OrderType::Oracle | OrderType::Limit
+        OrderType::Market | OrderType::Oracle | OrderType::Twap | OrderType::Limit
     ) {
         return Ok((0_i64, 0_i64, 0_u8));
     }
@@ -440,8 +560,8 @@ fn get_auction_params(
             (Some(auction_start_price), Some(auction_end_price)) => {
                 (auction_start_price, auction_end_price)
             }
-            _ if params.order_type == OrderType::Oracle => {
-                msg!("Oracle order must specify auction start and end price offsets");
+            _ if matches!(params.order_type, OrderType::Oracle | OrderType::Twap) => {
+                msg!("Oracle/twap order must specify auction start and end price offsets");
                 return Err(ErrorCode::InvalidOrderAuction);
             }
             _ => calculate_auction_prices(oracle_price_data, params.direction, params.price)?,
@@ -467,6 +587,7 @@ pub fn cancel_orders(
     market_type: Option<MarketType>,
     market_index: Option<u16>,
     direction: Option<PositionDirection>,
+    skip_isolated_perp_positions: bool,
 ) -> DriftResult<Vec<u32>> {
     let mut canceled_order_ids: Vec<u32> = vec![];
     for order_index in 0..user.orders.len() {
@@ -474,6 +595,16 @@ pub fn cancel_orders(
             continue;
         }
 
+        // cross margin liquidations leave isolated positions' orders alone
+        if skip_isolated_perp_positions
+            && user.orders[order_index].market_type == MarketType::Perp
+            && user
+                .get_perp_position(user.orders[order_index].market_index)
+                .map_or(false, |perp_position| perp_position.is_isolated())
+        {
+            continue;
+        }
+
         if let (Some(market_type), Some(market_index)) = (market_type, market_index) {
             if user.orders[order_index].market_type != market_type {
                 continue;
@@ -512,6 +643,156 @@ pub fn cancel_orders(
     Ok(canceled_order_ids)
 }
 
+/// Places a batch of perp and spot orders. Margin is only checked after the last order and orders
+/// are only expired before the first
+pub fn place_orders(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    params: &[OrderParams],
+) -> DriftResult {
+    validate!(
+        params.len() <= 32,
+        ErrorCode::DefaultError,
+        "max 32 order params"
+    )?;
+
+    let num_orders = params.len();
+    for (i, params) in params.iter().enumerate() {
+        validate!(
+            !params.is_immediate_or_cancel(),
+            ErrorCode::InvalidOrderIOC,
+            "immediate_or_cancel order must be in place_and_make or place_and_take"
+        )?;
+
+        // only enforce margin on last order and only try to expire on first order
+        let options = PlaceOrderOptions {
+            enforce_margin_check: i == num_orders - 1,
+            try_expire_orders: i == 0,
+            risk_increasing: false,
+            explanation: OrderActionExplanation::None,
+            group_id: 0,
+        };
+
+        if params.market_type == MarketType::Perp {
+            place_perp_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                *params,
+                options,
+            )?;
+        } else {
+            place_spot_order(
+                state,
+                user,
+                user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock,
+                *params,
+                options,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Cancels the orders with the given ids, or every order matching the market/direction filters if
+/// there are no ids, then places the new orders. Ids that can't be found are skipped. A failure
+/// placing any new order errors before anything is saved, so quotes are never left half updated
+pub fn cancel_and_place_orders(
+    state: &State,
+    user: &mut User,
+    user_key: Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    cancel_market_type: Option<MarketType>,
+    cancel_market_index: Option<u16>,
+    cancel_direction: Option<PositionDirection>,
+    cancel_order_ids: &[u32],
+    params: &[OrderParams],
+) -> DriftResult {
+    validate!(
+        cancel_order_ids.is_empty()
+            || (cancel_market_type.is_none()
+                && cancel_market_index.is_none()
+                && cancel_direction.is_none()),
+        ErrorCode::DefaultError,
+        "cant cancel by order ids and market/direction filter at the same time"
+    )?;
+
+    if cancel_order_ids.is_empty() {
+        cancel_orders(
+            user,
+            &user_key,
+            None,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::None,
+            cancel_market_type,
+            cancel_market_index,
+            cancel_direction,
+            false,
+        )?;
+    } else {
+        for order_id in cancel_order_ids.iter() {
+            let order_index = match user.get_order_index(*order_id) {
+                Ok(order_index) => order_index,
+                Err(_) => {
+                    msg!("could not find order id {}", order_id);
+                    continue;
+                }
+            };
+
+            cancel_order(
+                order_index,
+                user,
+                &user_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                clock.unix_timestamp,
+                clock.slot,
+                OrderActionExplanation::None,
+                None,
+                0,
+                false,
+            )?;
+        }
+    }
+
+    place_orders(
+        state,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        params,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    Ok(())
+}
+
 pub fn cancel_order_by_order_id(
     order_id: u32,
     user: &AccountLoader<User>,
@@ -592,6 +873,44 @@ pub fn cancel_order_by_user_order_id(
     Ok(())
 }
 
+pub fn cancel_order_by_client_order_id(
+    client_order_id: u64,
+    user: &AccountLoader<User>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+    let order_index = match user.get_order_index_by_client_order_id(client_order_id) {
+        Ok(order_index) => order_index,
+        Err(_) => {
+            msg!("could not find client order id {}", client_order_id);
+            return Ok(());
+        }
+    };
+
+    cancel_order(
+        order_index,
+        user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    Ok(())
+}
+
 pub fn cancel_order(
     order_index: usize,
     user: &mut User,
@@ -659,8 +978,10 @@ pub fn cancel_order(
 
         // only decrease open/bids ask if it's not a trigger order or if it's been triggered
         if !user.orders[order_index].must_be_triggered() || user.orders[order_index].triggered() {
-            let base_asset_amount_unfilled =
-                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
+            // open bids/asks include the hidden reserve of iceberg orders
+            let base_asset_amount_unfilled = user.orders[order_index]
+                .get_base_asset_amount_unfilled(None)?
+                .safe_add(user.orders[order_index].iceberg_reserve_base_asset_amount)?;
             position::decrease_open_bids_and_asks(
                 &mut user.perp_positions[position_index],
                 &order_direction,
@@ -675,8 +996,10 @@ pub fn cancel_order(
 
         // only decrease open/bids ask if it's not a trigger order or if it's been triggered
         if !user.orders[order_index].must_be_triggered() || user.orders[order_index].triggered() {
-            let base_asset_amount_unfilled =
-                user.orders[order_index].get_base_asset_amount_unfilled(None)?;
+            // open bids/asks include the hidden reserve of iceberg orders
+            let base_asset_amount_unfilled = user.orders[order_index]
+                .get_base_asset_amount_unfilled(None)?
+                .safe_add(user.orders[order_index].iceberg_reserve_base_asset_amount)?;
             decrease_spot_open_bids_and_asks(
                 &mut user.spot_positions[spot_position_index],
                 &order_direction,
@@ -690,9 +1013,58 @@ pub fn cancel_order(
     Ok(())
 }
 
+pub fn cancel_order_group(
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: Option<&Pubkey>,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    explanation: OrderActionExplanation,
+    group_id: u32,
+    excluded_order_id: u32,
+) -> DriftResult<Vec<u32>> {
+    let mut canceled_order_ids: Vec<u32> = vec![];
+
+    if group_id == 0 {
+        return Ok(canceled_order_ids);
+    }
+
+    for order_index in 0..user.orders.len() {
+        let order = &user.orders[order_index];
+        if order.status != OrderStatus::Open
+            || order.group_id != group_id
+            || order.order_id == excluded_order_id
+        {
+            continue;
+        }
+
+        canceled_order_ids.push(order.order_id);
+        cancel_order(
+            order_index,
+            user,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            filler_key,
+            0,
+            false,
+        )?;
+    }
+
+    Ok(canceled_order_ids)
+}
+
 pub enum ModifyOrderId {
     UserOrderId(u8),
     OrderId(u32),
+    ClientOrderId(u64),
 }
 
 pub fn modify_order(
@@ -708,78 +1080,179 @@ pub fn modify_order(
     let user_key = user_loader.key();
     let mut user = load_mut!(user_loader)?;
 
-    let order_index = match order_id {
-        ModifyOrderId::UserOrderId(user_order_id) => {
-            match user.get_order_index_by_user_order_id(user_order_id) {
-                Ok(order_index) => order_index,
-                Err(e) => {
-                    msg!("User order id {} not found", user_order_id);
-                    if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
-                        return Err(e);
-                    } else {
-                        return Ok(());
-                    }
-                }
-            }
-        }
-        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
-            Ok(order_index) => order_index,
-            Err(e) => {
-                msg!("Order id {} not found", order_id);
-                if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
-                    return Err(e);
-                } else {
-                    return Ok(());
-                }
-            }
-        },
-    };
-
-    let existing_order = user.orders[order_index];
-
-    cancel_order(
-        order_index,
+    modify_user_order(
+        order_id,
+        modify_order_params,
         &mut user,
-        &user_key,
+        user_key,
+        state,
         perp_market_map,
         spot_market_map,
         oracle_map,
-        clock.unix_timestamp,
-        clock.slot,
-        OrderActionExplanation::None,
-        None,
-        0,
-        false,
-    )?;
-
-    user.update_last_active_slot(clock.slot);
-
-    let order_params =
-        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+        clock,
+        PlaceOrderOptions::default(),
+    )
+}
 
-    if order_params.market_type == MarketType::Perp {
-        place_perp_order(
-            state,
+pub fn modify_orders(
+    modify_orders_params: Vec<ModifyOrderByIdParams>,
+    user_loader: &AccountLoader<User>,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+) -> DriftResult {
+    let user_key = user_loader.key();
+    let mut user = load_mut!(user_loader)?;
+
+    // resolve which orders exist up front so the margin check can run on the last modified order
+    let mut orders_to_modify: Vec<ModifyOrderByIdParams> =
+        Vec::with_capacity(modify_orders_params.len());
+    for params in modify_orders_params {
+        validate!(
+            !orders_to_modify
+                .iter()
+                .any(|order_to_modify| order_to_modify.order_id == params.order_id),
+            ErrorCode::DefaultError,
+            "order id {} modified more than once",
+            params.order_id
+        )?;
+
+        if let Err(e) = user.get_order_index(params.order_id) {
+            msg!("Order id {} not found", params.order_id);
+            if params.modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
+                return Err(e);
+            }
+            continue;
+        }
+
+        orders_to_modify.push(params);
+    }
+
+    let num_orders = orders_to_modify.len();
+    for (i, params) in orders_to_modify.into_iter().enumerate() {
+        // only enforce margin on last order and only try to expire on first order
+        let options = PlaceOrderOptions {
+            enforce_margin_check: i == num_orders - 1,
+            try_expire_orders: i == 0,
+            ..PlaceOrderOptions::default()
+        };
+
+        modify_user_order(
+            ModifyOrderId::OrderId(params.order_id),
+            params.modify_order_params,
             &mut user,
             user_key,
+            state,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            options,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn modify_user_order(
+    order_id: ModifyOrderId,
+    modify_order_params: ModifyOrderParams,
+    user: &mut User,
+    user_key: Pubkey,
+    state: &State,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    options: PlaceOrderOptions,
+) -> DriftResult {
+    let order_index = match order_id {
+        ModifyOrderId::UserOrderId(user_order_id) => {
+            match user.get_order_index_by_user_order_id(user_order_id) {
+                Ok(order_index) => order_index,
+                Err(e) => {
+                    msg!("User order id {} not found", user_order_id);
+                    if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
+                        return Err(e);
+                    } else {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        ModifyOrderId::ClientOrderId(client_order_id) => {
+            match user.get_order_index_by_client_order_id(client_order_id) {
+                Ok(order_index) => order_index,
+                Err(e) => {
+                    msg!("Client order id {} not found", client_order_id);
+                    if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
+                        return Err(e);
+                    } else {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        ModifyOrderId::OrderId(order_id) => match user.get_order_index(order_id) {
+            Ok(order_index) => order_index,
+            Err(e) => {
+                msg!("Order id {} not found", order_id);
+                if modify_order_params.policy == Some(ModifyOrderPolicy::MustModify) {
+                    return Err(e);
+                } else {
+                    return Ok(());
+                }
+            }
+        },
+    };
+
+    let existing_order = user.orders[order_index];
+
+    cancel_order(
+        order_index,
+        user,
+        &user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock.unix_timestamp,
+        clock.slot,
+        OrderActionExplanation::None,
+        None,
+        0,
+        false,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    let order_params =
+        merge_modify_order_params_with_existing_order(&existing_order, &modify_order_params)?;
+
+    if order_params.market_type == MarketType::Perp {
+        place_perp_order(
+            state,
+            user,
+            user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
             clock,
             order_params,
-            PlaceOrderOptions::default(),
+            options.group_id(existing_order.group_id),
         )?;
     } else {
         place_spot_order(
             state,
-            &mut user,
+            user,
             user_key,
             perp_market_map,
             spot_market_map,
             oracle_map,
             clock,
             order_params,
-            PlaceOrderOptions::default(),
+            options,
         )?;
     }
 
@@ -796,9 +1269,11 @@ fn merge_modify_order_params_with_existing_order(
         .direction
         .unwrap_or(existing_order.direction);
     let user_order_id = existing_order.user_order_id;
-    let base_asset_amount = modify_order_params
-        .base_asset_amount
-        .unwrap_or(existing_order.get_base_asset_amount_unfilled(None)?);
+    let base_asset_amount = modify_order_params.base_asset_amount.unwrap_or(
+        existing_order
+            .get_base_asset_amount_unfilled(None)?
+            .safe_add(existing_order.iceberg_reserve_base_asset_amount)?,
+    );
     let price = modify_order_params.price.unwrap_or(existing_order.price);
     let market_index = existing_order.market_index;
     let reduce_only = modify_order_params
@@ -844,6 +1319,14 @@ fn merge_modify_order_params_with_existing_order(
             (None, None, None)
         };
 
+    // once the remaining size fits in one slice, the order no longer needs to be an iceberg
+    let iceberg_display_size =
+        if existing_order.is_iceberg() && base_asset_amount > existing_order.iceberg_display_size {
+            Some(existing_order.iceberg_display_size)
+        } else {
+            None
+        };
+
     Ok(OrderParams {
         order_type,
         market_type,
@@ -862,6 +1345,19 @@ fn merge_modify_order_params_with_existing_order(
         auction_duration,
         auction_start_price,
         auction_end_price,
+        twap_slices: Some(existing_order.twap_slices),
+        twap_slice_interval: Some(existing_order.twap_slice_interval),
+        trailing_stop_type: Some(existing_order.trailing_stop_type),
+        trailing_stop_offset: Some(existing_order.trailing_stop_offset),
+        self_trade_prevention_mode: Some(existing_order.self_trade_prevention_mode),
+        iceberg_display_size,
+        time_in_force: Some(existing_order.time_in_force),
+        max_slot: Some(existing_order.max_slot),
+        close_position_percentage: Some(existing_order.close_position_percentage),
+        trigger_price_source: Some(existing_order.trigger_price_source),
+        trigger_market: existing_order.get_trigger_market(),
+        max_oracle_slippage_bps: Some(existing_order.max_oracle_slippage_bps),
+        client_order_id: Some(existing_order.client_order_id),
     })
 }
 
@@ -895,13 +1391,15 @@ pub fn fill_perp_order(
         .position(|order| order.order_id == order_id)
         .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
 
-    let (order_status, market_index, order_market_type, order_direction) = get_struct_values!(
+    let (order_status, market_index, order_market_type, order_direction, order_group_id) = get_struct_values!(
         user.orders[order_index],
         status,
         market_index,
         market_type,
-        direction
+        direction,
+        group_id
     );
+    let order_is_fill_or_kill = user.orders[order_index].is_fill_or_kill();
 
     validate!(
         order_market_type == MarketType::Perp,
@@ -928,6 +1426,13 @@ pub fn fill_perp_order(
         "Market fills paused",
     )?;
 
+    validate!(
+        !market.is_batch_auction_market(),
+        ErrorCode::InvalidBatchAuction,
+        "Market {} only fills through batch auctions",
+        market_index
+    )?;
+
     drop(market);
 
     validate!(
@@ -961,6 +1466,11 @@ pub fn fill_perp_order(
         }
     }
 
+    if user.is_isolated_perp_position_being_liquidated(market_index) {
+        msg!("isolated perp position is being liquidated");
+        return Ok(0);
+    }
+
     let reserve_price_before: u64;
     let oracle_validity: OracleValidity;
     let oracle_price: i64;
@@ -1055,7 +1565,13 @@ pub fn fill_perp_order(
 
     validate_perp_fill_possible(state, user, order_index, slot, makers_and_referrer.0.len())?;
 
-    let should_expire_order = should_expire_order_before_fill(user, order_index, now)?;
+    let should_expire_order = should_expire_order_before_fill(user, order_index, slot, now)?;
+
+    update_close_position_order_size(
+        user,
+        order_index,
+        perp_market_map.get_ref(&market_index)?.amm.order_step_size,
+    )?;
 
     let position_index =
         get_position_index(&user.perp_positions, user.orders[order_index].market_index)?;
@@ -1105,6 +1621,17 @@ pub fn fill_perp_order(
         return Ok(0);
     }
 
+    let (
+        order_base_asset_amount,
+        order_base_asset_amount_filled_before,
+        order_iceberg_reserve_base_asset_amount,
+    ) = get_struct_values!(
+        user.orders[order_index],
+        base_asset_amount,
+        base_asset_amount_filled,
+        iceberg_reserve_base_asset_amount
+    );
+
     let (base_asset_amount, quote_asset_amount) = fulfill_perp_order(
         user,
         order_index,
@@ -1130,6 +1657,17 @@ pub fn fill_perp_order(
         fill_mode,
     )?;
 
+    // canceled orders are reset the same as filled ones, so check the fills against the order's
+    // size before fulfillment
+    validate!(
+        !order_is_fill_or_kill
+            || order_base_asset_amount_filled_before.safe_add(base_asset_amount)?
+                >= order_base_asset_amount,
+        ErrorCode::FillOrKillOrderNotFilled,
+        "fill or kill order {} was not completely filled",
+        order_id
+    )?;
+
     if base_asset_amount != 0 {
         let fill_price =
             calculate_fill_price(quote_asset_amount, base_asset_amount, BASE_PRECISION_U64)?;
@@ -1144,6 +1682,28 @@ pub fn fill_perp_order(
                 .oracle_guard_rails
                 .max_oracle_twap_5min_percent_divergence(),
         )?;
+
+        // the rest of the group stays open until the order is completely filled, including any
+        // iceberg reserve
+        let order_completely_filled = order_base_asset_amount_filled_before
+            .safe_add(base_asset_amount)?
+            >= order_base_asset_amount.safe_add(order_iceberg_reserve_base_asset_amount)?;
+
+        if order_completely_filled {
+            cancel_order_group(
+                user,
+                &user_key,
+                Some(&filler_key),
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                OrderActionExplanation::OrderGroupSiblingFilled,
+                order_group_id,
+                order_id,
+            )?;
+        }
     }
 
     let base_asset_amount_after = user.perp_positions[position_index].base_asset_amount;
@@ -1315,7 +1875,10 @@ fn get_maker_orders_info(
 
         let mut maker = load_mut!(user_account_loader)?;
 
-        if maker.is_being_liquidated() || maker.is_bankrupt() {
+        if maker.is_being_liquidated()
+            || maker.is_bankrupt()
+            || maker.is_isolated_perp_position_being_liquidated(taker_order.market_index)
+        {
             continue;
         }
 
@@ -1372,7 +1935,9 @@ fn get_maker_orders_info(
                 )?
             };
 
-            let should_expire_order = should_expire_order(&maker, maker_order_index, now)?;
+            let should_expire_order = should_expire_order(&maker, maker_order_index, slot, now)?;
+
+            update_close_position_order_size(&mut maker, maker_order_index, step_size)?;
 
             let existing_base_asset_amount = maker
                 .get_perp_position(maker.orders[maker_order_index].market_index)?
@@ -1436,6 +2001,76 @@ fn get_maker_orders_info(
     Ok(maker_orders_info)
 }
 
+/// Cancels the rest of the groups of the maker orders that were completely filled
+/// (maker key, group id, order id)
+fn cancel_filled_maker_order_groups(
+    filled_maker_order_groups: &[(Pubkey, u32, u32)],
+    makers_and_referrer: &UserMap,
+    filler_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    for (maker_key, group_id, order_id) in filled_maker_order_groups.iter() {
+        cancel_order_group(
+            &mut makers_and_referrer.get_ref_mut(maker_key)?,
+            maker_key,
+            Some(filler_key),
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::OrderGroupSiblingFilled,
+            *group_id,
+            *order_id,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Cancels the user's orders in the market once its market maker protection trips
+fn cancel_orders_for_market_maker_protection(
+    user: &mut User,
+    user_key: &Pubkey,
+    filler_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    market_index: u16,
+) -> DriftResult {
+    let canceled_order_ids = cancel_orders(
+        user,
+        user_key,
+        Some(filler_key),
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        OrderActionExplanation::MarketMakerProtectionTriggered,
+        Some(MarketType::Perp),
+        Some(market_index),
+        None,
+        false,
+    )?;
+
+    if !canceled_order_ids.is_empty() {
+        msg!(
+            "market maker protection triggered for market {}. canceled orders {:?}",
+            market_index,
+            canceled_order_ids
+        );
+    }
+
+    Ok(())
+}
+
 #[inline(always)]
 fn insert_maker_order_info(
     maker_orders_info: &mut Vec<(Pubkey, usize, u64)>,
@@ -1537,6 +2172,20 @@ fn fulfill_perp_order(
         perp_market_map.get_ref(&market_index)?.amm.order_tick_size,
     )?;
 
+    // stop filling the taker once the price moves past the order's max oracle slippage
+    let limit_price = {
+        let market = perp_market_map.get_ref(&market_index)?;
+        let oracle_price = oracle_map.get_price_data(&market.amm.oracle)?.price;
+
+        apply_max_oracle_slippage_to_limit_price(
+            limit_price,
+            user.orders[user_order_index].direction,
+            user.orders[user_order_index].max_oracle_slippage_bps,
+            oracle_price,
+            market.amm.order_tick_size,
+        )?
+    };
+
     let fulfillment_methods = {
         let market = perp_market_map.get_ref(&market_index)?;
         let oracle_price = oracle_map.get_price_data(&market.amm.oracle)?.price;
@@ -1558,14 +2207,96 @@ fn fulfill_perp_order(
         return Ok((0, 0));
     }
 
+    let (matching_policy, pro_rata_min_allocation, step_size) = {
+        let market = perp_market_map.get_ref(&market_index)?;
+        (
+            market.matching_policy,
+            market.pro_rata_min_allocation,
+            market.amm.order_step_size,
+        )
+    };
+    let mut pro_rata_price: Option<u64> = None;
+    let mut pro_rata_allocations: Vec<(Pubkey, usize, u64)> = vec![];
+
     let mut base_asset_amount = 0_u64;
     let mut quote_asset_amount = 0_u64;
     let mut maker_fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
     let maker_direction = user.orders[user_order_index].direction.opposite();
+    let user_order_post_only = user.orders[user_order_index].post_only;
+    let mut market_maker_protections_tripped: Vec<Pubkey> = vec![];
+    let mut filled_maker_order_groups: Vec<(Pubkey, u32, u32)> = vec![];
     for fulfillment_method in fulfillment_methods.iter() {
         if user.orders[user_order_index].status != OrderStatus::Open {
             break;
         }
+
+        let max_maker_base_asset_amount = match fulfillment_method {
+            PerpFulfillmentMethod::Match(maker_key, maker_order_index)
+                if matching_policy == MatchingPolicy::ProRata =>
+            {
+                let maker_order_index = *maker_order_index as usize;
+                let maker_price = maker_orders_info
+                    .iter()
+                    .find(|(key, index, _)| key == maker_key && *index == maker_order_index)
+                    .map(|(_, _, price)| *price)
+                    .safe_unwrap()?;
+
+                // split the taker's remaining size once per price level
+                if pro_rata_price != Some(maker_price) {
+                    pro_rata_price = Some(maker_price);
+                    pro_rata_allocations = get_pro_rata_maker_allocations(
+                        user,
+                        user_order_index,
+                        makers_and_referrer,
+                        maker_orders_info,
+                        maker_price,
+                        pro_rata_min_allocation,
+                        step_size,
+                        slot,
+                    )?;
+                }
+
+                Some(
+                    pro_rata_allocations
+                        .iter()
+                        .find(|(key, index, _)| key == maker_key && *index == maker_order_index)
+                        .map_or(0, |(_, _, allocation)| *allocation),
+                )
+            }
+            _ => None,
+        };
+
+        if let PerpFulfillmentMethod::Match(maker_key, maker_order_index) = fulfillment_method {
+            let mut maker = makers_and_referrer.get_ref_mut(maker_key)?;
+            if maker.orders[*maker_order_index as usize].status != OrderStatus::Open {
+                continue;
+            }
+
+            // maker tripped its market maker protection earlier in this fill
+            if market_maker_protections_tripped.contains(maker_key) {
+                continue;
+            }
+
+            let self_trade_prevented = prevent_self_trade(
+                user,
+                user_key,
+                user_order_index,
+                &mut maker,
+                maker_key,
+                *maker_order_index as usize,
+                filler_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+            )?;
+
+            if self_trade_prevented {
+                continue;
+            }
+        }
+
         let mut market = perp_market_map.get_ref_mut(&market_index)?;
         let user_order_direction = user.orders[user_order_index].direction;
 
@@ -1601,6 +2332,18 @@ fn fulfill_perp_order(
                         AMMLiquiditySplit::Shared,
                     )?;
 
+                // the amm is the taker when it fills a resting maker order
+                if user_order_post_only
+                    && fill_base_asset_amount != 0
+                    && user.record_market_maker_protection_fill(
+                        market_index,
+                        fill_base_asset_amount,
+                        slot,
+                    )?
+                {
+                    market_maker_protections_tripped.push(*user_key);
+                }
+
                 (fill_base_asset_amount, fill_quote_asset_amount)
             }
             PerpFulfillmentMethod::Match(maker_key, maker_order_index) => {
@@ -1618,6 +2361,12 @@ fn fulfill_perp_order(
                     Some(&maker),
                 )?;
 
+                let (maker_order_id, maker_order_group_id) = get_struct_values!(
+                    maker.orders[*maker_order_index as usize],
+                    order_id,
+                    group_id
+                );
+
                 let (fill_base_asset_amount, fill_quote_asset_amount, maker_fill_base_asset_amount) =
                     fulfill_perp_order_with_match(
                         market.deref_mut(),
@@ -1641,6 +2390,7 @@ fn fulfill_perp_order(
                         slot,
                         fee_structure,
                         oracle_map,
+                        max_maker_base_asset_amount,
                     )?;
 
                 if maker_fill_base_asset_amount != 0 {
@@ -1650,14 +2400,45 @@ fn fulfill_perp_order(
                         maker_direction,
                         maker_fill_base_asset_amount,
                     )?;
-                }
 
-                (fill_base_asset_amount, fill_quote_asset_amount)
-            }
-        };
+                    if maker.record_market_maker_protection_fill(
+                        market_index,
+                        maker_fill_base_asset_amount,
+                        slot,
+                    )? {
+                        market_maker_protections_tripped.push(*maker_key);
+                    }
+
+                    // completely filled maker orders are reset
+                    if maker_order_group_id != 0
+                        && maker.orders[*maker_order_index as usize].order_id != maker_order_id
+                    {
+                        filled_maker_order_groups.push((
+                            *maker_key,
+                            maker_order_group_id,
+                            maker_order_id,
+                        ));
+                    }
+                }
+
+                (fill_base_asset_amount, fill_quote_asset_amount)
+            }
+        };
 
         base_asset_amount = base_asset_amount.safe_add(fill_base_asset_amount)?;
         quote_asset_amount = quote_asset_amount.safe_add(fill_quote_asset_amount)?;
+
+        if fill_base_asset_amount != 0 {
+            market.amm.update_last_fill_price_twap(
+                calculate_fill_price(
+                    fill_quote_asset_amount,
+                    fill_base_asset_amount,
+                    BASE_PRECISION_U64,
+                )?,
+                now,
+            )?;
+        }
+
         market
             .amm
             .update_volume_24h(fill_quote_asset_amount, user_order_direction, now)?;
@@ -1691,7 +2472,8 @@ fn fulfill_perp_order(
                 MarginRequirementType::Maintenance
             } else {
                 MarginRequirementType::Fill
-            }),
+            })
+            .for_perp_position(user, market_index),
         )?;
 
     if !taker_margin_calculation.meets_margin_requirement() {
@@ -1718,7 +2500,7 @@ fn fulfill_perp_order(
                 perp_market_map,
                 spot_market_map,
                 oracle_map,
-                MarginContext::standard(margin_type),
+                MarginContext::standard(margin_type).for_perp_position(&maker, market_index),
             )?;
 
         if !maker_margin_calculation.meets_margin_requirement() {
@@ -1732,9 +2514,349 @@ fn fulfill_perp_order(
         }
     }
 
+    cancel_filled_maker_order_groups(
+        &filled_maker_order_groups,
+        makers_and_referrer,
+        filler_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+    )?;
+
+    for tripped_user_key in market_maker_protections_tripped.iter() {
+        if tripped_user_key == user_key {
+            cancel_orders_for_market_maker_protection(
+                user,
+                user_key,
+                filler_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                market_index,
+            )?;
+        } else {
+            cancel_orders_for_market_maker_protection(
+                &mut makers_and_referrer.get_ref_mut(tripped_user_key)?,
+                tripped_user_key,
+                filler_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+                market_index,
+            )?;
+        }
+    }
+
     Ok((base_asset_amount, quote_asset_amount))
 }
 
+/// Allocates the taker's remaining size across the maker orders resting at a price in proportion to
+/// their size. Makers that can't be matched with the taker are left out so their share isn't lost
+/// (maker key, maker order index, max base asset amount the maker fills)
+fn get_pro_rata_maker_allocations(
+    taker: &User,
+    taker_order_index: usize,
+    makers_and_referrer: &UserMap,
+    maker_orders_info: &[(Pubkey, usize, u64)],
+    price: u64,
+    min_allocation: u64,
+    step_size: u64,
+    slot: u64,
+) -> DriftResult<Vec<(Pubkey, usize, u64)>> {
+    let taker_order = &taker.orders[taker_order_index];
+    let market_index = taker_order.market_index;
+
+    let taker_existing_position = taker.get_perp_position(market_index)?.base_asset_amount;
+    let taker_base_asset_amount = taker_order
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?
+        .min(
+            taker_order
+                .get_twap_base_asset_amount_available(slot, step_size)?
+                .unwrap_or(u64::MAX),
+        );
+
+    let mut maker_orders = vec![];
+    let mut maker_base_asset_amounts = vec![];
+    for (maker_key, maker_order_index, maker_price) in maker_orders_info.iter() {
+        if *maker_price != price {
+            continue;
+        }
+
+        let maker = makers_and_referrer.get_ref(maker_key)?;
+        let maker_order = &maker.orders[*maker_order_index];
+
+        let can_match = maker_order.status == OrderStatus::Open
+            && !maker.is_market_maker_protection_cooling_down(market_index, slot)
+            && (maker.authority != taker.authority
+                || get_self_trade_prevention_mode(maker_order, taker_order)
+                    == SelfTradePreventionMode::None);
+
+        if !can_match {
+            continue;
+        }
+
+        let maker_existing_position = maker.get_perp_position(market_index)?.base_asset_amount;
+        maker_orders.push((*maker_key, *maker_order_index));
+        maker_base_asset_amounts
+            .push(maker_order.get_base_asset_amount_unfilled(Some(maker_existing_position))?);
+    }
+
+    let allocations = calculate_pro_rata_maker_allocations(
+        taker_base_asset_amount,
+        &maker_base_asset_amounts,
+        min_allocation,
+        step_size,
+    )?;
+
+    Ok(maker_orders
+        .into_iter()
+        .zip(allocations)
+        .map(|((maker_key, maker_order_index), allocation)| {
+            (maker_key, maker_order_index, allocation)
+        })
+        .collect())
+}
+
+/// Applies the self trade prevention mode if the taker order would match a maker order with the same
+/// authority. Returns whether the match was prevented. This lives here rather than in
+/// is_maker_for_taker since preventing the match updates the users' orders and open bids/asks
+fn prevent_self_trade(
+    taker: &mut User,
+    taker_key: &Pubkey,
+    taker_order_index: usize,
+    maker: &mut User,
+    maker_key: &Pubkey,
+    maker_order_index: usize,
+    filler_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+) -> DriftResult<bool> {
+    if taker.authority != maker.authority {
+        return Ok(false);
+    }
+
+    let self_trade_prevention_mode = get_self_trade_prevention_mode(
+        &maker.orders[maker_order_index],
+        &taker.orders[taker_order_index],
+    );
+
+    let (cancel_taker, cancel_maker, explanation) = match self_trade_prevention_mode {
+        SelfTradePreventionMode::None => return Ok(false),
+        SelfTradePreventionMode::CancelMaker => (
+            false,
+            true,
+            OrderActionExplanation::SelfTradePreventionCancelMaker,
+        ),
+        SelfTradePreventionMode::CancelTaker => (
+            true,
+            false,
+            OrderActionExplanation::SelfTradePreventionCancelTaker,
+        ),
+        SelfTradePreventionMode::CancelBoth => (
+            true,
+            true,
+            OrderActionExplanation::SelfTradePreventionCancelBoth,
+        ),
+        SelfTradePreventionMode::DecrementAndCancel => {
+            // orders are compared by their full size left, including the hidden reserve of
+            // iceberg orders and the slices twap orders haven't released yet
+            let taker_base_asset_amount_unfilled = taker.orders[taker_order_index]
+                .get_base_asset_amount_unfilled(None)?
+                .safe_add(taker.orders[taker_order_index].iceberg_reserve_base_asset_amount)?;
+            let maker_base_asset_amount_unfilled = maker.orders[maker_order_index]
+                .get_base_asset_amount_unfilled(None)?
+                .safe_add(maker.orders[maker_order_index].iceberg_reserve_base_asset_amount)?;
+
+            let (cancel_taker, cancel_maker) =
+                match taker_base_asset_amount_unfilled.cmp(&maker_base_asset_amount_unfilled) {
+                    Ordering::Greater => {
+                        decrement_order_for_self_trade(
+                            taker,
+                            taker_order_index,
+                            maker_base_asset_amount_unfilled,
+                        )?;
+                        (false, true)
+                    }
+                    Ordering::Less => {
+                        decrement_order_for_self_trade(
+                            maker,
+                            maker_order_index,
+                            taker_base_asset_amount_unfilled,
+                        )?;
+                        (true, false)
+                    }
+                    Ordering::Equal => (true, true),
+                };
+
+            (
+                cancel_taker,
+                cancel_maker,
+                OrderActionExplanation::SelfTradePreventionDecrementAndCancel,
+            )
+        }
+    };
+
+    msg!(
+        "self trade prevented. taker order {} maker order {}",
+        taker.orders[taker_order_index].order_id,
+        maker.orders[maker_order_index].order_id
+    );
+
+    if cancel_maker {
+        cancel_order(
+            maker_order_index,
+            maker,
+            maker_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            Some(filler_key),
+            0,
+            false,
+        )?;
+    }
+
+    if cancel_taker {
+        cancel_order(
+            taker_order_index,
+            taker,
+            taker_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            explanation,
+            Some(filler_key),
+            0,
+            false,
+        )?;
+    }
+
+    Ok(true)
+}
+
+/// Shrinks an order by the size of the order it would have self traded against. Iceberg orders give
+/// up their hidden reserve before their visible slice. Twap orders shrink every slice, so the
+/// slices still to come are smaller and the twap waits if its fills are ahead of the new schedule
+fn decrement_order_for_self_trade(
+    user: &mut User,
+    order_index: usize,
+    base_asset_amount: u64,
+) -> DriftResult {
+    let order = &mut user.orders[order_index];
+    let reserve_decrement = base_asset_amount.min(order.iceberg_reserve_base_asset_amount);
+    order.iceberg_reserve_base_asset_amount = order
+        .iceberg_reserve_base_asset_amount
+        .safe_sub(reserve_decrement)?;
+    order.base_asset_amount = order
+        .base_asset_amount
+        .safe_sub(base_asset_amount.safe_sub(reserve_decrement)?)?;
+
+    // untriggered orders don't count towards open bids/asks
+    if order.must_be_triggered() && !order.triggered() {
+        return Ok(());
+    }
+
+    let (market_type, market_index, direction) =
+        get_struct_values!(order, market_type, market_index, direction);
+
+    if market_type == MarketType::Perp {
+        position::decrease_open_bids_and_asks(
+            user.get_perp_position_mut(market_index)?,
+            &direction,
+            base_asset_amount,
+        )?;
+    } else {
+        let spot_position_index = user.get_spot_position_index(market_index)?;
+        decrease_spot_open_bids_and_asks(
+            &mut user.spot_positions[spot_position_index],
+            &direction,
+            base_asset_amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Resizes a close position order to the user's current position so lp settlement, liquidations
+/// and other fills since the order was placed don't leave it stale. The percentage applies to the
+/// position before the order's own fills, so partial fills don't grow the order
+fn update_close_position_order_size(
+    user: &mut User,
+    order_index: usize,
+    step_size: u64,
+) -> DriftResult {
+    let order = &user.orders[order_index];
+    if order.status != OrderStatus::Open || !order.is_close_position_order() {
+        return Ok(());
+    }
+
+    let (market_index, direction, close_position_percentage) =
+        get_struct_values!(order, market_index, direction, close_position_percentage);
+
+    let existing_base_asset_amount = user.get_perp_position(market_index)?.base_asset_amount;
+
+    // orders that no longer reduce the position get canceled as reduce only orders
+    let is_position_reducing = match direction {
+        PositionDirection::Long => existing_base_asset_amount < 0,
+        PositionDirection::Short => existing_base_asset_amount > 0,
+    };
+    if !is_position_reducing {
+        return Ok(());
+    }
+
+    let base_asset_amount_filled = order.base_asset_amount_filled;
+    let position_before_order_fills = match direction {
+        PositionDirection::Long => {
+            existing_base_asset_amount.safe_sub(base_asset_amount_filled.cast()?)?
+        }
+        PositionDirection::Short => {
+            existing_base_asset_amount.safe_add(base_asset_amount_filled.cast()?)?
+        }
+    };
+
+    let base_asset_amount_unfilled = order.get_base_asset_amount_unfilled(None)?;
+    let new_base_asset_amount = calculate_close_position_base_asset_amount(
+        position_before_order_fills,
+        close_position_percentage,
+        step_size,
+    )?
+    .max(base_asset_amount_filled);
+    let new_base_asset_amount_unfilled =
+        new_base_asset_amount.safe_sub(base_asset_amount_filled)?;
+
+    if new_base_asset_amount_unfilled == base_asset_amount_unfilled {
+        return Ok(());
+    }
+
+    let order = &mut user.orders[order_index];
+    order.base_asset_amount = new_base_asset_amount;
+
+    // untriggered orders don't count towards open bids/asks
+    if order.must_be_triggered() && !order.triggered() {
+        return Ok(());
+    }
+
+    let perp_position = user.get_perp_position_mut(market_index)?;
+    decrease_open_bids_and_asks(perp_position, &direction, base_asset_amount_unfilled)?;
+    increase_open_bids_and_asks(perp_position, &direction, new_base_asset_amount_unfilled)?;
+
+    Ok(())
+}
+
 #[allow(clippy::type_complexity)]
 fn get_referrer<'a>(
     referrer_info: &'a Option<(Pubkey, Pubkey)>,
@@ -1836,6 +2958,13 @@ pub fn fulfill_perp_order_with_amm(
                 fee_tier,
             )?;
 
+            // twap orders can only fill the slices that have been released
+            let base_asset_amount = base_asset_amount.min(
+                user.orders[order_index]
+                    .get_twap_base_asset_amount_available(slot, market.amm.order_step_size)?
+                    .unwrap_or(u64::MAX),
+            );
+
             let fill_price = if user.orders[order_index].post_only {
                 limit_price
             } else {
@@ -2035,6 +3164,7 @@ pub fn fulfill_perp_order_with_amm(
         &mut user.orders[order_index],
         base_asset_amount,
         quote_asset_amount,
+        slot,
     )?;
 
     decrease_open_bids_and_asks(
@@ -2111,6 +3241,7 @@ pub fn fulfill_perp_order_with_match(
     slot: u64,
     fee_structure: &FeeStructure,
     oracle_map: &mut OracleMap,
+    max_maker_base_asset_amount: Option<u64>,
 ) -> DriftResult<(u64, u64, u64)> {
     if !are_orders_same_market_but_different_sides(
         &maker.orders[maker_order_index],
@@ -2139,7 +3270,12 @@ pub fn fulfill_perp_order_with_match(
         .get_perp_position(market.market_index)?
         .base_asset_amount;
     let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?
+        .min(
+            taker.orders[taker_order_index]
+                .get_twap_base_asset_amount_available(slot, market.amm.order_step_size)?
+                .unwrap_or(u64::MAX),
+        );
 
     let maker_price = maker.orders[maker_order_index].force_get_limit_price(
         Some(oracle_price),
@@ -2151,8 +3287,10 @@ pub fn fulfill_perp_order_with_match(
     let maker_existing_position = maker
         .get_perp_position(market.market_index)?
         .base_asset_amount;
+    // pro rata matching caps the maker at its allocation of the price level
     let maker_base_asset_amount = maker.orders[maker_order_index]
-        .get_base_asset_amount_unfilled(Some(maker_existing_position))?;
+        .get_base_asset_amount_unfilled(Some(maker_existing_position))?
+        .min(max_maker_base_asset_amount.unwrap_or(u64::MAX));
 
     let orders_cross = do_orders_cross(maker_direction, maker_price, taker_price);
 
@@ -2233,7 +3371,12 @@ pub fn fulfill_perp_order_with_match(
         .base_asset_amount;
 
     let taker_base_asset_amount = taker.orders[taker_order_index]
-        .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
+        .get_base_asset_amount_unfilled(Some(taker_existing_position))?
+        .min(
+            taker.orders[taker_order_index]
+                .get_twap_base_asset_amount_available(slot, market.amm.order_step_size)?
+                .unwrap_or(u64::MAX),
+        );
 
     let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) =
         calculate_fill_for_matched_orders(
@@ -2410,6 +3553,7 @@ pub fn fulfill_perp_order_with_match(
         &mut taker.orders[taker_order_index],
         base_asset_amount_fulfilled_by_maker,
         quote_asset_amount,
+        slot,
     )?;
 
     decrease_open_bids_and_asks(
@@ -2422,6 +3566,7 @@ pub fn fulfill_perp_order_with_match(
         &mut maker.orders[maker_order_index],
         base_asset_amount_fulfilled_by_maker,
         quote_asset_amount,
+        slot,
     )?;
 
     decrease_open_bids_and_asks(
@@ -2480,10 +3625,884 @@ pub fn fulfill_perp_order_with_match(
     ))
 }
 
+/// An open limit order collected for a batch auction
+struct BatchAuctionOrder {
+    user_key: Pubkey,
+    authority: Pubkey,
+    order_index: usize,
+    order_id: u32,
+    direction: PositionDirection,
+    price: u64,
+    base_asset_amount: u64,
+    reduce_only: bool,
+    group_id: u32,
+    slot: u64,
+}
+
+/// A bid and ask matched at the batch auction's clearing price
+struct BatchAuctionFill {
+    bid_index: usize,
+    ask_index: usize,
+    base_asset_amount: u64,
+    quote_asset_amount: u64,
+}
+
+/// Clears a batch auction market. The crossing limit orders of the users passed in all fill at the
+/// single price that matches the most size, best price first and then oldest first.
+/// Users whose margin can't cover their fills are left out of the batch and the clearing price is
+/// recomputed without them
+pub fn clear_perp_batch_auction(
+    market_index: u16,
+    state: &State,
+    users: &UserMap,
+    users_stats: &UserStatsMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    filler_key: &Pubkey,
+    clock: &Clock,
+) -> DriftResult<u64> {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    let (oracle_price, tick_size, step_size) = {
+        let market = perp_market_map.get_ref(&market_index)?;
+
+        validate!(
+            market.is_batch_auction_market(),
+            ErrorCode::InvalidBatchAuction,
+            "Market {} is not a batch auction market",
+            market_index
+        )?;
+
+        validate!(
+            matches!(
+                market.status,
+                MarketStatus::Active | MarketStatus::ReduceOnly
+            ) && !market.is_operation_paused(PerpOperation::Fill),
+            ErrorCode::MarketFillOrderPaused,
+            "Market fills paused",
+        )?;
+
+        let next_batch_auction_slot = market.get_next_batch_auction_slot()?;
+        validate!(
+            slot >= next_batch_auction_slot,
+            ErrorCode::InvalidBatchAuction,
+            "Batch auction for market {} can't clear until slot {}",
+            market_index,
+            next_batch_auction_slot
+        )?;
+
+        let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
+            &market.amm.oracle,
+            market.amm.historical_oracle_data.last_oracle_price_twap,
+        )?;
+
+        validate!(
+            is_oracle_valid_for_action(oracle_validity, Some(DriftAction::FillOrderMatch))?,
+            ErrorCode::InvalidOracle,
+            "OracleValidity for perp marketIndex={} invalid for batch auction",
+            market_index
+        )?;
+
+        (
+            oracle_price_data.price,
+            market.amm.order_tick_size,
+            market.amm.order_step_size,
+        )
+    };
+
+    let mut bids: Vec<BatchAuctionOrder> = vec![];
+    let mut asks: Vec<BatchAuctionOrder> = vec![];
+    let mut positions: BTreeMap<Pubkey, i64> = BTreeMap::new();
+    for (user_key, user_account_loader) in users.0.iter() {
+        let mut user = load_mut!(user_account_loader)?;
+
+        if user.is_being_liquidated()
+            || user.is_bankrupt()
+            || user.is_isolated_perp_position_being_liquidated(market_index)
+        {
+            continue;
+        }
+
+        let existing_position = match user.get_perp_position(market_index) {
+            Ok(position) => position.base_asset_amount,
+            Err(_) => continue,
+        };
+
+        let mut has_batch_auction_order = false;
+        for (order_index, order) in user.orders.iter().enumerate() {
+            let is_batch_auction_order = order.status == OrderStatus::Open
+                && order.market_type == MarketType::Perp
+                && order.market_index == market_index
+                && matches!(
+                    order.order_type,
+                    OrderType::Limit | OrderType::TriggerLimit | OrderType::Twap
+                )
+                && (!order.must_be_triggered() || order.triggered());
+
+            if !is_batch_auction_order || should_expire_order(&user, order_index, slot, now)? {
+                continue;
+            }
+
+            let price = apply_max_oracle_slippage_to_limit_price(
+                order.get_limit_price(Some(oracle_price), None, slot, tick_size)?,
+                order.direction,
+                order.max_oracle_slippage_bps,
+                oracle_price,
+                tick_size,
+            )?;
+
+            let price = match price {
+                Some(price) => price,
+                None => continue,
+            };
+
+            // twaps only offer the slices released so far
+            let base_asset_amount = order
+                .get_base_asset_amount_unfilled(Some(existing_position))?
+                .min(
+                    order
+                        .get_twap_base_asset_amount_available(slot, step_size)?
+                        .unwrap_or(u64::MAX),
+                );
+            if base_asset_amount == 0 {
+                continue;
+            }
+
+            let batch_auction_order = BatchAuctionOrder {
+                user_key: *user_key,
+                authority: user.authority,
+                order_index,
+                order_id: order.order_id,
+                direction: order.direction,
+                price,
+                base_asset_amount,
+                reduce_only: order.reduce_only,
+                group_id: order.group_id,
+                slot: order.slot,
+            };
+
+            match order.direction {
+                PositionDirection::Long => bids.push(batch_auction_order),
+                PositionDirection::Short => asks.push(batch_auction_order),
+            }
+
+            has_batch_auction_order = true;
+        }
+
+        if has_batch_auction_order {
+            let mut market = perp_market_map.get_ref_mut(&market_index)?;
+            settle_funding_payment(&mut user, user_key, &mut market, now)?;
+            user.update_last_active_slot(slot);
+            positions.insert(*user_key, existing_position);
+        }
+    }
+
+    bids.sort_by(|a, b| b.price.cmp(&a.price).then(a.slot.cmp(&b.slot)));
+    asks.sort_by(|a, b| a.price.cmp(&b.price).then(a.slot.cmp(&b.slot)));
+
+    prevent_self_trades_in_batch_auction(
+        &mut bids,
+        &mut asks,
+        users,
+        filler_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        step_size,
+    )?;
+
+    // plan the fills without touching the users, dropping anyone who can't afford theirs until
+    // every remaining user passes. each pass drops at least one user so this terminates
+    let mut excluded_users: Vec<Pubkey> = vec![];
+    let planned_fills = loop {
+        let planned_fills = match plan_batch_auction_fills(
+            &bids,
+            &asks,
+            &positions,
+            &excluded_users,
+            oracle_price.unsigned_abs(),
+        )? {
+            Some(planned_fills) => planned_fills,
+            None => break None,
+        };
+
+        let users_failing_margin = get_users_failing_batch_auction_margin(
+            market_index,
+            &bids,
+            &asks,
+            &planned_fills.1,
+            users,
+            users_stats,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            &state.perp_fee_structure,
+        )?;
+
+        if users_failing_margin.is_empty() {
+            break Some(planned_fills);
+        }
+
+        excluded_users.extend(users_failing_margin);
+    };
+
+    let mut market = perp_market_map.get_ref_mut(&market_index)?;
+    market.last_batch_auction_slot = slot;
+
+    let (clearing_price, planned_fills) = match planned_fills {
+        Some(planned_fills) => planned_fills,
+        None => {
+            msg!("no crossing orders in batch auction");
+            return Ok(0);
+        }
+    };
+
+    msg!("batch auction clearing price {}", clearing_price);
+
+    let mut fills: BTreeMap<Pubkey, i64> = BTreeMap::new();
+    let mut market_maker_protections_tripped: Vec<Pubkey> = vec![];
+    let mut base_asset_amount_filled: u64 = 0;
+    for planned_fill in planned_fills.iter() {
+        let bid = &bids[planned_fill.bid_index];
+        let ask = &asks[planned_fill.ask_index];
+
+        let mut bid_user = users.get_ref_mut(&bid.user_key)?;
+        let mut bid_user_stats = users_stats.get_ref_mut(&bid_user.authority)?;
+        let mut ask_user = users.get_ref_mut(&ask.user_key)?;
+        let mut ask_user_stats = users_stats.get_ref_mut(&ask_user.authority)?;
+
+        fulfill_perp_order_with_batch_auction(
+            &mut market,
+            (
+                &mut bid_user,
+                &mut bid_user_stats,
+                &bid.user_key,
+                bid.order_index,
+            ),
+            (
+                &mut ask_user,
+                &mut ask_user_stats,
+                &ask.user_key,
+                ask.order_index,
+            ),
+            planned_fill.base_asset_amount,
+            planned_fill.quote_asset_amount,
+            &state.perp_fee_structure,
+            filler_key,
+            oracle_price,
+            now,
+            slot,
+        )?;
+
+        // every order in a batch auction is a resting order, so both sides count towards market
+        // maker protection. the batch still clears as planned and tripped users' orders are
+        // canceled afterwards
+        for (user, user_key) in [
+            (&mut bid_user, &bid.user_key),
+            (&mut ask_user, &ask.user_key),
+        ] {
+            if user.record_market_maker_protection_fill(
+                market_index,
+                planned_fill.base_asset_amount,
+                slot,
+            )? && !market_maker_protections_tripped.contains(user_key)
+            {
+                market_maker_protections_tripped.push(*user_key);
+            }
+        }
+
+        base_asset_amount_filled =
+            base_asset_amount_filled.safe_add(planned_fill.base_asset_amount)?;
+
+        update_maker_fills_map(
+            &mut fills,
+            &bid.user_key,
+            PositionDirection::Long,
+            planned_fill.base_asset_amount,
+        )?;
+        update_maker_fills_map(
+            &mut fills,
+            &ask.user_key,
+            PositionDirection::Short,
+            planned_fill.base_asset_amount,
+        )?;
+    }
+
+    if base_asset_amount_filled != 0 {
+        market
+            .amm
+            .update_last_fill_price_twap(clearing_price, now)?;
+        market.amm.last_trade_ts = now;
+    }
+
+    drop(market);
+
+    // the rest of a completely filled order's group is cancelled, same as in fill_perp_order.
+    // completely filled orders are reset, partially filled ones keep their group open
+    let mut filled_order_groups: Vec<(Pubkey, u32, u32)> = vec![];
+    for planned_fill in planned_fills.iter() {
+        for order in [&bids[planned_fill.bid_index], &asks[planned_fill.ask_index]] {
+            let order_group = (order.user_key, order.group_id, order.order_id);
+            if order.group_id == 0 || filled_order_groups.contains(&order_group) {
+                continue;
+            }
+
+            if users.get_ref(&order.user_key)?.orders[order.order_index].order_id != order.order_id
+            {
+                filled_order_groups.push(order_group);
+            }
+        }
+    }
+
+    for (user_key, group_id, order_id) in filled_order_groups {
+        let mut user = users.get_ref_mut(&user_key)?;
+        cancel_order_group(
+            &mut user,
+            &user_key,
+            Some(filler_key),
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::OrderGroupSiblingFilled,
+            group_id,
+            order_id,
+        )?;
+    }
+
+    for tripped_user_key in market_maker_protections_tripped.iter() {
+        cancel_orders_for_market_maker_protection(
+            &mut users.get_ref_mut(tripped_user_key)?,
+            tripped_user_key,
+            filler_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            market_index,
+        )?;
+    }
+
+    // users were screened against their planned fills above, so this only guards against the
+    // simulation and the fills disagreeing
+    for (user_key, base_asset_amount_filled) in fills {
+        let user = users.get_ref(&user_key)?;
+
+        let margin_type =
+            select_margin_type_for_perp_maker(&user, base_asset_amount_filled, market_index)?;
+
+        let margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::standard(margin_type).for_perp_position(&user, market_index),
+            )?;
+
+        if !margin_calculation.meets_margin_requirement() {
+            msg!(
+                "user ({}) breached fill requirements (margin requirement {}) (total_collateral {})",
+                user_key,
+                margin_calculation.margin_requirement,
+                margin_calculation.total_collateral
+            );
+            return Err(ErrorCode::InsufficientCollateral);
+        }
+    }
+
+    Ok(base_asset_amount_filled)
+}
+
+/// Applies the self trade prevention mode to crossing orders from different users with the same
+/// authority. The later order is treated as the taker, as it would be outside of a batch auction.
+/// Bids and asks must already be sorted
+fn prevent_self_trades_in_batch_auction(
+    bids: &mut [BatchAuctionOrder],
+    asks: &mut [BatchAuctionOrder],
+    users: &UserMap,
+    filler_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+    slot: u64,
+    step_size: u64,
+) -> DriftResult {
+    for bid in bids.iter_mut() {
+        for ask in asks.iter_mut() {
+            if bid.base_asset_amount == 0 || ask.price > bid.price {
+                break;
+            }
+
+            // a user's own bids and asks are never matched against each other
+            if ask.base_asset_amount == 0
+                || ask.user_key == bid.user_key
+                || ask.authority != bid.authority
+            {
+                continue;
+            }
+
+            let (taker, maker) = if bid.slot >= ask.slot {
+                (&mut *bid, &mut *ask)
+            } else {
+                (&mut *ask, &mut *bid)
+            };
+
+            let mut taker_user = users.get_ref_mut(&taker.user_key)?;
+            let mut maker_user = users.get_ref_mut(&maker.user_key)?;
+
+            let self_trade_prevented = prevent_self_trade(
+                &mut taker_user,
+                &taker.user_key,
+                taker.order_index,
+                &mut maker_user,
+                &maker.user_key,
+                maker.order_index,
+                filler_key,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                now,
+                slot,
+            )?;
+
+            if self_trade_prevented {
+                taker.base_asset_amount = get_batch_auction_order_base_asset_amount_left(
+                    &taker_user,
+                    taker,
+                    slot,
+                    step_size,
+                )?;
+                maker.base_asset_amount = get_batch_auction_order_base_asset_amount_left(
+                    &maker_user,
+                    maker,
+                    slot,
+                    step_size,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_batch_auction_order_base_asset_amount_left(
+    user: &User,
+    order: &BatchAuctionOrder,
+    slot: u64,
+    step_size: u64,
+) -> DriftResult<u64> {
+    let user_order = &user.orders[order.order_index];
+    if user_order.status != OrderStatus::Open || user_order.order_id != order.order_id {
+        return Ok(0);
+    }
+
+    // a decremented twap releases less
+    let twap_base_asset_amount_available = user_order
+        .get_twap_base_asset_amount_available(slot, step_size)?
+        .unwrap_or(u64::MAX);
+
+    Ok(order
+        .base_asset_amount
+        .min(user_order.get_base_asset_amount_unfilled(None)?)
+        .min(twap_base_asset_amount_available))
+}
+
+/// Matches the crossing orders at the clearing price without modifying any user. Reduce only
+/// orders are sized against the position left after the user's earlier fills in the batch and
+/// once an order in a group fills, the rest of the group is left out
+fn plan_batch_auction_fills(
+    bids: &[BatchAuctionOrder],
+    asks: &[BatchAuctionOrder],
+    positions: &BTreeMap<Pubkey, i64>,
+    excluded_users: &[Pubkey],
+    oracle_price: u64,
+) -> DriftResult<Option<(u64, Vec<BatchAuctionFill>)>> {
+    let mut bids_base_asset_amount: Vec<u64> = bids
+        .iter()
+        .map(|bid| {
+            if excluded_users.contains(&bid.user_key) {
+                0
+            } else {
+                bid.base_asset_amount
+            }
+        })
+        .collect();
+
+    let mut asks_base_asset_amount: Vec<u64> = asks
+        .iter()
+        .map(|ask| {
+            if excluded_users.contains(&ask.user_key) {
+                0
+            } else {
+                ask.base_asset_amount
+            }
+        })
+        .collect();
+
+    let clearing_price_and_base_asset_amount = calculate_batch_auction_clearing_price(
+        &bids
+            .iter()
+            .zip(bids_base_asset_amount.iter())
+            .map(|(bid, base_asset_amount)| (bid.price, *base_asset_amount))
+            .collect::<Vec<_>>(),
+        &asks
+            .iter()
+            .zip(asks_base_asset_amount.iter())
+            .map(|(ask, base_asset_amount)| (ask.price, *base_asset_amount))
+            .collect::<Vec<_>>(),
+        oracle_price,
+    )?;
+
+    let (clearing_price, clearing_base_asset_amount) = match clearing_price_and_base_asset_amount {
+        Some(clearing_price_and_base_asset_amount) => clearing_price_and_base_asset_amount,
+        None => return Ok(None),
+    };
+
+    let mut positions = positions.clone();
+    let mut filled_order_groups: BTreeMap<(Pubkey, u32), u32> = BTreeMap::new();
+    let mut fills: Vec<BatchAuctionFill> = vec![];
+    let mut base_asset_amount_remaining = clearing_base_asset_amount;
+    for (bid_index, bid) in bids.iter().enumerate() {
+        if bid.price < clearing_price || base_asset_amount_remaining == 0 {
+            break;
+        }
+
+        for (ask_index, ask) in asks.iter().enumerate() {
+            if ask.price > clearing_price || base_asset_amount_remaining == 0 {
+                break;
+            }
+
+            let bid_base_asset_amount = get_batch_auction_order_base_asset_amount_fillable(
+                bid,
+                bids_base_asset_amount[bid_index],
+                &positions,
+                &filled_order_groups,
+            )?;
+
+            if bid_base_asset_amount == 0 {
+                break;
+            }
+
+            let ask_base_asset_amount = get_batch_auction_order_base_asset_amount_fillable(
+                ask,
+                asks_base_asset_amount[ask_index],
+                &positions,
+                &filled_order_groups,
+            )?;
+
+            // users can't fill against themselves
+            if ask_base_asset_amount == 0 || ask.user_key == bid.user_key {
+                continue;
+            }
+
+            let (base_asset_amount, quote_asset_amount) = calculate_fill_for_matched_orders(
+                bid_base_asset_amount.min(base_asset_amount_remaining),
+                clearing_price,
+                ask_base_asset_amount,
+                PERP_DECIMALS,
+                PositionDirection::Long,
+            )?;
+
+            if base_asset_amount == 0 {
+                continue;
+            }
+
+            bids_base_asset_amount[bid_index] =
+                bids_base_asset_amount[bid_index].safe_sub(base_asset_amount)?;
+            asks_base_asset_amount[ask_index] =
+                asks_base_asset_amount[ask_index].safe_sub(base_asset_amount)?;
+            base_asset_amount_remaining =
+                base_asset_amount_remaining.safe_sub(base_asset_amount)?;
+
+            for (order, delta) in [
+                (bid, base_asset_amount.cast::<i64>()?),
+                (ask, -base_asset_amount.cast::<i64>()?),
+            ] {
+                let position = positions.entry(order.user_key).or_insert(0);
+                *position = position.safe_add(delta)?;
+
+                if order.group_id != 0 {
+                    filled_order_groups.insert((order.user_key, order.group_id), order.order_id);
+                }
+            }
+
+            fills.push(BatchAuctionFill {
+                bid_index,
+                ask_index,
+                base_asset_amount,
+                quote_asset_amount,
+            });
+        }
+    }
+
+    Ok(Some((clearing_price, fills)))
+}
+
+fn get_batch_auction_order_base_asset_amount_fillable(
+    order: &BatchAuctionOrder,
+    base_asset_amount: u64,
+    positions: &BTreeMap<Pubkey, i64>,
+    filled_order_groups: &BTreeMap<(Pubkey, u32), u32>,
+) -> DriftResult<u64> {
+    if base_asset_amount == 0 {
+        return Ok(0);
+    }
+
+    // another order in the group already filled
+    if order.group_id != 0
+        && filled_order_groups
+            .get(&(order.user_key, order.group_id))
+            .map_or(false, |order_id| *order_id != order.order_id)
+    {
+        return Ok(0);
+    }
+
+    if !order.reduce_only {
+        return Ok(base_asset_amount);
+    }
+
+    let position = positions.get(&order.user_key).copied().unwrap_or(0);
+    let base_asset_amount_reducible = match order.direction {
+        PositionDirection::Long if position < 0 => position.unsigned_abs(),
+        PositionDirection::Short if position > 0 => position.unsigned_abs(),
+        _ => 0,
+    };
+
+    Ok(base_asset_amount.min(base_asset_amount_reducible))
+}
+
+/// Applies each user's planned fills and fees to a copy of the user and returns the users that
+/// wouldn't meet their fill margin requirement afterwards
+fn get_users_failing_batch_auction_margin(
+    market_index: u16,
+    bids: &[BatchAuctionOrder],
+    asks: &[BatchAuctionOrder],
+    planned_fills: &[BatchAuctionFill],
+    users: &UserMap,
+    users_stats: &UserStatsMap,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    fee_structure: &FeeStructure,
+) -> DriftResult<Vec<Pubkey>> {
+    let mut user_fills: BTreeMap<Pubkey, Vec<(PositionDirection, u64, u64)>> = BTreeMap::new();
+    for planned_fill in planned_fills.iter() {
+        for order in [&bids[planned_fill.bid_index], &asks[planned_fill.ask_index]] {
+            user_fills.entry(order.user_key).or_default().push((
+                order.direction,
+                planned_fill.base_asset_amount,
+                planned_fill.quote_asset_amount,
+            ));
+        }
+    }
+
+    let mut users_failing_margin: Vec<Pubkey> = vec![];
+    for (user_key, user_fills) in user_fills {
+        let mut simulated_user = Box::new(*users.get_ref(&user_key)?);
+        let user_stats = users_stats.get_ref(&simulated_user.authority)?;
+        let mut market = *perp_market_map.get_ref(&market_index)?;
+        let position_index = get_position_index(&simulated_user.perp_positions, market_index)?;
+
+        let mut base_asset_amount_filled: i64 = 0;
+        for (direction, base_asset_amount, quote_asset_amount) in user_fills {
+            let position_delta =
+                get_position_delta_for_fill(base_asset_amount, quote_asset_amount, direction)?;
+
+            update_position_and_market(
+                &mut simulated_user.perp_positions[position_index],
+                &mut market,
+                &position_delta,
+            )?;
+
+            let fee = fees::calculate_fee_for_fulfillment_with_batch_auction(
+                &user_stats,
+                quote_asset_amount,
+                fee_structure,
+                market.fee_adjustment,
+            )?;
+
+            controller::position::update_quote_asset_and_break_even_amount(
+                &mut simulated_user.perp_positions[position_index],
+                &mut market,
+                -fee.cast()?,
+            )?;
+
+            decrease_open_bids_and_asks(
+                &mut simulated_user.perp_positions[position_index],
+                &direction,
+                base_asset_amount,
+            )?;
+
+            base_asset_amount_filled = match direction {
+                PositionDirection::Long => {
+                    base_asset_amount_filled.safe_add(base_asset_amount.cast()?)?
+                }
+                PositionDirection::Short => {
+                    base_asset_amount_filled.safe_sub(base_asset_amount.cast()?)?
+                }
+            };
+        }
+
+        let margin_type = select_margin_type_for_perp_maker(
+            &simulated_user,
+            base_asset_amount_filled,
+            market_index,
+        )?;
+
+        let margin_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &simulated_user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::standard(margin_type)
+                    .for_perp_position(&simulated_user, market_index),
+            )?;
+
+        if !margin_calculation.meets_margin_requirement() {
+            msg!(
+                "user ({}) left out of batch auction, fills would breach margin requirement {} with total_collateral {}",
+                user_key,
+                margin_calculation.margin_requirement,
+                margin_calculation.total_collateral
+            );
+            users_failing_margin.push(user_key);
+        }
+    }
+
+    Ok(users_failing_margin)
+}
+
+/// Fills a bid against an ask at the batch auction's clearing price. Both sides pay the taker fee
+fn fulfill_perp_order_with_batch_auction(
+    market: &mut PerpMarket,
+    bid: (&mut User, &mut UserStats, &Pubkey, usize),
+    ask: (&mut User, &mut UserStats, &Pubkey, usize),
+    base_asset_amount: u64,
+    quote_asset_amount: u64,
+    fee_structure: &FeeStructure,
+    filler_key: &Pubkey,
+    oracle_price: i64,
+    now: i64,
+    slot: u64,
+) -> DriftResult {
+    let (bid_user, bid_user_stats, bid_user_key, bid_order_index) = bid;
+    let (ask_user, ask_user_stats, ask_user_key, ask_order_index) = ask;
+
+    let mut fees = [0_u64; 2];
+    for (fee, (user, user_stats, order_index)) in fees.iter_mut().zip([
+        (&mut *bid_user, &mut *bid_user_stats, bid_order_index),
+        (&mut *ask_user, &mut *ask_user_stats, ask_order_index),
+    ]) {
+        let direction = user.orders[order_index].direction;
+        let position_index = get_position_index(&user.perp_positions, market.market_index)?;
+
+        let position_delta =
+            get_position_delta_for_fill(base_asset_amount, quote_asset_amount, direction)?;
+
+        update_position_and_market(
+            &mut user.perp_positions[position_index],
+            market,
+            &position_delta,
+        )?;
+
+        *fee = fees::calculate_fee_for_fulfillment_with_batch_auction(
+            user_stats,
+            quote_asset_amount,
+            fee_structure,
+            market.fee_adjustment,
+        )?;
+
+        controller::position::update_quote_asset_and_break_even_amount(
+            &mut user.perp_positions[position_index],
+            market,
+            -fee.cast()?,
+        )?;
+
+        user_stats.update_taker_volume_30d(quote_asset_amount, now)?;
+        user_stats.increment_total_fees(*fee)?;
+
+        update_order_after_fill(
+            &mut user.orders[order_index],
+            base_asset_amount,
+            quote_asset_amount,
+            slot,
+        )?;
+
+        decrease_open_bids_and_asks(
+            &mut user.perp_positions[position_index],
+            &direction,
+            base_asset_amount,
+        )?;
+    }
+
+    let [bid_fee, ask_fee] = fees;
+    let fee_to_market = bid_fee.safe_add(ask_fee)?;
+
+    market.amm.total_fee = market.amm.total_fee.safe_add(fee_to_market.cast()?)?;
+    market.amm.total_exchange_fee = market
+        .amm
+        .total_exchange_fee
+        .safe_add(fee_to_market.cast()?)?;
+    market.amm.total_fee_minus_distributions = market
+        .amm
+        .total_fee_minus_distributions
+        .safe_add(fee_to_market.cast()?)?;
+    market.amm.net_revenue_since_last_funding = market
+        .amm
+        .net_revenue_since_last_funding
+        .safe_add(fee_to_market.cast()?)?;
+
+    let fill_record_id = get_then_update_id!(market, next_fill_record_id);
+    let mut order_action_record = get_order_action_record(
+        now,
+        OrderAction::Fill,
+        OrderActionExplanation::OrderFilledWithBatchAuction,
+        market.market_index,
+        Some(*filler_key),
+        Some(fill_record_id),
+        None,
+        Some(base_asset_amount),
+        Some(quote_asset_amount),
+        Some(bid_fee),
+        None,
+        None,
+        None,
+        None,
+        Some(*bid_user_key),
+        Some(bid_user.orders[bid_order_index]),
+        Some(*ask_user_key),
+        Some(ask_user.orders[ask_order_index]),
+        oracle_price,
+    )?;
+    order_action_record.maker_fee = Some(ask_fee.cast()?);
+    emit_stack::<_, { OrderActionRecord::SIZE }>(order_action_record)?;
+
+    for (user, order_index) in [(bid_user, bid_order_index), (ask_user, ask_order_index)] {
+        if user.orders[order_index].get_base_asset_amount_unfilled(None)? == 0 {
+            user.decrement_open_orders(user.orders[order_index].has_auction());
+            user.orders[order_index] = Order::default();
+            let position_index = get_position_index(&user.perp_positions, market.market_index)?;
+            user.perp_positions[position_index].open_orders -= 1;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn update_order_after_fill(
     order: &mut Order,
     base_asset_amount: u64,
     quote_asset_amount: u64,
+    slot: u64,
 ) -> DriftResult {
     order.base_asset_amount_filled = order.base_asset_amount_filled.safe_add(base_asset_amount)?;
 
@@ -2491,6 +4510,9 @@ pub fn update_order_after_fill(
         .quote_asset_amount_filled
         .safe_add(quote_asset_amount)?;
 
+    // iceberg orders refresh from the reserve once the visible slice is filled
+    order.release_iceberg_slice(slot)?;
+
     if order.get_base_asset_amount_unfilled(None)? == 0 {
         order.status = OrderStatus::Filled;
     }
@@ -2533,8 +4555,13 @@ pub fn trigger_order(
         .position(|order| order.order_id == order_id)
         .ok_or_else(print_error!(ErrorCode::OrderDoesNotExist))?;
 
-    let (order_status, market_index, market_type) =
-        get_struct_values!(user.orders[order_index], status, market_index, market_type);
+    let (order_status, market_index, market_type, group_id) = get_struct_values!(
+        user.orders[order_index],
+        status,
+        market_index,
+        market_type,
+        group_id
+    );
 
     validate!(
         order_status == OrderStatus::Open,
@@ -2570,6 +4597,17 @@ pub fn trigger_order(
 
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
+    // cross market trigger orders check their trigger condition against another market's oracle
+    let trigger_market_oracle_price = match user.orders[order_index].get_trigger_market() {
+        Some(trigger_market) => Some(get_trigger_market_oracle_price(
+            trigger_market,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+        )?),
+        None => None,
+    };
+
     let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
     let oracle_price_data = &oracle_map.get_price_data(&perp_market.amm.oracle)?;
 
@@ -2587,11 +4625,33 @@ pub fn trigger_order(
     validate!(is_oracle_valid, ErrorCode::InvalidOracle)?;
 
     let oracle_price = oracle_price_data.price;
+    let trigger_source_price = match trigger_market_oracle_price {
+        Some(trigger_market_oracle_price) => trigger_market_oracle_price,
+        None => get_perp_trigger_source_price(
+            user.orders[order_index].trigger_price_source,
+            &perp_market,
+            oracle_price,
+        )?,
+    };
 
-    let can_trigger = order_satisfies_trigger_condition(
-        &user.orders[order_index],
-        oracle_price.unsigned_abs().cast()?,
+    // trailing stops ratchet their trigger price towards the trigger price source before checking the trigger condition
+    let trigger_price_updated = update_trailing_stop_trigger_price(
+        &mut user.orders[order_index],
+        trigger_source_price,
+        perp_market.amm.order_tick_size,
     )?;
+
+    let can_trigger =
+        order_satisfies_trigger_condition(&user.orders[order_index], trigger_source_price)?;
+
+    if !can_trigger && trigger_price_updated {
+        msg!(
+            "trailing stop trigger price updated to {}",
+            user.orders[order_index].trigger_price
+        );
+        return Ok(());
+    }
+
     validate!(can_trigger, ErrorCode::OrderDidNotSatisfyTriggerCondition)?;
 
     let worst_case_base_asset_amount_before = user
@@ -2668,7 +4728,15 @@ pub fn trigger_order(
     // If order increases risk and user is below initial margin, cancel it
     if is_risk_increasing && !user.orders[order_index].reduce_only {
         let meets_initial_margin_requirement =
-            meets_initial_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial)
+                    .for_perp_position(user, market_index),
+            )?
+            .meets_margin_requirement();
 
         if !meets_initial_margin_requirement {
             cancel_order(
@@ -2688,6 +4756,176 @@ pub fn trigger_order(
         }
     }
 
+    // only cancel the rest of the group if the triggered order is still live
+    if user.orders[order_index].status == OrderStatus::Open {
+        cancel_order_group(
+            user,
+            &user_key,
+            Some(&filler_key),
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            now,
+            slot,
+            OrderActionExplanation::OrderGroupSiblingTriggered,
+            group_id,
+            order_id,
+        )?;
+    }
+
+    user.update_last_active_slot(slot);
+
+    Ok(())
+}
+
+/// The oracle price a cross market trigger order checks its trigger condition against
+fn get_trigger_market_oracle_price(
+    trigger_market: MarketIdentifier,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<u64> {
+    let (oracle_price, oracle_validity) = match trigger_market.market_type {
+        MarketType::Perp => {
+            let perp_market = perp_market_map.get_ref(&trigger_market.market_index)?;
+            let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
+                &perp_market.amm.oracle,
+                perp_market
+                    .amm
+                    .historical_oracle_data
+                    .last_oracle_price_twap,
+            )?;
+            (oracle_price_data.price, oracle_validity)
+        }
+        MarketType::Spot => {
+            let spot_market = spot_market_map.get_ref(&trigger_market.market_index)?;
+            let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
+                &spot_market.oracle,
+                spot_market.historical_oracle_data.last_oracle_price_twap,
+            )?;
+            (oracle_price_data.price, oracle_validity)
+        }
+    };
+
+    validate!(
+        is_oracle_valid_for_action(oracle_validity, Some(DriftAction::TriggerOrder))?,
+        ErrorCode::InvalidOracle,
+        "OracleValidity for trigger {:?} marketIndex={} invalid for TriggerOrder",
+        trigger_market.market_type,
+        trigger_market.market_index
+    )?;
+
+    Ok(oracle_price.unsigned_abs())
+}
+
+pub fn trigger_position_take_profit_stop_loss(
+    market_index: u16,
+    state: &State,
+    user: &AccountLoader<User>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    clock: &Clock,
+) -> DriftResult {
+    let slot = clock.slot;
+
+    let filler_key = filler.key();
+    let user_key = user.key();
+    let user = &mut load_mut!(user)?;
+
+    validate_user_not_being_liquidated(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        state.liquidation_margin_buffer_ratio,
+    )?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+
+    validate!(
+        user.perp_positions[position_index].has_take_profit_stop_loss(),
+        ErrorCode::InvalidPositionTakeProfitStopLoss,
+        "Position has no take profit or stop loss"
+    )?;
+
+    let mut perp_market = perp_market_map.get_ref_mut(&market_index)?;
+    let oracle_price_data = &oracle_map.get_price_data(&perp_market.amm.oracle)?;
+
+    let oracle_validity = oracle::oracle_validity(
+        perp_market
+            .amm
+            .historical_oracle_data
+            .last_oracle_price_twap,
+        oracle_price_data,
+        &state.oracle_guard_rails.validity,
+    )?;
+    let is_oracle_valid =
+        is_oracle_valid_for_action(oracle_validity, Some(DriftAction::TriggerOrder))?;
+
+    validate!(is_oracle_valid, ErrorCode::InvalidOracle)?;
+
+    let oracle_price = oracle_price_data.price.unsigned_abs();
+
+    let position = &mut user.perp_positions[position_index];
+    let explanation = if position.is_take_profit_triggered(oracle_price) {
+        OrderActionExplanation::PositionTakeProfitTriggered
+    } else if position.is_stop_loss_triggered(oracle_price) {
+        OrderActionExplanation::PositionStopLossTriggered
+    } else {
+        msg!(
+            "oracle price {} hasn't reached take profit {} or stop loss {}",
+            oracle_price,
+            position.take_profit_price,
+            position.stop_loss_price
+        );
+        return Err(ErrorCode::OrderDidNotSatisfyTriggerCondition);
+    };
+
+    position.clear_take_profit_stop_loss();
+
+    // the order sizes itself to the whole position when it fills
+    let params = OrderParams {
+        close_position_percentage: Some(100),
+        ..OrderParams::get_close_perp_params(
+            &perp_market,
+            position.get_direction_to_close(),
+            position.base_asset_amount.unsigned_abs(),
+        )?
+    };
+
+    let is_filler_taker = user_key == filler_key;
+    let mut filler = if !is_filler_taker {
+        Some(load_mut!(filler)?)
+    } else {
+        None
+    };
+
+    pay_keeper_flat_reward_for_perps(
+        user,
+        filler.as_deref_mut(),
+        &mut perp_market,
+        state.perp_fee_structure.flat_filler_fee,
+        slot,
+    )?;
+
+    drop(perp_market);
+
+    place_perp_order(
+        state,
+        user,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        params,
+        PlaceOrderOptions::default().explanation(explanation),
+    )?;
+
     user.update_last_active_slot(slot);
 
     Ok(())
@@ -2846,6 +5084,63 @@ pub fn force_cancel_orders(
     Ok(())
 }
 
+pub fn cancel_orders_on_missed_heartbeat(
+    state: &State,
+    user_account_loader: &AccountLoader<User>,
+    spot_market_map: &SpotMarketMap,
+    perp_market_map: &PerpMarketMap,
+    oracle_map: &mut OracleMap,
+    filler: &AccountLoader<User>,
+    clock: &Clock,
+) -> DriftResult {
+    let now = clock.unix_timestamp;
+    let slot = clock.slot;
+
+    let filler_key = filler.key();
+    let user_key = user_account_loader.key();
+    let user = &mut load_mut!(user_account_loader)?;
+    let filler = &mut load_mut!(filler)?;
+
+    validate!(
+        user.has_missed_heartbeat(slot),
+        ErrorCode::HeartbeatNotMissed,
+        "cancel after slot ({}) has not passed (slot {})",
+        user.cancel_after_slot,
+        slot
+    )?;
+
+    let canceled_order_ids = cancel_orders(
+        user,
+        &user_key,
+        Some(&filler_key),
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        now,
+        slot,
+        OrderActionExplanation::MissedHeartbeat,
+        None,
+        None,
+        None,
+        false,
+    )?;
+
+    // the switch only fires once, the user has to send a new heartbeat to re-arm it
+    user.cancel_after_slot = 0;
+
+    if !canceled_order_ids.is_empty() {
+        pay_keeper_flat_reward_for_spot(
+            user,
+            Some(filler),
+            spot_market_map.get_quote_spot_market_mut()?.deref_mut(),
+            state.spot_fee_structure.flat_filler_fee,
+            slot,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn can_reward_user_with_perp_pnl(user: &mut Option<&mut User>, market_index: u16) -> bool {
     match user.as_mut() {
         Some(user) => user.force_get_perp_position_mut(market_index).is_ok(),
@@ -3104,6 +5399,13 @@ pub fn place_spot_order(
         return Ok(());
     }
 
+    let time_in_force = params.get_time_in_force();
+    let max_slot = params.max_slot.unwrap_or(0);
+    if max_slot != 0 && max_slot < slot {
+        msg!("max_slot ({}) < slot ({}), skipping order", max_slot, slot);
+        return Ok(());
+    }
+
     let new_order_index = user
         .orders
         .iter()
@@ -3122,6 +5424,16 @@ pub fn place_spot_order(
         }
     }
 
+    if let Some(client_order_id) = params.client_order_id.filter(|id| *id != 0) {
+        validate!(
+            user.get_order_index_by_client_order_id(client_order_id)
+                .is_err(),
+            ErrorCode::ClientOrderIdAlreadyInUse,
+            "client_order_id is already in use {}",
+            client_order_id
+        )?;
+    }
+
     let market_index = params.market_index;
     let spot_market = &spot_market_map.get_ref(&market_index)?;
     let force_reduce_only = spot_market.is_reduce_only();
@@ -3206,7 +5518,9 @@ pub fn place_spot_order(
         "must be spot order"
     )?;
 
-    let new_order = Order {
+    let trigger_market = params.trigger_market.unwrap_or_default();
+
+    let mut new_order = Order {
         status: OrderStatus::Open,
         order_type: params.order_type,
         market_type: params.market_type,
@@ -3229,14 +5543,48 @@ pub fn place_spot_order(
         trigger_condition: params.trigger_condition,
         post_only: params.post_only != PostOnlyParam::None,
         oracle_price_offset: params.oracle_price_offset.unwrap_or(0),
-        immediate_or_cancel: params.immediate_or_cancel,
+        padding0: 0,
         auction_start_price,
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        twap_slices: params.twap_slices.unwrap_or(0),
+        twap_slice_interval: params.twap_slice_interval.unwrap_or(0),
+        group_id: 0,
+        trailing_stop_offset: params.trailing_stop_offset.unwrap_or(0),
+        trailing_stop_type: params.trailing_stop_type.unwrap_or(TrailingStopType::None),
+        self_trade_prevention_mode: params
+            .self_trade_prevention_mode
+            .unwrap_or(SelfTradePreventionMode::None),
+        time_in_force,
+        close_position_percentage: params.close_position_percentage.unwrap_or(0),
+        trigger_price_source: params.trigger_price_source.unwrap_or_default(),
+        padding1: [0; 3],
+        iceberg_display_size: params.iceberg_display_size.unwrap_or(0),
+        iceberg_reserve_base_asset_amount: 0,
+        max_slot,
+        trigger_market_index: trigger_market.market_index,
+        trigger_market_type: trigger_market.market_type,
+        has_trigger_market: params.trigger_market.is_some(),
+        max_oracle_slippage_bps: params.max_oracle_slippage_bps.unwrap_or(0),
+        padding2: [0; 2],
+        client_order_id: params.client_order_id.unwrap_or(0),
+        padding: [0; 8],
     };
 
+    // trailing stops start following the trigger price source as soon as they're placed
+    if new_order.trailing_stop_type != TrailingStopType::None {
+        update_trailing_stop_trigger_price(
+            &mut new_order,
+            get_spot_trigger_source_price(
+                new_order.trigger_price_source,
+                spot_market,
+                oracle_price_data.price,
+            )?,
+            spot_market.order_tick_size,
+        )?;
+    }
+
     validate_spot_order(
         &new_order,
         spot_market.order_step_size,
@@ -3250,6 +5598,9 @@ pub fn place_spot_order(
         user.spot_positions[spot_position_index].open_asks,
     )?;
 
+    // open bids/asks include the iceberg reserve, so margin covers the full order size
+    new_order.hide_iceberg_reserve()?;
+
     user.increment_open_orders(new_order.has_auction());
     user.orders[new_order_index] = new_order;
     user.spot_positions[spot_position_index].open_orders += 1;
@@ -3270,6 +5621,7 @@ pub fn place_spot_order(
             spot_market_map,
             oracle_map,
             options.risk_increasing,
+            None,
         )?;
     }
 
@@ -3464,7 +5816,7 @@ pub fn fill_spot_order(
         }
     }
 
-    let should_expire_order = should_expire_order_before_fill(user, order_index, now)?;
+    let should_expire_order = should_expire_order_before_fill(user, order_index, slot, now)?;
 
     let should_cancel_reduce_only = if user.orders[order_index].reduce_only {
         let market_index = user.orders[order_index].market_index;
@@ -3727,7 +6079,7 @@ fn get_spot_maker_order<'a>(
         )?
     };
 
-    let should_expire_order = should_expire_order(&maker, maker_order_index, now)?;
+    let should_expire_order = should_expire_order(&maker, maker_order_index, slot, now)?;
 
     let should_cancel_reduce_only_order = if maker.orders[maker_order_index].reduce_only {
         let spot_position_index =
@@ -3812,9 +6164,61 @@ fn fulfill_spot_order(
     let base_market_index = user.orders[user_order_index].market_index;
     let order_direction = user.orders[user_order_index].direction;
 
+    let mut self_trade_prevented = false;
+    if let (Some(maker), Some(maker_order_index), Some(maker_key)) =
+        (maker.as_deref_mut(), maker_order_index, maker_key)
+    {
+        if maker.authority == user.authority {
+            let orders_cross = {
+                let base_market = spot_market_map.get_ref(&base_market_index)?;
+                let oracle_price = oracle_map.get_price_data(&base_market.oracle)?.price;
+                let taker_price = user.orders[user_order_index].get_limit_price(
+                    Some(oracle_price),
+                    None,
+                    slot,
+                    base_market.order_tick_size,
+                )?;
+                let maker_price = maker.orders[maker_order_index].force_get_limit_price(
+                    Some(oracle_price),
+                    None,
+                    slot,
+                    base_market.order_tick_size,
+                )?;
+
+                are_orders_same_market_but_different_sides(
+                    &maker.orders[maker_order_index],
+                    &user.orders[user_order_index],
+                ) && taker_price.map_or(false, |taker_price| {
+                    do_orders_cross(
+                        maker.orders[maker_order_index].direction,
+                        maker_price,
+                        taker_price,
+                    )
+                })
+            };
+
+            if orders_cross {
+                self_trade_prevented = prevent_self_trade(
+                    user,
+                    user_key,
+                    user_order_index,
+                    maker,
+                    maker_key,
+                    maker_order_index,
+                    filler_key,
+                    perp_market_map,
+                    spot_market_map,
+                    oracle_map,
+                    now,
+                    slot,
+                )?;
+            }
+        }
+    }
+
     let fulfillment_methods = determine_spot_fulfillment_methods(
         &user.orders[user_order_index],
-        maker.is_some(),
+        maker.is_some() && !self_trade_prevented,
         fulfillment_params.is_external(),
     )?;
 
@@ -4030,7 +6434,12 @@ pub fn fulfill_spot_order_with_match(
         .get_standardized_base_asset_amount_unfilled(
             Some(taker_token_amount.cast()?),
             base_market.order_step_size,
-        )?;
+        )?
+        .min(
+            taker.orders[taker_order_index]
+                .get_twap_base_asset_amount_available(slot, base_market.order_step_size)?
+                .unwrap_or(u64::MAX),
+        );
     let taker_order_slot = taker.orders[taker_order_index].slot;
     let taker_direction = taker.orders[taker_order_index].direction;
 
@@ -4187,6 +6596,7 @@ pub fn fulfill_spot_order_with_match(
         &mut taker.orders[taker_order_index],
         base_asset_amount,
         quote_asset_amount,
+        slot,
     )?;
 
     let taker_order_direction = taker.orders[taker_order_index].direction;
@@ -4230,6 +6640,7 @@ pub fn fulfill_spot_order_with_match(
         &mut maker.orders[maker_order_index],
         base_asset_amount,
         quote_asset_amount,
+        slot,
     )?;
 
     let maker_order_direction = maker.orders[maker_order_index].direction;
@@ -4351,7 +6762,12 @@ pub fn fulfill_spot_order_with_external_market(
         .get_standardized_base_asset_amount_unfilled(
             Some(taker_token_amount.cast()?),
             base_market.order_step_size,
-        )?;
+        )?
+        .min(
+            taker.orders[taker_order_index]
+                .get_twap_base_asset_amount_available(slot, base_market.order_step_size)?
+                .unwrap_or(u64::MAX),
+        );
     let order_direction = taker.orders[taker_order_index].direction;
     let taker_order_slot = taker.orders[taker_order_index].slot;
 
@@ -4536,6 +6952,7 @@ pub fn fulfill_spot_order_with_external_market(
         &mut taker.orders[taker_order_index],
         base_asset_amount_filled,
         quote_asset_amount_filled,
+        slot,
     )?;
 
     let taker_order_direction = taker.orders[taker_order_index].direction;
@@ -4673,6 +7090,17 @@ pub fn trigger_spot_order(
 
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
+    // cross market trigger orders check their trigger condition against another market's oracle
+    let trigger_market_oracle_price = match user.orders[order_index].get_trigger_market() {
+        Some(trigger_market) => Some(get_trigger_market_oracle_price(
+            trigger_market,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+        )?),
+        None => None,
+    };
+
     let spot_market = spot_market_map.get_ref(&market_index)?;
     let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
         &spot_market.oracle,
@@ -4695,11 +7123,33 @@ pub fn trigger_spot_order(
     )?;
 
     let oracle_price = oracle_price_data.price;
+    let trigger_source_price = match trigger_market_oracle_price {
+        Some(trigger_market_oracle_price) => trigger_market_oracle_price,
+        None => get_spot_trigger_source_price(
+            user.orders[order_index].trigger_price_source,
+            &spot_market,
+            oracle_price,
+        )?,
+    };
 
-    let can_trigger = order_satisfies_trigger_condition(
-        &user.orders[order_index],
-        oracle_price.unsigned_abs().cast()?,
+    // trailing stops ratchet their trigger price towards the trigger price source before checking the trigger condition
+    let trigger_price_updated = update_trailing_stop_trigger_price(
+        &mut user.orders[order_index],
+        trigger_source_price,
+        spot_market.order_tick_size,
     )?;
+
+    let can_trigger =
+        order_satisfies_trigger_condition(&user.orders[order_index], trigger_source_price)?;
+
+    if !can_trigger && trigger_price_updated {
+        msg!(
+            "trailing stop trigger price updated to {}",
+            user.orders[order_index].trigger_price
+        );
+        return Ok(());
+    }
+
     validate!(can_trigger, ErrorCode::OrderDidNotSatisfyTriggerCondition)?;
 
     let position_index = user.get_spot_position_index(market_index)?;
@@ -4827,7 +7277,7 @@ pub fn expire_orders(
     slot: u64,
 ) -> DriftResult {
     for order_index in 0..user.orders.len() {
-        if !should_expire_order(user, order_index, now)? {
+        if !should_expire_order(user, order_index, slot, now)? {
             continue;
         }
 
@@ -130,6 +130,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -249,6 +250,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -368,6 +370,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -487,6 +490,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -606,6 +610,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -691,6 +696,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -777,6 +783,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -863,6 +870,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -949,6 +957,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1055,6 +1064,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1164,6 +1174,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1280,6 +1291,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1397,6 +1409,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1538,6 +1551,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1654,6 +1668,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -1769,6 +1784,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut oracle_map,
+            None,
         )
         .unwrap();
 
@@ -1909,6 +1925,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut oracle_map,
+            None,
         )
         .unwrap();
 
@@ -2042,6 +2059,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut oracle_map,
+            None,
         )
         .unwrap();
 
@@ -2181,6 +2199,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut oracle_map,
+            None,
         )
         .unwrap();
 
@@ -2308,6 +2327,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -2434,6 +2454,7 @@ pub mod fulfill_order_with_maker_order {
             slot,
             &fee_structure,
             &mut get_oracle_map(),
+            None,
         )
         .unwrap();
 
@@ -4775,7 +4796,7 @@ pub mod fill_order {
     use crate::state::perp_market_map::PerpMarketMap;
     use crate::state::spot_market::{SpotBalanceType, SpotMarket};
     use crate::state::spot_market_map::SpotMarketMap;
-    use crate::state::state::State;
+    use crate::state::state::{ExchangeStatus, State};
     use crate::state::user::{MarketType, OrderStatus, OrderType, SpotPosition, User, UserStats};
     use crate::test_utils::*;
     use crate::test_utils::{
@@ -5197,6 +5218,245 @@ pub mod fill_order {
         assert_eq!(base_asset_amount, 1000000000);
     }
 
+    #[test]
+    fn order_group_siblings_canceled_only_when_completely_filled() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_base_asset_reserve: u128::MAX,
+                min_base_asset_reserve: 0,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        // taker bids for 2 with a sibling bid in the same group
+        let mut taker_orders = [Order::default(); 32];
+        taker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            market_type: MarketType::Perp,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            group_id: 1,
+            ..Order::default()
+        };
+        taker_orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            market_type: MarketType::Perp,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 90 * PRICE_PRECISION_U64,
+            group_id: 1,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            authority: Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap(), // different authority than filler
+            orders: taker_orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: 3 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, user_stats_account_info);
+        let user_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&user_stats_account_info).unwrap();
+
+        // maker asks 1 with a sibling ask in the same group that doesn't cross
+        let mut maker_orders = [Order::default(); 32];
+        maker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 100 * PRICE_PRECISION_U64,
+            post_only: true,
+            group_id: 5,
+            ..Order::default()
+        };
+        maker_orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            slot: 0,
+            price: 110 * PRICE_PRECISION_U64,
+            post_only: true,
+            group_id: 5,
+            ..Order::default()
+        };
+
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111113").unwrap();
+        let maker_authority =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let mut maker = User {
+            authority: maker_authority,
+            orders: maker_orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            ..User::default()
+        };
+        create_anchor_account_info!(maker, &maker_key, User, maker_account_info);
+        let makers_and_referrers = UserMap::load_one(&maker_account_info).unwrap();
+
+        let mut maker_stats = UserStats {
+            authority: maker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(maker_stats, UserStats, maker_stats_account_info);
+        let maker_and_referrer_stats = UserStatsMap::load_one(&maker_stats_account_info).unwrap();
+
+        let filler_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        create_anchor_account_info!(User::default(), &filler_key, User, user_account_info);
+        let filler_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        create_anchor_account_info!(UserStats::default(), UserStats, filler_stats_account_info);
+        let filler_stats_account_loader: AccountLoader<UserStats> =
+            AccountLoader::try_from(&filler_stats_account_info).unwrap();
+
+        // only the maker can fill the taker
+        let state = State {
+            exchange_status: ExchangeStatus::AmmPaused as u8,
+            min_perp_auction_duration: 1,
+            default_market_order_time_in_force: 10,
+            ..State::default()
+        };
+
+        let base_asset_amount = fill_perp_order(
+            1,
+            &state,
+            &user_account_loader,
+            &user_stats_account_loader,
+            &spot_market_map,
+            &market_map,
+            &mut oracle_map,
+            &filler_account_loader,
+            &filler_stats_account_loader,
+            &makers_and_referrers,
+            &maker_and_referrer_stats,
+            None,
+            &clock,
+            FillMode::Fill,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64);
+
+        // the partially filled taker order keeps its sibling
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].base_asset_amount_filled, BASE_PRECISION_U64);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].order_id, 2);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+
+        // the completely filled maker order cancels its sibling
+        let maker = makers_and_referrers.get_ref(&maker_key).unwrap();
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(maker.orders[1], Order::default());
+        assert_eq!(maker.perp_positions[0].open_orders, 0);
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+    }
+
     #[test]
     fn expire_order() {
         let mut market = PerpMarket {
@@ -10597,3 +10857,2028 @@ mod update_maker_fills_map {
         assert_eq!(*map.get(&maker_key).unwrap(), -2 * fill as i64);
     }
 }
+
+pub mod cancel_order_group {
+    use crate::controller::orders::cancel_order_group;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, OrderStatus, OrderTriggerCondition, OrderType, User};
+    use crate::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn cancel_siblings() {
+        let mut market = PerpMarket {
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            decimals: 6,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut oracle_map = get_oracle_map();
+
+        let mut orders = [Order::default(); 32];
+        // take profit
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 110 * PRICE_PRECISION_U64,
+            reduce_only: true,
+            group_id: 2,
+            ..Order::default()
+        };
+        // stop loss
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 3,
+            status: OrderStatus::Open,
+            order_type: OrderType::TriggerMarket,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            trigger_price: 90 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            reduce_only: true,
+            group_id: 2,
+            ..Order::default()
+        };
+        // not in the group
+        orders[2] = Order {
+            market_index: 0,
+            order_id: 4,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 120 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                open_orders: 3,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            open_orders: 3,
+            ..User::default()
+        };
+
+        let canceled_order_ids = cancel_order_group(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+            OrderActionExplanation::OrderGroupSiblingFilled,
+            2,
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(canceled_order_ids, vec![3]);
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1], Order::default());
+        assert_eq!(user.orders[2].status, OrderStatus::Open);
+        assert_eq!(user.open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        // stop loss wasn't triggered so it didn't count towards open asks
+        assert_eq!(user.perp_positions[0].open_asks, -2 * BASE_PRECISION_I64);
+
+        // orders without a group are never canceled as siblings
+        let canceled_order_ids = cancel_order_group(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+            OrderActionExplanation::OrderGroupSiblingFilled,
+            0,
+            2,
+        )
+        .unwrap();
+
+        assert!(canceled_order_ids.is_empty());
+        assert_eq!(user.orders[2].status, OrderStatus::Open);
+    }
+}
+
+pub mod prevent_self_trade {
+    use std::str::FromStr;
+
+    use crate::controller::orders::prevent_self_trade;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::state::oracle::OracleSource;
+    use crate::state::perp_market::PerpMarket;
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, OrderStatus, OrderType, SelfTradePreventionMode, User};
+    use crate::test_utils::*;
+
+    use super::*;
+
+    fn get_users(
+        taker_mode: SelfTradePreventionMode,
+        taker_base_asset_amount: u64,
+        maker_base_asset_amount: u64,
+    ) -> (User, User) {
+        let authority = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+
+        let taker = User {
+            authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Market,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: taker_base_asset_amount,
+                self_trade_prevention_mode: taker_mode,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: taker_base_asset_amount as i64,
+                ..PerpPosition::default()
+            }),
+            open_orders: 1,
+            ..User::default()
+        };
+
+        let maker = User {
+            authority,
+            sub_account_id: 1,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Short,
+                base_asset_amount: maker_base_asset_amount,
+                price: 100 * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_asks: -(maker_base_asset_amount as i64),
+                ..PerpPosition::default()
+            }),
+            open_orders: 1,
+            ..User::default()
+        };
+
+        (taker, maker)
+    }
+
+    #[test]
+    fn modes() {
+        let mut market = PerpMarket {
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            decimals: 6,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut oracle_map = get_oracle_map();
+
+        let taker_key = Pubkey::default();
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let filler_key = Pubkey::default();
+
+        // no mode set, orders can match
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::None,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(!prevented);
+
+        // different authorities are never self trades
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::CancelBoth,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        maker.authority = Pubkey::default();
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(!prevented);
+
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::CancelMaker,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(taker.orders[0].status, OrderStatus::Open);
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(maker.perp_positions[0].open_asks, 0);
+
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::CancelBoth,
+            BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(taker.orders[0], Order::default());
+        assert_eq!(maker.orders[0], Order::default());
+
+        // taker is bigger, so it is decremented by the maker size and the maker is canceled
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::DecrementAndCancel,
+            3 * BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(taker.orders[0].base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(taker.perp_positions[0].open_bids, 2 * BASE_PRECISION_I64);
+        assert_eq!(maker.orders[0], Order::default());
+
+        // maker is bigger, so it is decremented by the taker size and the taker is canceled
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::DecrementAndCancel,
+            BASE_PRECISION_U64,
+            3 * BASE_PRECISION_U64,
+        );
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(taker.orders[0], Order::default());
+        assert_eq!(maker.orders[0].base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(maker.perp_positions[0].open_asks, -2 * BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn decrement_iceberg_and_twap() {
+        let mut market = PerpMarket {
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            decimals: 6,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut oracle_map = get_oracle_map();
+
+        let taker_key = Pubkey::default();
+        let maker_key = Pubkey::from_str("My11111111111111111111111111111111111111111").unwrap();
+        let filler_key = Pubkey::default();
+
+        // the maker's reserve counts towards its size and is decremented before its visible slice
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::DecrementAndCancel,
+            2 * BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        maker.orders[0].iceberg_display_size = BASE_PRECISION_U64;
+        maker.orders[0].iceberg_reserve_base_asset_amount = 3 * BASE_PRECISION_U64;
+        maker.perp_positions[0].open_asks = -4 * BASE_PRECISION_I64;
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(taker.orders[0], Order::default());
+        assert_eq!(maker.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(
+            maker.orders[0].iceberg_reserve_base_asset_amount,
+            BASE_PRECISION_U64
+        );
+        assert_eq!(maker.perp_positions[0].open_asks, -2 * BASE_PRECISION_I64);
+
+        // the taker twap's slices shrink to cover the decrement
+        let (mut taker, mut maker) = get_users(
+            SelfTradePreventionMode::DecrementAndCancel,
+            4 * BASE_PRECISION_U64,
+            BASE_PRECISION_U64,
+        );
+        taker.orders[0].order_type = OrderType::Twap;
+        taker.orders[0].twap_slices = 4;
+        taker.orders[0].twap_slice_interval = 10;
+        let prevented = prevent_self_trade(
+            &mut taker,
+            &taker_key,
+            0,
+            &mut maker,
+            &maker_key,
+            0,
+            &filler_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(prevented);
+        assert_eq!(maker.orders[0], Order::default());
+        assert_eq!(taker.orders[0].base_asset_amount, 3 * BASE_PRECISION_U64);
+        assert_eq!(taker.perp_positions[0].open_bids, 3 * BASE_PRECISION_I64);
+        assert_eq!(
+            taker.orders[0]
+                .get_twap_base_asset_amount_available(0, BASE_PRECISION_U64 / 4)
+                .unwrap(),
+            Some(3 * BASE_PRECISION_U64 / 4)
+        );
+        assert_eq!(
+            taker.orders[0]
+                .get_twap_base_asset_amount_available(30, BASE_PRECISION_U64 / 4)
+                .unwrap(),
+            Some(3 * BASE_PRECISION_U64)
+        );
+    }
+}
+
+pub mod modify_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::modify_orders;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::order_params::{ModifyOrderByIdParams, ModifyOrderParams, ModifyOrderPolicy};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{MarketType, OrderStatus, OrderType, SpotPosition, User};
+    use crate::test_utils::*;
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    #[test]
+    fn modify_multiple_orders() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        for (i, price) in [99, 98].iter().enumerate() {
+            orders[i] = Order {
+                market_index: 0,
+                order_id: i as u32 + 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: price * PRICE_PRECISION_U64,
+                post_only: true,
+                ..Order::default()
+            };
+        }
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: 2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            next_order_id: 3,
+            ..User::default()
+        };
+        create_anchor_account_info!(user, User, user_account_info);
+        let user_account_loader: AccountLoader<User> =
+            AccountLoader::try_from(&user_account_info).unwrap();
+
+        let state = State::default();
+
+        let modify_price =
+            |order_id: u32, price: u64, policy: ModifyOrderPolicy| ModifyOrderByIdParams {
+                order_id,
+                modify_order_params: ModifyOrderParams {
+                    price: Some(price * PRICE_PRECISION_U64),
+                    policy: Some(policy),
+                    ..ModifyOrderParams::default()
+                },
+            };
+
+        // missing order id must be modified
+        let result = modify_orders(
+            vec![
+                modify_price(1, 97, ModifyOrderPolicy::TryModify),
+                modify_price(5, 96, ModifyOrderPolicy::MustModify),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        );
+        assert!(result.is_err());
+
+        // same order id twice
+        let result = modify_orders(
+            vec![
+                modify_price(1, 97, ModifyOrderPolicy::TryModify),
+                modify_price(1, 96, ModifyOrderPolicy::TryModify),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        );
+        assert!(result.is_err());
+
+        modify_orders(
+            vec![
+                modify_price(1, 97, ModifyOrderPolicy::TryModify),
+                modify_price(2, 96, ModifyOrderPolicy::TryModify),
+                modify_price(5, 95, ModifyOrderPolicy::TryModify),
+            ],
+            &user_account_loader,
+            &state,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+        )
+        .unwrap();
+
+        let user = user_account_loader.load().unwrap();
+        assert_eq!(user.orders[0].order_id, 3);
+        assert_eq!(user.orders[0].price, 97 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[1].order_id, 4);
+        assert_eq!(user.orders[1].price, 96 * PRICE_PRECISION_U64);
+        assert_eq!(user.open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, 2 * BASE_PRECISION_I64);
+    }
+}
+
+pub mod update_close_position_order_size {
+    use crate::controller::orders::update_close_position_order_size;
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64};
+    use crate::state::user::{Order, OrderStatus, OrderType, PerpPosition, User};
+    use crate::test_utils::{get_orders, get_positions};
+
+    fn get_user(base_asset_amount: i64) -> User {
+        User {
+            orders: get_orders(Order {
+                market_index: 0,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                direction: PositionDirection::Short,
+                base_asset_amount: BASE_PRECISION_U64,
+                base_asset_amount_filled: BASE_PRECISION_U64 / 2,
+                reduce_only: true,
+                close_position_percentage: 100,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount,
+                open_orders: 1,
+                open_asks: -BASE_PRECISION_I64 / 2,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        }
+    }
+
+    #[test]
+    fn sizes_to_position() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let mut user = get_user(2 * BASE_PRECISION_I64);
+
+        update_close_position_order_size(&mut user, 0, step_size).unwrap();
+
+        assert_eq!(
+            user.orders[0].base_asset_amount,
+            BASE_PRECISION_U64 / 2 + 2 * BASE_PRECISION_U64
+        );
+        assert_eq!(user.perp_positions[0].open_asks, -2 * BASE_PRECISION_I64);
+
+        let mut user = get_user(BASE_PRECISION_I64 / 5);
+
+        update_close_position_order_size(&mut user, 0, step_size).unwrap();
+
+        assert_eq!(
+            user.orders[0].base_asset_amount,
+            BASE_PRECISION_U64 / 2 + BASE_PRECISION_U64 / 5
+        );
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64 / 5);
+    }
+
+    #[test]
+    fn position_flipped() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let mut user = get_user(-BASE_PRECISION_I64);
+
+        update_close_position_order_size(&mut user, 0, step_size).unwrap();
+
+        assert_eq!(user.orders[0].base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64 / 2);
+    }
+
+    #[test]
+    fn partial_close_after_fill() {
+        let step_size = BASE_PRECISION_U64 / 10;
+
+        // 50% of a 10 position, 2 already filled
+        let mut user = get_user(8 * BASE_PRECISION_I64);
+        user.orders[0].close_position_percentage = 50;
+        user.orders[0].base_asset_amount = 5 * BASE_PRECISION_U64;
+        user.orders[0].base_asset_amount_filled = 2 * BASE_PRECISION_U64;
+        user.perp_positions[0].open_asks = -3 * BASE_PRECISION_I64;
+
+        update_close_position_order_size(&mut user, 0, step_size).unwrap();
+
+        assert_eq!(user.orders[0].base_asset_amount, 5 * BASE_PRECISION_U64);
+        assert_eq!(user.perp_positions[0].open_asks, -3 * BASE_PRECISION_I64);
+
+        // position grew by 4 outside of the order
+        user.perp_positions[0].base_asset_amount = 12 * BASE_PRECISION_I64;
+
+        update_close_position_order_size(&mut user, 0, step_size).unwrap();
+
+        assert_eq!(user.orders[0].base_asset_amount, 7 * BASE_PRECISION_U64);
+        assert_eq!(user.perp_positions[0].open_asks, -5 * BASE_PRECISION_I64);
+    }
+}
+
+pub mod get_trigger_market_oracle_price {
+    use std::str::FromStr;
+
+    use crate::controller::orders::get_trigger_market_oracle_price;
+    use crate::create_account_info;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{PRICE_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION_I64};
+    use crate::state::margin_calculation::MarketIdentifier;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn perp_and_spot() {
+        let slot = 0_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            market_index: 1,
+            amm: AMM {
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price: (100 * PRICE_PRECISION) as i64,
+                    last_oracle_price_twap: (100 * PRICE_PRECISION) as i64,
+                    last_oracle_price_twap_5min: (100 * PRICE_PRECISION) as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            status: MarketStatus::Active,
+            ..PerpMarket::default_test()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            decimals: 6,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let price = get_trigger_market_oracle_price(
+            MarketIdentifier::perp(1),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        )
+        .unwrap();
+        assert_eq!(price, 100 * PRICE_PRECISION_U64);
+
+        let price = get_trigger_market_oracle_price(
+            MarketIdentifier::spot(0),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        )
+        .unwrap();
+        assert_eq!(price, PRICE_PRECISION_U64);
+
+        // trigger market isn't loaded
+        let result = get_trigger_market_oracle_price(
+            MarketIdentifier::perp(2),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        );
+        assert!(result.is_err());
+    }
+}
+
+pub mod cancel_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use crate::controller::orders::cancel_orders;
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::events::OrderActionExplanation;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, OrderStatus, OrderType, PositionFlag, User};
+    use crate::test_utils::*;
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    #[test]
+    fn skip_isolated_perp_positions() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 99 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                isolated_position_scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+                position_flag: PositionFlag::IsolatedPosition as u8,
+                ..PerpPosition::default()
+            }),
+            open_orders: 1,
+            next_order_id: 2,
+            ..User::default()
+        };
+
+        let canceled_order_ids = cancel_orders(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::Liquidation,
+            None,
+            None,
+            None,
+            true,
+        )
+        .unwrap();
+
+        assert!(canceled_order_ids.is_empty());
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+
+        let canceled_order_ids = cancel_orders(
+            &mut user,
+            &Pubkey::default(),
+            None,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock.unix_timestamp,
+            clock.slot,
+            OrderActionExplanation::Liquidation,
+            None,
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(canceled_order_ids, vec![1]);
+        assert_eq!(user.orders[0].status, OrderStatus::Init);
+        assert_eq!(user.perp_positions[0].open_orders, 0);
+    }
+}
+
+pub mod cancel_and_place_orders {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Clock;
+
+    use crate::controller::orders::{cancel_and_place_orders, place_orders};
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, BASE_PRECISION_U64, PEG_PRECISION,
+        PRICE_PRECISION_U64, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::order_params::{OrderParams, PostOnlyParam};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{MarketType, OrderStatus, OrderType, SpotPosition, TimeInForce, User};
+    use crate::test_utils::*;
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    #[test]
+    fn no_ids_and_no_filters_cancels_everything() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 90 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 110 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            has_open_order: true,
+            next_order_id: 3,
+            ..User::default()
+        };
+
+        let params = OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 95 * PRICE_PRECISION_U64,
+            market_index: 0,
+            post_only: PostOnlyParam::MustPostOnly,
+            ..OrderParams::default()
+        };
+
+        cancel_and_place_orders(
+            &State::default(),
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            None,
+            None,
+            None,
+            &[],
+            &[params],
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].order_id, 3);
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[0].price, 95 * PRICE_PRECISION_U64);
+        assert_eq!(user.orders[1].status, OrderStatus::Init);
+        assert_eq!(user.open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_orders, 1);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, 0);
+        assert_eq!(user.last_active_slot, clock.slot);
+    }
+
+    #[test]
+    fn cancel_by_order_ids_skips_missing_ids() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                terminal_quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 100,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                max_spread: 1000,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price_twap: oracle_price.twap as i64,
+                    last_oracle_price_twap_5min: oracle_price.twap as i64,
+                    last_oracle_price: oracle_price.agg.price as i64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            ..PerpMarket::default()
+        };
+        market.amm.max_base_asset_reserve = u128::MAX;
+        market.amm.min_base_asset_reserve = 0;
+        let (new_ask_base_asset_reserve, new_ask_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Long,
+            )
+            .unwrap();
+        let (new_bid_base_asset_reserve, new_bid_quote_asset_reserve) =
+            crate::math::amm_spread::calculate_spread_reserves(
+                &market.amm,
+                PositionDirection::Short,
+            )
+            .unwrap();
+        market.amm.ask_base_asset_reserve = new_ask_base_asset_reserve;
+        market.amm.bid_base_asset_reserve = new_bid_base_asset_reserve;
+        market.amm.ask_quote_asset_reserve = new_ask_quote_asset_reserve;
+        market.amm.bid_quote_asset_reserve = new_bid_quote_asset_reserve;
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut orders = [Order::default(); 32];
+        orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 90 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        orders[1] = Order {
+            market_index: 0,
+            order_id: 2,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 110 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let mut user = User {
+            orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_bids: BASE_PRECISION_I64,
+                open_asks: -BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 2,
+            has_open_order: true,
+            next_order_id: 3,
+            ..User::default()
+        };
+
+        let params = OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 105 * PRICE_PRECISION_U64,
+            market_index: 0,
+            post_only: PostOnlyParam::MustPostOnly,
+            ..OrderParams::default()
+        };
+
+        cancel_and_place_orders(
+            &State::default(),
+            &mut user,
+            Pubkey::default(),
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            None,
+            None,
+            None,
+            &[2, 7],
+            &[params],
+        )
+        .unwrap();
+
+        assert_eq!(user.orders[0].order_id, 1);
+        assert_eq!(user.orders[0].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].order_id, 3);
+        assert_eq!(user.orders[1].status, OrderStatus::Open);
+        assert_eq!(user.orders[1].price, 105 * PRICE_PRECISION_U64);
+        assert_eq!(user.open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_orders, 2);
+        assert_eq!(user.perp_positions[0].open_bids, BASE_PRECISION_I64);
+        assert_eq!(user.perp_positions[0].open_asks, -BASE_PRECISION_I64);
+    }
+
+    #[test]
+    fn cant_cancel_by_order_ids_and_filter() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut user = User::default();
+
+        let result = cancel_and_place_orders(
+            &State::default(),
+            &mut user,
+            Pubkey::default(),
+            &PerpMarketMap::empty(),
+            &SpotMarketMap::load_multiple(vec![], true).unwrap(),
+            &mut OracleMap::empty(),
+            &clock,
+            Some(MarketType::Perp),
+            None,
+            None,
+            &[1],
+            &[],
+        );
+
+        assert_eq!(result, Err(ErrorCode::DefaultError));
+    }
+
+    #[test]
+    fn immediate_or_cancel_orders_not_placed() {
+        let clock = Clock {
+            slot: 56,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut user = User::default();
+
+        let params = OrderParams {
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Long,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 95 * PRICE_PRECISION_U64,
+            time_in_force: Some(TimeInForce::ImmediateOrCancel),
+            ..OrderParams::default()
+        };
+
+        let result = place_orders(
+            &State::default(),
+            &mut user,
+            Pubkey::default(),
+            &PerpMarketMap::empty(),
+            &SpotMarketMap::load_multiple(vec![], true).unwrap(),
+            &mut OracleMap::empty(),
+            &clock,
+            &[params],
+        );
+
+        assert_eq!(result, Err(ErrorCode::InvalidOrderIOC));
+        assert_eq!(user.open_orders, 0);
+    }
+}
+
+pub mod batch_auction {
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::{AccountLoader, Clock};
+
+    use crate::controller::orders::{
+        clear_perp_batch_auction, get_users_failing_batch_auction_margin, plan_batch_auction_fills,
+        BatchAuctionFill, BatchAuctionOrder,
+    };
+    use crate::controller::position::PositionDirection;
+    use crate::create_anchor_account_info;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64, QUOTE_PRECISION_U64,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::state::State;
+    use crate::state::user::{
+        MarketMakerProtection, MarketType, OrderStatus, OrderType, SpotPosition, User, UserStats,
+    };
+    use crate::state::user_map::{UserMap, UserStatsMap};
+    use crate::test_utils::*;
+    use crate::{create_account_info, QUOTE_PRECISION_I64};
+
+    use super::*;
+
+    fn get_batch_auction_order(
+        user_key: Pubkey,
+        order_id: u32,
+        direction: PositionDirection,
+        price: u64,
+        base_asset_amount: u64,
+    ) -> BatchAuctionOrder {
+        BatchAuctionOrder {
+            user_key,
+            authority: user_key,
+            order_index: 0,
+            order_id,
+            direction,
+            price: price * PRICE_PRECISION_U64,
+            base_asset_amount,
+            reduce_only: false,
+            group_id: 0,
+            slot: 0,
+        }
+    }
+
+    #[test]
+    fn plan_fills_at_clearing_price() {
+        let bidder = Pubkey::new_unique();
+        let first_asker = Pubkey::new_unique();
+        let second_asker = Pubkey::new_unique();
+
+        let bids = [get_batch_auction_order(
+            bidder,
+            1,
+            PositionDirection::Long,
+            101,
+            2 * BASE_PRECISION_U64,
+        )];
+        let asks = [
+            get_batch_auction_order(
+                first_asker,
+                1,
+                PositionDirection::Short,
+                99,
+                BASE_PRECISION_U64,
+            ),
+            get_batch_auction_order(
+                second_asker,
+                1,
+                PositionDirection::Short,
+                100,
+                2 * BASE_PRECISION_U64,
+            ),
+        ];
+
+        let (clearing_price, fills) = plan_batch_auction_fills(
+            &bids,
+            &asks,
+            &BTreeMap::new(),
+            &[],
+            100 * PRICE_PRECISION_U64,
+        )
+        .unwrap()
+        .unwrap();
+
+        // 100 and 101 both clear 2, 100 is closer to the oracle
+        assert_eq!(clearing_price, 100 * PRICE_PRECISION_U64);
+        assert_eq!(fills.len(), 2);
+        for (ask_index, fill) in fills.iter().enumerate() {
+            assert_eq!(fill.bid_index, 0);
+            assert_eq!(fill.ask_index, ask_index);
+            assert_eq!(fill.base_asset_amount, BASE_PRECISION_U64);
+            assert_eq!(fill.quote_asset_amount, 100 * QUOTE_PRECISION_U64);
+        }
+
+        // excluded users are left out
+        let (clearing_price, fills) = plan_batch_auction_fills(
+            &bids,
+            &asks,
+            &BTreeMap::new(),
+            &[second_asker],
+            100 * PRICE_PRECISION_U64,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(clearing_price, 100 * PRICE_PRECISION_U64);
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ask_index, 0);
+        assert_eq!(fills[0].base_asset_amount, BASE_PRECISION_U64);
+
+        // nothing crosses
+        let fills = plan_batch_auction_fills(
+            &bids,
+            &asks[1..],
+            &BTreeMap::new(),
+            &[second_asker],
+            100 * PRICE_PRECISION_U64,
+        )
+        .unwrap();
+
+        assert!(fills.is_none());
+    }
+
+    #[test]
+    fn plan_fills_reduce_only_and_order_groups() {
+        let bidder = Pubkey::new_unique();
+        let asker = Pubkey::new_unique();
+
+        // reduce only bid sized against the bidder's short
+        let bids = [BatchAuctionOrder {
+            reduce_only: true,
+            ..get_batch_auction_order(
+                bidder,
+                1,
+                PositionDirection::Long,
+                101,
+                2 * BASE_PRECISION_U64,
+            )
+        }];
+        let asks = [get_batch_auction_order(
+            asker,
+            1,
+            PositionDirection::Short,
+            100,
+            2 * BASE_PRECISION_U64,
+        )];
+
+        let mut positions = BTreeMap::new();
+        positions.insert(bidder, -BASE_PRECISION_I64);
+
+        let (_, fills) =
+            plan_batch_auction_fills(&bids, &asks, &positions, &[], 100 * PRICE_PRECISION_U64)
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].base_asset_amount, BASE_PRECISION_U64);
+
+        // once one order in a group fills, the rest of the group is left out of the batch
+        let bids = [get_batch_auction_order(
+            bidder,
+            1,
+            PositionDirection::Long,
+            101,
+            2 * BASE_PRECISION_U64,
+        )];
+        let asks = [
+            BatchAuctionOrder {
+                group_id: 7,
+                ..get_batch_auction_order(
+                    asker,
+                    1,
+                    PositionDirection::Short,
+                    99,
+                    BASE_PRECISION_U64,
+                )
+            },
+            BatchAuctionOrder {
+                order_index: 1,
+                group_id: 7,
+                ..get_batch_auction_order(
+                    asker,
+                    2,
+                    PositionDirection::Short,
+                    100,
+                    BASE_PRECISION_U64,
+                )
+            },
+        ];
+
+        let (_, fills) = plan_batch_auction_fills(
+            &bids,
+            &asks,
+            &BTreeMap::new(),
+            &[],
+            100 * PRICE_PRECISION_U64,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ask_index, 0);
+    }
+
+    #[test]
+    fn users_failing_margin() {
+        let slot = 5_u64;
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            batch_auction_interval: 5,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let bidder_key = Pubkey::new_unique();
+        let bidder_authority = Pubkey::new_unique();
+        let mut bidder = User {
+            authority: bidder_authority,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(bidder, &bidder_key, User, bidder_account_info);
+
+        // $1 can't back a $100 short
+        let asker_key = Pubkey::new_unique();
+        let asker_authority = Pubkey::new_unique();
+        let mut asker = User {
+            authority: asker_authority,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+        create_anchor_account_info!(asker, &asker_key, User, asker_account_info);
+
+        let mut users = UserMap::load_one(&bidder_account_info).unwrap();
+        users
+            .insert(
+                asker_key,
+                AccountLoader::try_from(&asker_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let mut bidder_stats = UserStats {
+            authority: bidder_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(bidder_stats, UserStats, bidder_stats_account_info);
+        let mut asker_stats = UserStats {
+            authority: asker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(asker_stats, UserStats, asker_stats_account_info);
+
+        let mut users_stats = UserStatsMap::load_one(&bidder_stats_account_info).unwrap();
+        users_stats
+            .insert(
+                asker_authority,
+                AccountLoader::try_from(&asker_stats_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let bids = [get_batch_auction_order(
+            bidder_key,
+            1,
+            PositionDirection::Long,
+            100,
+            BASE_PRECISION_U64,
+        )];
+        let asks = [get_batch_auction_order(
+            asker_key,
+            1,
+            PositionDirection::Short,
+            100,
+            BASE_PRECISION_U64,
+        )];
+        let planned_fills = [BatchAuctionFill {
+            bid_index: 0,
+            ask_index: 0,
+            base_asset_amount: BASE_PRECISION_U64,
+            quote_asset_amount: 100 * QUOTE_PRECISION_U64,
+        }];
+
+        let users_failing_margin = get_users_failing_batch_auction_margin(
+            0,
+            &bids,
+            &asks,
+            &planned_fills,
+            &users,
+            &users_stats,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &get_fee_structure(),
+        )
+        .unwrap();
+
+        assert_eq!(users_failing_margin, vec![asker_key]);
+
+        // the simulation doesn't touch the users
+        assert_eq!(
+            users.get_ref(&asker_key).unwrap().perp_positions[0].base_asset_amount,
+            0
+        );
+    }
+
+    #[test]
+    fn clear_batch_auction() {
+        let clock = Clock {
+            slot: 5,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 0,
+        };
+
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                order_step_size: 1000,
+                order_tick_size: 1,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData::default_price(oracle_price.agg.price),
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Active,
+            batch_auction_interval: 5,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let bidder_key = Pubkey::new_unique();
+        let bidder_authority = Pubkey::new_unique();
+        let mut bidder = User {
+            authority: bidder_authority,
+            orders: get_orders(Order {
+                market_index: 0,
+                order_id: 1,
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                price: 101 * PRICE_PRECISION_U64,
+                ..Order::default()
+            }),
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 1,
+                open_bids: BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            open_orders: 1,
+            ..User::default()
+        };
+        create_anchor_account_info!(bidder, &bidder_key, User, bidder_account_info);
+
+        // the asker's market maker protection trips on the fill, canceling its other ask
+        let mut asker_orders = [Order::default(); 32];
+        asker_orders[0] = Order {
+            market_index: 0,
+            order_id: 1,
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_type: MarketType::Perp,
+            direction: PositionDirection::Short,
+            base_asset_amount: BASE_PRECISION_U64,
+            price: 100 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+        asker_orders[1] = Order {
+            order_id: 2,
+            price: 110 * PRICE_PRECISION_U64,
+            ..asker_orders[0]
+        };
+        let mut market_maker_protections = [MarketMakerProtection::default(); 8];
+        market_maker_protections[0] = MarketMakerProtection {
+            market_index: 0,
+            fill_limit_base_asset_amount: BASE_PRECISION_U64 / 2,
+            window_slots: 10,
+            cooldown_slots: 10,
+            ..MarketMakerProtection::default()
+        };
+
+        let asker_key = Pubkey::new_unique();
+        let asker_authority = Pubkey::new_unique();
+        let mut asker = User {
+            authority: asker_authority,
+            orders: asker_orders,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                open_orders: 2,
+                open_asks: -2 * BASE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            market_maker_protections,
+            open_orders: 2,
+            ..User::default()
+        };
+        create_anchor_account_info!(asker, &asker_key, User, asker_account_info);
+
+        let mut users = UserMap::load_one(&bidder_account_info).unwrap();
+        users
+            .insert(
+                asker_key,
+                AccountLoader::try_from(&asker_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let mut bidder_stats = UserStats {
+            authority: bidder_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(bidder_stats, UserStats, bidder_stats_account_info);
+        let mut asker_stats = UserStats {
+            authority: asker_authority,
+            ..UserStats::default()
+        };
+        create_anchor_account_info!(asker_stats, UserStats, asker_stats_account_info);
+
+        let mut users_stats = UserStatsMap::load_one(&bidder_stats_account_info).unwrap();
+        users_stats
+            .insert(
+                asker_authority,
+                AccountLoader::try_from(&asker_stats_account_info).unwrap(),
+            )
+            .unwrap();
+
+        let state = State {
+            perp_fee_structure: get_fee_structure(),
+            ..State::default()
+        };
+
+        let base_asset_amount_filled = clear_perp_batch_auction(
+            0,
+            &state,
+            &users,
+            &users_stats,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &Pubkey::default(),
+            &clock,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount_filled, BASE_PRECISION_U64);
+
+        let bidder = users.get_ref(&bidder_key).unwrap();
+        assert_eq!(
+            bidder.perp_positions[0].base_asset_amount,
+            BASE_PRECISION_I64
+        );
+        assert_eq!(bidder.orders[0], Order::default());
+        assert_eq!(bidder.perp_positions[0].open_orders, 0);
+
+        let asker = users.get_ref(&asker_key).unwrap();
+        assert_eq!(
+            asker.perp_positions[0].base_asset_amount,
+            -BASE_PRECISION_I64
+        );
+        assert_eq!(asker.orders[0], Order::default());
+        assert_eq!(asker.orders[1], Order::default());
+        assert_eq!(asker.perp_positions[0].open_orders, 0);
+        assert_eq!(asker.market_maker_protections[0].cooldown_end_slot, 15);
+
+        let market = market_map.get_ref(&0).unwrap();
+        assert_eq!(market.last_batch_auction_slot, 5);
+
+        // the next batch can't clear until the interval passes
+        drop(market);
+        drop(bidder);
+        drop(asker);
+        let err = clear_perp_batch_auction(
+            0,
+            &state,
+            &users,
+            &users_stats,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &Pubkey::default(),
+            &clock,
+        );
+        assert_eq!(err, Err(ErrorCode::InvalidBatchAuction));
+    }
+}
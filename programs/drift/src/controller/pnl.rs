@@ -15,6 +15,7 @@ use crate::error::{DriftResult, ErrorCode};
 use crate::math::amm::calculate_net_user_pnl;
 
 use crate::math::casting::Cast;
+use crate::math::constants::QUOTE_SPOT_MARKET_INDEX;
 use crate::math::margin::{
     calculate_margin_requirement_and_total_collateral_and_liability_info,
     meets_maintenance_margin_requirement, MarginRequirementType,
@@ -32,7 +33,7 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::{SpotBalance, SpotBalanceType};
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::State;
-use crate::state::user::{MarketType, User};
+use crate::state::user::{MarketType, SpotPosition, User};
 use crate::validate;
 use anchor_lang::prelude::Pubkey;
 use anchor_lang::prelude::*;
@@ -74,6 +75,7 @@ pub fn settle_pnl(
 
     let position_index = get_position_index(&user.perp_positions, market_index)?;
     let unrealized_pnl = user.perp_positions[position_index].get_unrealized_pnl(oracle_price)?;
+    let is_isolated_position = user.perp_positions[position_index].is_isolated();
 
     // cannot settle negative pnl this way on a user who is in liquidation territory
     if user.perp_positions[position_index].is_lp() && !user.is_advanced_lp() {
@@ -113,8 +115,10 @@ pub fn settle_pnl(
                 return Ok(());
             }
         }
-    } else if unrealized_pnl < 0 {
+    } else if unrealized_pnl < 0 && !is_isolated_position {
         // cannot settle pnl this way on a user who is in liquidation territory
+        // isolated losses are capped at the position's own collateral below, so settling them
+        // leaves cross margin untouched and lets a bankrupt isolated position be resolved
         if !(meets_maintenance_margin_requirement(
             user,
             perp_market_map,
@@ -193,10 +197,28 @@ pub fn settle_pnl(
     let user_unsettled_pnl: i128 =
         user.perp_positions[position_index].get_claimable_pnl(oracle_price, max_pnl_pool_excess)?;
 
+    // losses on an isolated position can only be paid from its own collateral
+    let (user_unsettled_pnl, user_quote_position) = if is_isolated_position {
+        let isolated_token_amount = user.perp_positions[position_index]
+            .get_isolated_token_amount(spot_market)?
+            .cast::<i128>()?;
+        let isolated_quote_position = SpotPosition {
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            scaled_balance: user.perp_positions[position_index].isolated_position_scaled_balance,
+            ..SpotPosition::default()
+        };
+        (
+            user_unsettled_pnl.max(-isolated_token_amount),
+            isolated_quote_position,
+        )
+    } else {
+        (user_unsettled_pnl, *user.get_quote_spot_position())
+    };
+
     let pnl_to_settle_with_user = update_pool_balances(
         perp_market,
         spot_market,
-        user.get_quote_spot_position(),
+        &user_quote_position,
         user_unsettled_pnl,
         now,
     )?;
@@ -227,7 +249,11 @@ pub fn settle_pnl(
             &SpotBalanceType::Borrow
         },
         spot_market,
-        user.get_quote_spot_position_mut(),
+        if is_isolated_position {
+            &mut user.perp_positions[position_index]
+        } else {
+            user.get_quote_spot_position_mut()
+        },
         false,
     )?;
 
@@ -311,6 +337,7 @@ pub fn settle_expired_position(
         Some(MarketType::Perp),
         Some(perp_market_index),
         None,
+        false,
     )?;
 
     let position_index = match get_position_index(&user.perp_positions, perp_market_index) {
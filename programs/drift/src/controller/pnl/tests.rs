@@ -21,7 +21,7 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::{SpotBalanceType, SpotMarket};
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::state::{OracleGuardRails, State, ValidityGuardRails};
-use crate::state::user::{PerpPosition, SpotPosition, User};
+use crate::state::user::{PerpPosition, PositionFlag, SpotPosition, User};
 use crate::test_utils::*;
 use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
 use anchor_lang::prelude::Clock;
@@ -1623,3 +1623,137 @@ pub fn user_short_negative_unrealized_pnl() {
     assert_eq!(expected_user, user);
     assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
+
+#[test]
+pub fn isolated_position_negative_pnl_past_collateral() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 200 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    // liquidated down to no base, with losses larger than the isolated collateral
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I64,
+            isolated_position_scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            position_flag: PositionFlag::IsolatedPosition as u8
+                | PositionFlag::BeingLiquidated as u8,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    // only the isolated collateral is used, cross collateral is untouched
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = -50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].isolated_position_scaled_balance = 0;
+    expected_user.settled_perp_pnl = -100 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -100 * QUOTE_PRECISION_I64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+    assert!(crate::math::bankruptcy::is_isolated_perp_position_bankrupt(
+        &user.perp_positions[0]
+    ));
+}
@@ -399,6 +399,14 @@ pub fn update_position_and_market(
     position.quote_entry_amount = new_quote_entry_amount;
     position.quote_break_even_amount = new_quote_break_even_amount;
 
+    // take profit and stop loss prices only apply to the position they were set for
+    if matches!(
+        update_type,
+        PositionUpdateType::Close | PositionUpdateType::Flip
+    ) {
+        position.clear_take_profit_stop_loss();
+    }
+
     Ok(pnl)
 }
 
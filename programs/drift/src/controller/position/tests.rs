@@ -9,8 +9,9 @@ use crate::controller::lp::{apply_lp_rebase_to_perp_market, settle_lp_position};
 
 use crate::controller::repeg::_update_amm;
 use crate::math::constants::{
-    AMM_RESERVE_PRECISION, AMM_RESERVE_PRECISION_I128, BASE_PRECISION, BASE_PRECISION_I64,
-    PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION_I128,
+    AMM_RESERVE_PRECISION, AMM_RESERVE_PRECISION_I128, BASE_PRECISION, BASE_PRECISION_I128,
+    BASE_PRECISION_I64, PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION_I128,
+    QUOTE_PRECISION_I64,
 };
 use crate::math::position::swap_direction_to_close_position;
 use crate::state::oracle::OraclePriceData;
@@ -544,6 +545,68 @@ fn test_position_entry_sim() {
     assert_eq!(existing_position.get_breakeven_price().unwrap(), 99345000);
 }
 
+#[test]
+fn take_profit_stop_loss_cleared_on_close() {
+    let mut existing_position = PerpPosition {
+        base_asset_amount: BASE_PRECISION_I64,
+        last_cumulative_funding_rate: 1,
+        quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+        quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+        quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+        take_profit_price: 110 * PRICE_PRECISION_U64,
+        stop_loss_price: 90 * PRICE_PRECISION_U64,
+        ..PerpPosition::default()
+    };
+    let mut market = PerpMarket {
+        amm: AMM {
+            cumulative_funding_rate_long: 1,
+            sqrt_k: 1,
+            order_step_size: (BASE_PRECISION_I64 / 10) as u64,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            ..AMM::default()
+        },
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        ..PerpMarket::default_test()
+    };
+
+    let position_delta_to_reduce = PositionDelta {
+        base_asset_amount: -BASE_PRECISION_I64 / 2,
+        quote_asset_amount: 50 * QUOTE_PRECISION_I64,
+        remainder_base_asset_amount: None,
+    };
+
+    update_position_and_market(
+        &mut existing_position,
+        &mut market,
+        &position_delta_to_reduce,
+    )
+    .unwrap();
+
+    assert_eq!(
+        existing_position.take_profit_price,
+        110 * PRICE_PRECISION_U64
+    );
+    assert_eq!(existing_position.stop_loss_price, 90 * PRICE_PRECISION_U64);
+
+    let position_delta_to_close = PositionDelta {
+        base_asset_amount: -BASE_PRECISION_I64 / 2,
+        quote_asset_amount: 50 * QUOTE_PRECISION_I64,
+        remainder_base_asset_amount: None,
+    };
+
+    update_position_and_market(
+        &mut existing_position,
+        &mut market,
+        &position_delta_to_close,
+    )
+    .unwrap();
+
+    assert_eq!(existing_position.base_asset_amount, 0);
+    assert!(!existing_position.has_take_profit_stop_loss());
+}
+
 #[test]
 fn increase_long_from_no_position() {
     let mut existing_position = PerpPosition::default();
@@ -521,6 +521,52 @@ pub enum ErrorCode {
     CantPayUserInitFee,
     #[msg("CantReclaimRent")]
     CantReclaimRent,
+    #[msg("InvalidTwapOrder")]
+    InvalidTwapOrder,
+    #[msg("InvalidBracketOrder")]
+    InvalidBracketOrder,
+    #[msg("InvalidUserMigration")]
+    InvalidUserMigration,
+    #[msg("InvalidTrailingStopOrder")]
+    InvalidTrailingStopOrder,
+    #[msg("InvalidIcebergOrder")]
+    InvalidIcebergOrder,
+    #[msg("MaxNumberOfMarketMakerProtections")]
+    MaxNumberOfMarketMakerProtections,
+    #[msg("MarketMakerProtectionCoolingDown")]
+    MarketMakerProtectionCoolingDown,
+    #[msg("HeartbeatNotMissed")]
+    HeartbeatNotMissed,
+    #[msg("InvalidTimeInForce")]
+    InvalidTimeInForce,
+    #[msg("FillOrKillOrderNotFilled")]
+    FillOrKillOrderNotFilled,
+    #[msg("SigVerificationFailed")]
+    SigVerificationFailed,
+    #[msg("SignedOrderExpired")]
+    SignedOrderExpired,
+    #[msg("InvalidSignedOrderNonce")]
+    InvalidSignedOrderNonce,
+    #[msg("InvalidSignedOrder")]
+    InvalidSignedOrder,
+    #[msg("InvalidClosePositionOrder")]
+    InvalidClosePositionOrder,
+    #[msg("InvalidPositionTakeProfitStopLoss")]
+    InvalidPositionTakeProfitStopLoss,
+    #[msg("InvalidTriggerPriceSource")]
+    InvalidTriggerPriceSource,
+    #[msg("InvalidTriggerMarket")]
+    InvalidTriggerMarket,
+    #[msg("InvalidScaleOrder")]
+    InvalidScaleOrder,
+    #[msg("InvalidBatchAuction")]
+    InvalidBatchAuction,
+    #[msg("InvalidMaxOracleSlippage")]
+    InvalidMaxOracleSlippage,
+    #[msg("ClientOrderIdAlreadyInUse")]
+    ClientOrderIdAlreadyInUse,
+    #[msg("InvalidIsolatedPerpPosition")]
+    InvalidIsolatedPerpPosition,
 }
 
 #[macro_export]
@@ -15,7 +15,7 @@ use crate::math::constants::{
     DEFAULT_BASE_ASSET_AMOUNT_STEP_SIZE, DEFAULT_LIQUIDATION_MARGIN_BUFFER_RATIO,
     DEFAULT_QUOTE_ASSET_AMOUNT_TICK_SIZE, FEE_POOL_TO_REVENUE_POOL_THRESHOLD, IF_FACTOR_PRECISION,
     INSURANCE_A_MAX, INSURANCE_B_MAX, INSURANCE_C_MAX, INSURANCE_SPECULATIVE_MAX,
-    LIQUIDATION_FEE_PRECISION, MAX_CONCENTRATION_COEFFICIENT, MAX_SQRT_K,
+    LIQUIDATION_FEE_PRECISION, MARGIN_PRECISION, MAX_CONCENTRATION_COEFFICIENT, MAX_SQRT_K,
     MAX_UPDATE_K_PRICE_CHANGE, QUOTE_SPOT_MARKET_INDEX, SPOT_CUMULATIVE_INTEREST_PRECISION,
     SPOT_IMF_PRECISION, SPOT_WEIGHT_PRECISION, THIRTEEN_DAY, TWENTY_FOUR_HOUR,
 };
@@ -39,7 +39,8 @@ use crate::state::oracle::{
 };
 use crate::state::paused_operations::{PerpOperation, SpotOperation};
 use crate::state::perp_market::{
-    ContractTier, ContractType, InsuranceClaim, MarketStatus, PerpMarket, PoolBalance, AMM,
+    ContractTier, ContractType, InsuranceClaim, MarketStatus, MatchingPolicy, PerpMarket,
+    PoolBalance, AMM,
 };
 use crate::state::spot_market::{
     AssetTier, InsuranceFund, SpotBalanceType, SpotFulfillmentConfigStatus, SpotMarket,
@@ -642,7 +643,14 @@ pub fn handle_initialize_perp_market(
         paused_operations: 0,
         quote_spot_market_index: 0,
         fee_adjustment: 0,
-        padding: [0; 46],
+        matching_policy: MatchingPolicy::default(),
+        padding1: [0; 5],
+        pro_rata_min_allocation: 0,
+        last_batch_auction_slot: 0,
+        batch_auction_interval: 0,
+        basis_spot_market_index: 0,
+        basis_margin_offset: 0,
+        padding: [0; 18],
         amm: AMM {
             oracle: *ctx.accounts.oracle.key,
             oracle_source,
@@ -2254,6 +2262,105 @@ pub fn handle_update_perp_market_fee_adjustment(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_matching_policy(
+    ctx: Context<AdminUpdatePerpMarket>,
+    matching_policy: MatchingPolicy,
+    pro_rata_min_allocation: u64,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    validate!(
+        pro_rata_min_allocation % perp_market.amm.order_step_size == 0,
+        ErrorCode::DefaultError,
+        "pro rata min allocation {} must be a multiple of step size {}",
+        pro_rata_min_allocation,
+        perp_market.amm.order_step_size
+    )?;
+
+    msg!(
+        "matching_policy {:?} -> {:?}",
+        perp_market.matching_policy,
+        matching_policy
+    );
+
+    perp_market.matching_policy = matching_policy;
+    perp_market.pro_rata_min_allocation = pro_rata_min_allocation;
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_batch_auction_interval(
+    ctx: Context<AdminUpdatePerpMarket>,
+    batch_auction_interval: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    msg!(
+        "batch_auction_interval {} -> {}",
+        perp_market.batch_auction_interval,
+        batch_auction_interval
+    );
+
+    perp_market.batch_auction_interval = batch_auction_interval;
+    perp_market.last_batch_auction_slot = Clock::get()?.slot;
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+    spot_market_valid(&ctx.accounts.spot_market)
+)]
+pub fn handle_update_perp_market_basis_margin_offset(
+    ctx: Context<AdminUpdatePerpMarketBasisSpotMarket>,
+    basis_margin_offset: u16,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+    let spot_market = &load!(ctx.accounts.spot_market)?;
+
+    validate!(
+        spot_market.market_index != QUOTE_SPOT_MARKET_INDEX,
+        ErrorCode::DefaultError,
+        "basis spot market can not be the quote spot market"
+    )?;
+
+    validate!(
+        spot_market.oracle == perp_market.amm.oracle,
+        ErrorCode::DefaultError,
+        "basis spot market oracle {} must match perp market oracle {}",
+        spot_market.oracle,
+        perp_market.amm.oracle
+    )?;
+
+    validate!(
+        basis_margin_offset.cast::<u32>()? <= MARGIN_PRECISION,
+        ErrorCode::DefaultError,
+        "basis margin offset {} must be <= {}",
+        basis_margin_offset,
+        MARGIN_PRECISION
+    )?;
+
+    msg!(
+        "basis_spot_market_index {} -> {}",
+        perp_market.basis_spot_market_index,
+        spot_market.market_index
+    );
+
+    msg!(
+        "basis_margin_offset {} -> {}",
+        perp_market.basis_margin_offset,
+        basis_margin_offset
+    );
+
+    perp_market.basis_spot_market_index = spot_market.market_index;
+    perp_market.basis_margin_offset = basis_margin_offset;
+    Ok(())
+}
+
 pub fn handle_update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> Result<()> {
     ctx.accounts.state.admin = admin;
     Ok(())
@@ -2627,6 +2734,18 @@ pub struct AdminUpdatePerpMarket<'info> {
     pub perp_market: AccountLoader<'info, PerpMarket>,
 }
 
+#[derive(Accounts)]
+pub struct AdminUpdatePerpMarketBasisSpotMarket<'info> {
+    pub admin: Signer<'info>,
+    #[account(
+        has_one = admin
+    )]
+    pub state: Box<Account<'info, State>>,
+    #[account(mut)]
+    pub perp_market: AccountLoader<'info, PerpMarket>,
+    pub spot_market: AccountLoader<'info, SpotMarket>,
+}
+
 #[derive(Accounts)]
 pub struct SettleExpiredMarketPoolsToRevenuePool<'info> {
     #[account(
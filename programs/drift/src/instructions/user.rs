@@ -25,6 +25,7 @@ use crate::load_mut;
 use crate::math::casting::Cast;
 use crate::math::liquidation::is_user_being_liquidated;
 use crate::math::margin::{
+    calculate_margin_requirement_and_total_collateral_and_liability_info,
     calculate_max_withdrawable_amount, meets_initial_margin_requirement,
     meets_withdraw_margin_requirement, validate_spot_margin_trading, MarginRequirementType,
 };
@@ -44,9 +45,11 @@ use crate::state::fill_mode::FillMode;
 use crate::state::fulfillment_params::drift::MatchFulfillmentParams;
 use crate::state::fulfillment_params::phoenix::PhoenixFulfillmentParams;
 use crate::state::fulfillment_params::serum::SerumFulfillmentParams;
+use crate::state::margin_calculation::MarginContext;
 use crate::state::oracle::StrictOraclePrice;
 use crate::state::order_params::{
-    ModifyOrderParams, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    ModifyOrderByIdParams, ModifyOrderParams, OrderParams, PlaceOrderOptions, PostOnlyParam,
+    ScaleOrderParams, SignedOrderParamsMessage,
 };
 use crate::state::paused_operations::PerpOperation;
 use crate::state::perp_market::MarketStatus;
@@ -59,9 +62,14 @@ use crate::state::spot_market_map::{
 };
 use crate::state::state::State;
 use crate::state::traits::Size;
-use crate::state::user::{MarketType, OrderType, ReferrerName, User, UserStats};
+use crate::state::user::{
+    migrate_legacy_user_data, MarketType, OrderType, PositionFlag, ReferrerName, TimeInForce, User,
+    UserStats, LEGACY_USER_SIZE,
+};
 use crate::state::user_map::load_user_maps;
 use crate::validate;
+use crate::validation::order::{validate_bracket_order_params, validate_scale_order_params};
+use crate::validation::sig_verification::get_ed25519_verified_message;
 use crate::validation::user::validate_user_deletion;
 use crate::validation::whitelist::validate_whitelist_token;
 use crate::{controller, math};
@@ -771,6 +779,206 @@ pub fn handle_transfer_deposit(
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_deposit_into_isolated_perp_position(
+    ctx: Context<TransferIsolatedPerpPositionDeposit>,
+    perp_market_index: u16,
+    amount: u64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let state = &ctx.accounts.state;
+    let user = &mut load_mut!(ctx.accounts.user)?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    validate!(
+        !user.is_being_liquidated(),
+        ErrorCode::UserIsBeingLiquidated,
+        "cant move collateral out of cross margin while being liquidated"
+    )?;
+
+    validate!(amount != 0, ErrorCode::InsufficientDeposit)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate!(
+        perp_market_map.get_ref(&perp_market_index)?.status == MarketStatus::Active,
+        ErrorCode::MarketActionPaused,
+        "perp market {} not active",
+        perp_market_index
+    )?;
+
+    {
+        let spot_market = &mut spot_market_map.get_ref_mut(&QUOTE_SPOT_MARKET_INDEX)?;
+        let oracle_price_data = oracle_map.get_price_data(&spot_market.oracle)?;
+        controller::spot_balance::update_spot_market_cumulative_interest(
+            spot_market,
+            Some(oracle_price_data),
+            clock.unix_timestamp,
+        )?;
+
+        let quote_spot_position = user.get_quote_spot_position_mut();
+        validate!(
+            quote_spot_position.balance_type == SpotBalanceType::Deposit
+                && quote_spot_position.get_token_amount(spot_market)? >= amount.cast()?,
+            ErrorCode::InsufficientCollateral,
+            "not enough quote deposits to move {} into isolated perp position",
+            amount
+        )?;
+
+        controller::spot_balance::update_spot_balances(
+            amount.cast()?,
+            &SpotBalanceType::Borrow,
+            spot_market,
+            quote_spot_position,
+            false,
+        )?;
+
+        let perp_position = user.force_get_perp_position_mut(perp_market_index)?;
+        validate!(
+            perp_position.is_isolated() || perp_position.is_available(),
+            ErrorCode::InvalidIsolatedPerpPosition,
+            "perp position in market {} is already cross margined",
+            perp_market_index
+        )?;
+
+        perp_position.add_position_flag(PositionFlag::IsolatedPosition);
+
+        controller::spot_balance::update_spot_balances(
+            amount.cast()?,
+            &SpotBalanceType::Deposit,
+            spot_market,
+            perp_position,
+            false,
+        )?;
+    }
+
+    meets_withdraw_margin_requirement(
+        user,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        MarginRequirementType::Initial,
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_withdraw_from_isolated_perp_position(
+    ctx: Context<TransferIsolatedPerpPositionDeposit>,
+    perp_market_index: u16,
+    amount: u64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let state = &ctx.accounts.state;
+    let user = &mut load_mut!(ctx.accounts.user)?;
+
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+
+    validate!(amount != 0, ErrorCode::InsufficientDeposit)?;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &get_writable_spot_market_set(QUOTE_SPOT_MARKET_INDEX),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    {
+        let spot_market = &mut spot_market_map.get_ref_mut(&QUOTE_SPOT_MARKET_INDEX)?;
+        let oracle_price_data = oracle_map.get_price_data(&spot_market.oracle)?;
+        controller::spot_balance::update_spot_market_cumulative_interest(
+            spot_market,
+            Some(oracle_price_data),
+            clock.unix_timestamp,
+        )?;
+
+        let perp_position = user.get_perp_position_mut(perp_market_index)?;
+        validate!(
+            perp_position.is_isolated(),
+            ErrorCode::InvalidIsolatedPerpPosition,
+            "perp position in market {} is not isolated",
+            perp_market_index
+        )?;
+
+        validate!(
+            !perp_position.is_being_liquidated(),
+            ErrorCode::UserIsBeingLiquidated,
+            "isolated perp position in market {} is being liquidated",
+            perp_market_index
+        )?;
+
+        validate!(
+            perp_position.get_isolated_token_amount(spot_market)? >= amount.cast()?,
+            ErrorCode::InsufficientCollateral,
+            "isolated perp position in market {} has less than {} collateral",
+            perp_market_index,
+            amount
+        )?;
+
+        controller::spot_balance::update_spot_balances(
+            amount.cast()?,
+            &SpotBalanceType::Borrow,
+            spot_market,
+            perp_position,
+            false,
+        )?;
+
+        controller::spot_balance::update_spot_balances(
+            amount.cast()?,
+            &SpotBalanceType::Deposit,
+            spot_market,
+            user.get_quote_spot_position_mut(),
+            false,
+        )?;
+    }
+
+    let margin_calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        MarginContext::standard(MarginRequirementType::Initial)
+            .strict(true)
+            .isolated_perp_position(perp_market_index),
+    )?;
+
+    validate!(
+        margin_calculation.meets_margin_requirement(),
+        ErrorCode::InsufficientCollateral,
+        "isolated perp position in market {} would have total_collateral {} below initial_margin_requirement {}",
+        perp_market_index,
+        margin_calculation.total_collateral,
+        margin_calculation.margin_requirement
+    )?;
+
+    user.update_last_active_slot(clock.slot);
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -790,7 +998,7 @@ pub fn handle_place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         Some(state.oracle_guard_rails),
     )?;
 
-    if params.immediate_or_cancel {
+    if params.is_immediate_or_cancel() {
         msg!("immediate_or_cancel order must be in place_and_make or place_and_take");
         return Err(print_error!(ErrorCode::InvalidOrderIOC)().into());
     }
@@ -880,6 +1088,40 @@ pub fn handle_cancel_order_by_user_id(ctx: Context<CancelOrder>, user_order_id:
     Ok(())
 }
 
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_order_by_client_id(
+    ctx: Context<CancelOrder>,
+    client_order_id: u64,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    controller::orders::cancel_order_by_client_order_id(
+        client_order_id,
+        &ctx.accounts.user,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+    )?;
+
+    Ok(())
+}
+
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
@@ -953,6 +1195,7 @@ pub fn handle_cancel_orders(
         market_type,
         market_index,
         direction,
+        false,
     )?;
 
     Ok(())
@@ -1040,7 +1283,47 @@ pub fn handle_modify_order_by_user_order_id(
 #[access_control(
     exchange_not_paused(&ctx.accounts.state)
 )]
-pub fn handle_place_orders(ctx: Context<PlaceOrder>, params: Vec<OrderParams>) -> Result<()> {
+pub fn handle_modify_order_by_client_order_id(
+    ctx: Context<CancelOrder>,
+    client_order_id: u64,
+    modify_order_params: ModifyOrderParams,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    controller::orders::modify_order(
+        ModifyOrderId::ClientOrderId(client_order_id),
+        modify_order_params,
+        &ctx.accounts.user,
+        state,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_modify_orders(
+    ctx: Context<CancelOrder>,
+    modify_orders_params: Vec<ModifyOrderByIdParams>,
+) -> Result<()> {
     let clock = &Clock::get()?;
     let state = &ctx.accounts.state;
 
@@ -1057,18 +1340,154 @@ pub fn handle_place_orders(ctx: Context<PlaceOrder>, params: Vec<OrderParams>) -
     )?;
 
     validate!(
-        params.len() <= 32,
+        modify_orders_params.len() <= 32,
         ErrorCode::DefaultError,
-        "max 32 order params"
+        "max 32 modify order params"
+    )?;
+
+    controller::orders::modify_orders(
+        modify_orders_params,
+        &ctx.accounts.user,
+        state,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_orders(ctx: Context<PlaceOrder>, params: Vec<OrderParams>) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::place_orders(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        &params,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_cancel_and_place_orders(
+    ctx: Context<PlaceOrder>,
+    cancel_market_type: Option<MarketType>,
+    cancel_market_index: Option<u16>,
+    cancel_direction: Option<PositionDirection>,
+    cancel_order_ids: Vec<u32>,
+    params: Vec<OrderParams>,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::cancel_and_place_orders(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        cancel_market_type,
+        cancel_market_index,
+        cancel_direction,
+        &cancel_order_ids,
+        &params,
+    )?;
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_bracket_orders(
+    ctx: Context<PlaceOrder>,
+    entry_params: Option<OrderParams>,
+    take_profit_params: OrderParams,
+    stop_loss_params: OrderParams,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate_bracket_order_params(
+        entry_params.as_ref(),
+        &take_profit_params,
+        &stop_loss_params,
     )?;
 
     let user_key = ctx.accounts.user.key();
     let mut user = load_mut!(ctx.accounts.user)?;
 
-    let num_orders = params.len();
-    for (i, params) in params.iter().enumerate() {
+    // take profit and stop loss are one-cancels-other. the group id is the id of the first order placed
+    let group_id = user.next_order_id;
+
+    let mut bracket_params = Vec::with_capacity(3);
+    if let Some(entry_params) = entry_params {
+        bracket_params.push((entry_params, 0));
+    }
+    bracket_params.push((take_profit_params, group_id));
+    bracket_params.push((stop_loss_params, group_id));
+
+    let num_orders = bracket_params.len();
+    for (i, (params, group_id)) in bracket_params.into_iter().enumerate() {
         validate!(
-            !params.immediate_or_cancel,
+            !params.is_immediate_or_cancel(),
             ErrorCode::InvalidOrderIOC,
             "immediate_or_cancel order must be in place_and_make or place_and_take"
         )?;
@@ -1079,33 +1498,77 @@ pub fn handle_place_orders(ctx: Context<PlaceOrder>, params: Vec<OrderParams>) -
             try_expire_orders: i == 0,
             risk_increasing: false,
             explanation: OrderActionExplanation::None,
+            group_id,
         };
 
-        if params.market_type == MarketType::Perp {
-            controller::orders::place_perp_order(
-                &ctx.accounts.state,
-                &mut user,
-                user_key,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                clock,
-                *params,
-                options,
-            )?;
-        } else {
-            controller::orders::place_spot_order(
-                &ctx.accounts.state,
-                &mut user,
-                user_key,
-                &perp_market_map,
-                &spot_market_map,
-                &mut oracle_map,
-                clock,
-                *params,
-                options,
-            )?;
-        }
+        controller::orders::place_perp_order(
+            &ctx.accounts.state,
+            &mut user,
+            user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock,
+            params,
+            options,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[access_control(
+    exchange_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_scale_orders(ctx: Context<PlaceOrder>, params: ScaleOrderParams) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        &mut ctx.remaining_accounts.iter().peekable(),
+        &MarketSet::new(),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    validate_scale_order_params(&params)?;
+
+    let scale_params = {
+        let perp_market = perp_market_map.get_ref(&params.market_index)?;
+        params.get_order_params(
+            perp_market.amm.order_tick_size,
+            perp_market.amm.order_step_size,
+        )?
+    };
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    let num_orders = scale_params.len();
+    for (i, params) in scale_params.into_iter().enumerate() {
+        // only enforce margin on last order and only try to expire on first order
+        let options = PlaceOrderOptions {
+            enforce_margin_check: i == num_orders - 1,
+            try_expire_orders: i == 0,
+            ..PlaceOrderOptions::default()
+        };
+
+        controller::orders::place_perp_order(
+            &ctx.accounts.state,
+            &mut user,
+            user_key,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock,
+            params,
+            options,
+        )?;
     }
 
     Ok(())
@@ -1143,7 +1606,8 @@ pub fn handle_place_and_take_perp_order<'info>(
     let (makers_and_referrer, makers_and_referrer_stats) =
         load_user_maps(remaining_accounts_iter, true)?;
 
-    let is_immediate_or_cancel = params.immediate_or_cancel;
+    let is_immediate_or_cancel = params.is_immediate_or_cancel();
+    let is_fill_or_kill = params.get_time_in_force() == TimeInForce::FillOrKill;
 
     controller::repeg::update_amm(
         params.market_index,
@@ -1171,9 +1635,16 @@ pub fn handle_place_and_take_perp_order<'info>(
     drop(user);
 
     let user = &mut ctx.accounts.user;
-    let order_id = load!(user)?.get_last_order_id();
+    let (order_id, order_base_asset_amount) = {
+        let user = load!(user)?;
+        let order_id = user.get_last_order_id();
+        let order_base_asset_amount = user
+            .get_order(order_id)
+            .map_or(0, |order| order.base_asset_amount);
+        (order_id, order_base_asset_amount)
+    };
 
-    controller::orders::fill_perp_order(
+    let base_asset_amount_filled = controller::orders::fill_perp_order(
         order_id,
         &ctx.accounts.state,
         user,
@@ -1195,6 +1666,12 @@ pub fn handle_place_and_take_perp_order<'info>(
         .iter()
         .any(|order| order.order_id == order_id);
 
+    // the order can be canceled without filling, so check the fill size rather than the order
+    if is_fill_or_kill && base_asset_amount_filled < order_base_asset_amount {
+        msg!("fill or kill order {} was not completely filled", order_id);
+        return Err(print_error!(ErrorCode::FillOrKillOrderNotFilled)().into());
+    }
+
     if is_immediate_or_cancel && order_exists {
         controller::orders::cancel_order_by_order_id(
             order_id,
@@ -1233,7 +1710,7 @@ pub fn handle_place_and_make_perp_order<'a, 'b, 'c, 'info>(
         Some(state.oracle_guard_rails),
     )?;
 
-    if !params.immediate_or_cancel
+    if !params.is_immediate_or_cancel()
         || params.post_only == PostOnlyParam::None
         || params.order_type != OrderType::Limit
     {
@@ -1309,6 +1786,224 @@ pub fn handle_place_and_make_perp_order<'a, 'b, 'c, 'info>(
     Ok(())
 }
 
+#[access_control(
+    fill_not_paused(&ctx.accounts.state)
+)]
+pub fn handle_place_and_make_signed_perp_order<'a, 'b, 'c, 'info>(
+    ctx: Context<'a, 'b, 'c, 'info, PlaceAndMakeSignedOrder<'info>>,
+    params: OrderParams,
+) -> Result<()> {
+    let clock = &Clock::get()?;
+    let state = &ctx.accounts.state;
+
+    if !params.is_immediate_or_cancel()
+        || params.post_only == PostOnlyParam::None
+        || params.order_type != OrderType::Limit
+    {
+        msg!("place_and_make must use IOC post only limit order");
+        return Err(print_error!(ErrorCode::InvalidOrderIOCPostOnly)().into());
+    }
+
+    let taker_key = ctx.accounts.taker.key();
+    let (taker_authority, taker_sub_account_id, taker_last_signed_order_nonce) = {
+        let taker = load!(ctx.accounts.taker)?;
+        (
+            taker.authority,
+            taker.sub_account_id,
+            taker.last_signed_order_nonce,
+        )
+    };
+
+    // The taker's signed message is verified by the ed25519 program in the preceding instruction
+    let ixs = ctx.accounts.instructions.as_ref();
+    let current_index = instructions::load_current_index_checked(ixs)? as usize;
+    validate!(
+        current_index > 0,
+        ErrorCode::SigVerificationFailed,
+        "signed order must be preceded by an ed25519 instruction"
+    )?;
+    let ed25519_ix = instructions::load_instruction_at_checked(current_index - 1, ixs)?;
+    let message = get_ed25519_verified_message(&ed25519_ix, &taker_authority.to_bytes())?;
+
+    let SignedOrderParamsMessage {
+        signed_order_params,
+        sub_account_id,
+        nonce,
+        expiry_slot,
+    } = SignedOrderParamsMessage::try_from_slice(message).map_err(|_| {
+        msg!("could not deserialize signed order params message");
+        ErrorCode::SigVerificationFailed
+    })?;
+
+    validate!(
+        sub_account_id == taker_sub_account_id,
+        ErrorCode::InvalidSignedOrder,
+        "signed order sub account id {} does not match taker {}",
+        sub_account_id,
+        taker_sub_account_id
+    )?;
+
+    validate!(
+        clock.slot <= expiry_slot,
+        ErrorCode::SignedOrderExpired,
+        "signed order expired at slot {}, current slot {}",
+        expiry_slot,
+        clock.slot
+    )?;
+
+    validate!(
+        nonce > taker_last_signed_order_nonce,
+        ErrorCode::InvalidSignedOrderNonce,
+        "signed order nonce {} must be greater than last nonce {}",
+        nonce,
+        taker_last_signed_order_nonce
+    )?;
+
+    validate!(
+        signed_order_params.market_type == MarketType::Perp
+            && signed_order_params.market_index == params.market_index,
+        ErrorCode::InvalidSignedOrder,
+        "signed order must be for perp market {}",
+        params.market_index
+    )?;
+
+    let remaining_accounts_iter = &mut ctx.remaining_accounts.iter().peekable();
+    let AccountMaps {
+        perp_market_map,
+        spot_market_map,
+        mut oracle_map,
+    } = load_maps(
+        remaining_accounts_iter,
+        &get_writable_perp_market_set(params.market_index),
+        &MarketSet::new(),
+        clock.slot,
+        Some(state.oracle_guard_rails),
+    )?;
+
+    controller::repeg::update_amm(
+        params.market_index,
+        &perp_market_map,
+        &mut oracle_map,
+        state,
+        clock,
+    )?;
+
+    let mut taker = load_mut!(ctx.accounts.taker)?;
+    let taker_next_order_id = taker.next_order_id;
+
+    controller::orders::place_perp_order(
+        state,
+        &mut taker,
+        taker_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        signed_order_params,
+        PlaceOrderOptions::default(),
+    )?;
+
+    validate!(
+        taker.next_order_id != taker_next_order_id,
+        ErrorCode::InvalidSignedOrder,
+        "signed order was not placed"
+    )?;
+
+    taker.last_signed_order_nonce = nonce;
+    let taker_order_id = taker.get_last_order_id();
+    let taker_order_base_asset_amount = taker
+        .get_order(taker_order_id)
+        .map_or(0, |order| order.base_asset_amount);
+
+    drop(taker);
+
+    let user_key = ctx.accounts.user.key();
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    controller::orders::place_perp_order(
+        state,
+        &mut user,
+        user_key,
+        &perp_market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock,
+        params,
+        PlaceOrderOptions::default(),
+    )?;
+
+    let (order_id, authority) = (user.get_last_order_id(), user.authority);
+
+    drop(user);
+
+    let (mut makers_and_referrer, mut makers_and_referrer_stats) =
+        load_user_maps(remaining_accounts_iter, true)?;
+    makers_and_referrer.insert(ctx.accounts.user.key(), ctx.accounts.user.clone())?;
+    makers_and_referrer_stats.insert(authority, ctx.accounts.user_stats.clone())?;
+
+    let taker_base_asset_amount_filled = controller::orders::fill_perp_order(
+        taker_order_id,
+        state,
+        &ctx.accounts.taker,
+        &ctx.accounts.taker_stats,
+        &spot_market_map,
+        &perp_market_map,
+        &mut oracle_map,
+        &ctx.accounts.user.clone(),
+        &ctx.accounts.user_stats.clone(),
+        &makers_and_referrer,
+        &makers_and_referrer_stats,
+        Some(order_id),
+        clock,
+        FillMode::PlaceAndMake,
+    )?;
+
+    let order_exists = load!(ctx.accounts.user)?
+        .orders
+        .iter()
+        .any(|order| order.order_id == order_id);
+
+    if order_exists {
+        controller::orders::cancel_order_by_order_id(
+            order_id,
+            &ctx.accounts.user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock,
+        )?;
+    }
+
+    let taker_time_in_force = signed_order_params.get_time_in_force();
+    if taker_time_in_force == TimeInForce::FillOrKill
+        && taker_base_asset_amount_filled < taker_order_base_asset_amount
+    {
+        msg!(
+            "fill or kill order {} was not completely filled",
+            taker_order_id
+        );
+        return Err(print_error!(ErrorCode::FillOrKillOrderNotFilled)().into());
+    }
+
+    let taker_order_exists = load!(ctx.accounts.taker)?
+        .orders
+        .iter()
+        .any(|order| order.order_id == taker_order_id);
+
+    if taker_order_exists && taker_time_in_force == TimeInForce::ImmediateOrCancel {
+        controller::orders::cancel_order_by_order_id(
+            taker_order_id,
+            &ctx.accounts.taker,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            clock,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn handle_place_spot_order(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
     let AccountMaps {
         perp_market_map,
@@ -1322,7 +2017,7 @@ pub fn handle_place_spot_order(ctx: Context<PlaceOrder>, params: OrderParams) ->
         None,
     )?;
 
-    if params.immediate_or_cancel {
+    if params.is_immediate_or_cancel() {
         msg!("immediate_or_cancel order must be in place_and_make or place_and_take");
         return Err(print_error!(ErrorCode::InvalidOrderIOC)().into());
     }
@@ -1385,7 +2080,7 @@ pub fn handle_place_and_take_spot_order<'info>(
 
     let (_referrer, _referrer_stats) = get_referrer_and_referrer_stats(remaining_accounts_iter)?;
 
-    let is_immediate_or_cancel = params.immediate_or_cancel;
+    let is_immediate_or_cancel = params.is_immediate_or_cancel();
 
     let mut fulfillment_params: Box<dyn SpotFulfillmentParams> = match fulfillment_type {
         SpotFulfillmentType::SerumV3 => {
@@ -1507,7 +2202,7 @@ pub fn handle_place_and_make_spot_order<'info>(
 
     let (_referrer, _referrer_stats) = get_referrer_and_referrer_stats(remaining_accounts_iter)?;
 
-    if !params.immediate_or_cancel
+    if !params.is_immediate_or_cancel()
         || params.post_only == PostOnlyParam::None
         || params.order_type != OrderType::Limit
     {
@@ -1887,6 +2582,47 @@ pub fn handle_update_user_advanced_lp(
     Ok(())
 }
 
+pub fn handle_update_user_market_maker_protection(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    market_index: u16,
+    fill_limit_base_asset_amount: u64,
+    window_slots: u32,
+    cooldown_slots: u32,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    user.update_market_maker_protection(
+        market_index,
+        fill_limit_base_asset_amount,
+        window_slots,
+        cooldown_slots,
+    )?;
+    Ok(())
+}
+
+pub fn handle_update_perp_position_take_profit_stop_loss(
+    ctx: Context<UpdateUser>,
+    _sub_account_id: u16,
+    market_index: u16,
+    take_profit_price: u64,
+    stop_loss_price: u64,
+) -> Result<()> {
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    user.get_perp_position_mut(market_index)?
+        .update_take_profit_stop_loss(take_profit_price, stop_loss_price)?;
+    Ok(())
+}
+
+pub fn handle_heartbeat(ctx: Context<Heartbeat>, cancel_after_slots: u64) -> Result<()> {
+    let clock = Clock::get()?;
+    let mut user = load_mut!(ctx.accounts.user)?;
+
+    user.update_cancel_after_slot(cancel_after_slots, clock.slot)?;
+    Ok(())
+}
+
 pub fn handle_delete_user(ctx: Context<DeleteUser>) -> Result<()> {
     let user = &load!(ctx.accounts.user)?;
     let user_stats = &mut load_mut!(ctx.accounts.user_stats)?;
@@ -1946,6 +2682,49 @@ pub fn handle_reclaim_rent(ctx: Context<ReclaimRent>) -> Result<()> {
     Ok(())
 }
 
+pub fn handle_migrate_user(ctx: Context<MigrateUser>) -> Result<()> {
+    let user = &ctx.accounts.user;
+
+    let legacy_data = {
+        let data = user.try_borrow_data()?;
+
+        validate!(
+            data.len() == LEGACY_USER_SIZE,
+            ErrorCode::InvalidUserMigration,
+            "user account size {} is not the legacy size {}",
+            data.len(),
+            LEGACY_USER_SIZE
+        )?;
+
+        validate!(
+            data[..8] == User::discriminator(),
+            ErrorCode::InvalidUserMigration,
+            "account is not a user"
+        )?;
+
+        data.to_vec()
+    };
+
+    let minimum_lamports = Rent::get()?.minimum_balance(User::SIZE);
+    let lamports_needed = minimum_lamports.saturating_sub(user.try_lamports()?);
+    if lamports_needed > 0 {
+        invoke(
+            &transfer(&ctx.accounts.payer.key(), &user.key(), lamports_needed),
+            &[
+                ctx.accounts.payer.to_account_info().clone(),
+                user.clone(),
+                ctx.accounts.system_program.to_account_info().clone(),
+            ],
+        )?;
+    }
+
+    user.realloc(User::SIZE, true)?;
+
+    migrate_legacy_user_data(&legacy_data, &mut user.try_borrow_mut_data()?)?;
+
+    Ok(())
+}
+
 #[access_control(
     deposit_not_paused(&ctx.accounts.state)
 )]
@@ -2179,6 +2958,17 @@ pub struct TransferDeposit<'info> {
     pub spot_market_vault: Box<Account<'info, TokenAccount>>,
 }
 
+#[derive(Accounts)]
+pub struct TransferIsolatedPerpPositionDeposit<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        has_one = authority,
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct PlaceOrder<'info> {
     pub state: Box<Account<'info, State>>,
@@ -2190,6 +2980,16 @@ pub struct PlaceOrder<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct Heartbeat<'info> {
+    #[account(
+        mut,
+        constraint = can_sign_for_user(&user, &authority)?
+    )]
+    pub user: AccountLoader<'info, User>,
+    pub authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct CancelOrder<'info> {
     pub state: Box<Account<'info, State>>,
@@ -2240,6 +3040,33 @@ pub struct PlaceAndMake<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct PlaceAndMakeSignedOrder<'info> {
+    pub state: Box<Account<'info, State>>,
+    #[account(
+        mut,
+        constraint = can_sign_for_user(&user, &authority)?
+    )]
+    pub user: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&user, &user_stats)?
+    )]
+    pub user_stats: AccountLoader<'info, UserStats>,
+    #[account(mut)]
+    pub taker: AccountLoader<'info, User>,
+    #[account(
+        mut,
+        constraint = is_stats_for_user(&taker, &taker_stats)?
+    )]
+    pub taker_stats: AccountLoader<'info, UserStats>,
+    pub authority: Signer<'info>,
+    /// Instructions Sysvar for instruction introspection
+    /// CHECK: fixed instructions sysvar account
+    #[account(address = instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+}
+
 #[derive(Accounts)]
 pub struct AddRemoveLiquidity<'info> {
     pub state: Box<Account<'info, State>>,
@@ -2307,6 +3134,19 @@ pub struct ReclaimRent<'info> {
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+pub struct MigrateUser<'info> {
+    /// CHECK: legacy user accounts are too small to load, checked in `migrate_user` ix
+    #[account(
+        mut,
+        owner = crate::ID
+    )]
+    pub user: AccountInfo<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(in_market_index: u16, out_market_index: u16, )]
 pub struct Swap<'info> {
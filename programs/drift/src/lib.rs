@@ -11,8 +11,10 @@ use math::{bn, constants::*};
 use state::oracle::OracleSource;
 
 use crate::controller::position::PositionDirection;
-use crate::state::order_params::{ModifyOrderParams, OrderParams};
-use crate::state::perp_market::{ContractTier, MarketStatus};
+use crate::state::order_params::{
+    ModifyOrderByIdParams, ModifyOrderParams, OrderParams, ScaleOrderParams,
+};
+use crate::state::perp_market::{ContractTier, MarketStatus, MatchingPolicy};
 use crate::state::spot_market::AssetTier;
 use crate::state::spot_market::SpotFulfillmentConfigStatus;
 use crate::state::state::FeeStructure;
@@ -88,6 +90,22 @@ pub mod drift {
         handle_transfer_deposit(ctx, market_index, amount)
     }
 
+    pub fn deposit_into_isolated_perp_position(
+        ctx: Context<TransferIsolatedPerpPositionDeposit>,
+        perp_market_index: u16,
+        amount: u64,
+    ) -> Result<()> {
+        handle_deposit_into_isolated_perp_position(ctx, perp_market_index, amount)
+    }
+
+    pub fn withdraw_from_isolated_perp_position(
+        ctx: Context<TransferIsolatedPerpPositionDeposit>,
+        perp_market_index: u16,
+        amount: u64,
+    ) -> Result<()> {
+        handle_withdraw_from_isolated_perp_position(ctx, perp_market_index, amount)
+    }
+
     pub fn place_perp_order(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
         handle_place_perp_order(ctx, params)
     }
@@ -100,6 +118,13 @@ pub mod drift {
         handle_cancel_order_by_user_id(ctx, user_order_id)
     }
 
+    pub fn cancel_order_by_client_id(
+        ctx: Context<CancelOrder>,
+        client_order_id: u64,
+    ) -> Result<()> {
+        handle_cancel_order_by_client_id(ctx, client_order_id)
+    }
+
     pub fn cancel_orders(
         ctx: Context<CancelOrder>,
         market_type: Option<MarketType>,
@@ -129,6 +154,21 @@ pub mod drift {
         handle_modify_order_by_user_order_id(ctx, user_order_id, modify_order_params)
     }
 
+    pub fn modify_order_by_client_id(
+        ctx: Context<CancelOrder>,
+        client_order_id: u64,
+        modify_order_params: ModifyOrderParams,
+    ) -> Result<()> {
+        handle_modify_order_by_client_order_id(ctx, client_order_id, modify_order_params)
+    }
+
+    pub fn modify_orders(
+        ctx: Context<CancelOrder>,
+        modify_orders_params: Vec<ModifyOrderByIdParams>,
+    ) -> Result<()> {
+        handle_modify_orders(ctx, modify_orders_params)
+    }
+
     pub fn place_and_take_perp_order(
         ctx: Context<PlaceAndTake>,
         params: OrderParams,
@@ -145,6 +185,13 @@ pub mod drift {
         handle_place_and_make_perp_order(ctx, params, taker_order_id)
     }
 
+    pub fn place_and_make_signed_perp_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, PlaceAndMakeSignedOrder<'info>>,
+        params: OrderParams,
+    ) -> Result<()> {
+        handle_place_and_make_signed_perp_order(ctx, params)
+    }
+
     pub fn place_spot_order(ctx: Context<PlaceOrder>, params: OrderParams) -> Result<()> {
         handle_place_spot_order(ctx, params)
     }
@@ -181,6 +228,37 @@ pub mod drift {
         handle_place_orders(ctx, params)
     }
 
+    pub fn cancel_and_place_orders(
+        ctx: Context<PlaceOrder>,
+        cancel_market_type: Option<MarketType>,
+        cancel_market_index: Option<u16>,
+        cancel_direction: Option<PositionDirection>,
+        cancel_order_ids: Vec<u32>,
+        params: Vec<OrderParams>,
+    ) -> Result<()> {
+        handle_cancel_and_place_orders(
+            ctx,
+            cancel_market_type,
+            cancel_market_index,
+            cancel_direction,
+            cancel_order_ids,
+            params,
+        )
+    }
+
+    pub fn place_bracket_orders(
+        ctx: Context<PlaceOrder>,
+        entry_params: Option<OrderParams>,
+        take_profit_params: OrderParams,
+        stop_loss_params: OrderParams,
+    ) -> Result<()> {
+        handle_place_bracket_orders(ctx, entry_params, take_profit_params, stop_loss_params)
+    }
+
+    pub fn place_scale_orders(ctx: Context<PlaceOrder>, params: ScaleOrderParams) -> Result<()> {
+        handle_place_scale_orders(ctx, params)
+    }
+
     pub fn begin_swap(
         ctx: Context<Swap>,
         in_market_index: u16,
@@ -278,6 +356,44 @@ pub mod drift {
         handle_update_user_advanced_lp(ctx, _sub_account_id, advanced_lp)
     }
 
+    pub fn update_user_market_maker_protection(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        market_index: u16,
+        fill_limit_base_asset_amount: u64,
+        window_slots: u32,
+        cooldown_slots: u32,
+    ) -> Result<()> {
+        handle_update_user_market_maker_protection(
+            ctx,
+            _sub_account_id,
+            market_index,
+            fill_limit_base_asset_amount,
+            window_slots,
+            cooldown_slots,
+        )
+    }
+
+    pub fn update_perp_position_take_profit_stop_loss(
+        ctx: Context<UpdateUser>,
+        _sub_account_id: u16,
+        market_index: u16,
+        take_profit_price: u64,
+        stop_loss_price: u64,
+    ) -> Result<()> {
+        handle_update_perp_position_take_profit_stop_loss(
+            ctx,
+            _sub_account_id,
+            market_index,
+            take_profit_price,
+            stop_loss_price,
+        )
+    }
+
+    pub fn heartbeat(ctx: Context<Heartbeat>, cancel_after_slots: u64) -> Result<()> {
+        handle_heartbeat(ctx, cancel_after_slots)
+    }
+
     pub fn delete_user(ctx: Context<DeleteUser>) -> Result<()> {
         handle_delete_user(ctx)
     }
@@ -286,6 +402,10 @@ pub mod drift {
         handle_reclaim_rent(ctx)
     }
 
+    pub fn migrate_user(ctx: Context<MigrateUser>) -> Result<()> {
+        handle_migrate_user(ctx)
+    }
+
     // Keeper Instructions
 
     pub fn fill_perp_order(
@@ -300,6 +420,13 @@ pub mod drift {
         handle_revert_fill(ctx)
     }
 
+    pub fn clear_perp_batch_auction<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClearPerpBatchAuction<'info>>,
+        market_index: u16,
+    ) -> Result<()> {
+        handle_clear_perp_batch_auction(ctx, market_index)
+    }
+
     pub fn fill_spot_order<'info>(
         ctx: Context<'_, '_, '_, 'info, FillOrder<'info>>,
         order_id: Option<u32>,
@@ -313,10 +440,21 @@ pub mod drift {
         handle_trigger_order(ctx, order_id)
     }
 
+    pub fn trigger_perp_position_take_profit_stop_loss(
+        ctx: Context<TriggerOrder>,
+        market_index: u16,
+    ) -> Result<()> {
+        handle_trigger_perp_position_take_profit_stop_loss(ctx, market_index)
+    }
+
     pub fn force_cancel_orders(ctx: Context<ForceCancelOrder>) -> Result<()> {
         handle_force_cancel_orders(ctx)
     }
 
+    pub fn cancel_orders_on_missed_heartbeat(ctx: Context<ForceCancelOrder>) -> Result<()> {
+        handle_cancel_orders_on_missed_heartbeat(ctx)
+    }
+
     pub fn update_user_idle(ctx: Context<UpdateUserIdle>) -> Result<()> {
         handle_update_user_idle(ctx)
     }
@@ -1081,6 +1219,28 @@ pub mod drift {
         handle_update_perp_market_fee_adjustment(ctx, fee_adjustment)
     }
 
+    pub fn update_perp_market_matching_policy(
+        ctx: Context<AdminUpdatePerpMarket>,
+        matching_policy: MatchingPolicy,
+        pro_rata_min_allocation: u64,
+    ) -> Result<()> {
+        handle_update_perp_market_matching_policy(ctx, matching_policy, pro_rata_min_allocation)
+    }
+
+    pub fn update_perp_market_batch_auction_interval(
+        ctx: Context<AdminUpdatePerpMarket>,
+        batch_auction_interval: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_batch_auction_interval(ctx, batch_auction_interval)
+    }
+
+    pub fn update_perp_market_basis_margin_offset(
+        ctx: Context<AdminUpdatePerpMarketBasisSpotMarket>,
+        basis_margin_offset: u16,
+    ) -> Result<()> {
+        handle_update_perp_market_basis_margin_offset(ctx, basis_margin_offset)
+    }
+
     pub fn update_admin(ctx: Context<AdminUpdateState>, admin: Pubkey) -> Result<()> {
         handle_update_admin(ctx, admin)
     }
@@ -93,7 +93,7 @@ pub fn calculate_auction_price(
         | OrderType::TriggerLimit => {
             calculate_auction_price_for_fixed_auction(order, slot, tick_size)
         }
-        OrderType::Oracle => calculate_auction_price_for_oracle_offset_auction(
+        OrderType::Oracle | OrderType::Twap => calculate_auction_price_for_oracle_offset_auction(
             order,
             slot,
             tick_size,
@@ -107,7 +107,7 @@ fn calculate_auction_price_for_fixed_auction(
     slot: u64,
     tick_size: u64,
 ) -> DriftResult<u64> {
-    let slots_elapsed = slot.safe_sub(order.slot)?;
+    let slots_elapsed = slot.safe_sub(order.get_auction_start_slot(slot)?)?;
 
     let delta_numerator = min(slots_elapsed, order.auction_duration.cast()?);
     let delta_denominator = order.auction_duration;
@@ -149,7 +149,7 @@ fn calculate_auction_price_for_oracle_offset_auction(
         ErrorCode::OracleNotFound
     })?;
 
-    let slots_elapsed = slot.safe_sub(order.slot)?;
+    let slots_elapsed = slot.safe_sub(order.get_auction_start_slot(slot)?)?;
 
     let delta_numerator = min(slots_elapsed, order.auction_duration.cast()?);
     let delta_denominator = order.auction_duration;
@@ -213,7 +213,11 @@ pub fn is_amm_available_liquidity_source(
     min_auction_duration: u8,
     slot: u64,
 ) -> DriftResult<bool> {
-    is_auction_complete(order.slot, min_auction_duration, slot)
+    is_auction_complete(
+        order.get_auction_start_slot(slot)?,
+        min_auction_duration,
+        slot,
+    )
 }
 
 pub fn calculate_auction_params_for_trigger_order(
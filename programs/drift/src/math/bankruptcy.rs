@@ -1,5 +1,5 @@
 use crate::state::spot_market::SpotBalanceType;
-use crate::state::user::User;
+use crate::state::user::{PerpPosition, User};
 
 #[cfg(test)]
 mod tests;
@@ -19,6 +19,11 @@ pub fn is_user_bankrupt(user: &User) -> bool {
     }
 
     for perp_position in user.perp_positions.iter() {
+        // isolated positions go bankrupt on their own
+        if perp_position.is_isolated() {
+            continue;
+        }
+
         if perp_position.base_asset_amount != 0
             || perp_position.quote_asset_amount > 0
             || perp_position.has_open_order()
@@ -34,3 +39,14 @@ pub fn is_user_bankrupt(user: &User) -> bool {
 
     has_liability
 }
+
+pub fn is_isolated_perp_position_bankrupt(perp_position: &PerpPosition) -> bool {
+    // an isolated position is bankrupt iff it has no collateral, no exposure and negative pnl
+
+    perp_position.is_isolated()
+        && perp_position.isolated_position_scaled_balance == 0
+        && perp_position.base_asset_amount == 0
+        && !perp_position.has_open_order()
+        && !perp_position.is_lp()
+        && perp_position.quote_asset_amount < 0
+}
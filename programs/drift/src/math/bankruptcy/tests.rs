@@ -1,6 +1,6 @@
-use crate::math::bankruptcy::is_user_bankrupt;
+use crate::math::bankruptcy::{is_isolated_perp_position_bankrupt, is_user_bankrupt};
 use crate::state::spot_market::SpotBalanceType;
-use crate::state::user::{PerpPosition, SpotPosition, User};
+use crate::state::user::{PerpPosition, PositionFlag, SpotPosition, User};
 use crate::test_utils::{get_positions, get_spot_positions};
 
 #[test]
@@ -81,3 +81,34 @@ fn user_with_empty_position_and_balances() {
     let is_bankrupt = is_user_bankrupt(&user);
     assert!(!is_bankrupt);
 }
+
+#[test]
+fn user_with_isolated_position_with_negative_quote() {
+    let user = User {
+        perp_positions: get_positions(PerpPosition {
+            quote_asset_amount: -1,
+            position_flag: PositionFlag::IsolatedPosition as u8,
+            ..PerpPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let is_bankrupt = is_user_bankrupt(&user);
+    assert!(!is_bankrupt);
+
+    let is_bankrupt = is_isolated_perp_position_bankrupt(&user.perp_positions[0]);
+    assert!(is_bankrupt);
+}
+
+#[test]
+fn isolated_position_with_collateral() {
+    let perp_position = PerpPosition {
+        quote_asset_amount: -1,
+        isolated_position_scaled_balance: 1,
+        position_flag: PositionFlag::IsolatedPosition as u8,
+        ..PerpPosition::default()
+    };
+
+    let is_bankrupt = is_isolated_perp_position_bankrupt(&perp_position);
+    assert!(!is_bankrupt);
+}
@@ -133,6 +133,19 @@ pub fn calculate_fee_for_fulfillment_with_amm(
     }
 }
 
+/// Every order filled by a batch auction pays the taker fee, since neither side provided
+/// resting liquidity the other took
+pub fn calculate_fee_for_fulfillment_with_batch_auction(
+    user_stats: &UserStats,
+    quote_asset_amount: u64,
+    fee_structure: &FeeStructure,
+    fee_adjustment: i16,
+) -> DriftResult<u64> {
+    let fee_tier = determine_user_fee_tier(user_stats, fee_structure, &MarketType::Perp)?;
+
+    calculate_taker_fee(quote_asset_amount, fee_tier, fee_adjustment)
+}
+
 fn calculate_taker_fee(
     quote_asset_amount: u64,
     fee_tier: &FeeTier,
@@ -1,9 +1,16 @@
+use crate::controller::position::{
+    increase_open_bids_and_asks, update_position_and_market, PositionDirection,
+};
+use crate::controller::spot_balance::update_spot_balances;
+use crate::controller::spot_position::increase_spot_open_bids_and_asks;
 use crate::error::DriftResult;
 use crate::error::ErrorCode;
 use crate::math::constants::{
-    MARGIN_PRECISION_U128, MAX_POSITIVE_UPNL_FOR_INITIAL_MARGIN, PRICE_PRECISION,
+    BASE_PRECISION, MARGIN_PRECISION_U128, MAX_POSITIVE_UPNL_FOR_INITIAL_MARGIN,
+    PERCENTAGE_PRECISION, PERCENTAGE_PRECISION_I128, PRICE_PRECISION, QUOTE_SPOT_MARKET_INDEX,
     SPOT_IMF_PRECISION_U128, SPOT_WEIGHT_PRECISION, SPOT_WEIGHT_PRECISION_U128,
 };
+use crate::math::orders::get_position_delta_for_fill;
 use crate::math::position::{
     calculate_base_asset_value_and_pnl_with_oracle_price,
     calculate_base_asset_value_with_oracle_price,
@@ -19,14 +26,17 @@ use crate::math::oracle::{is_oracle_valid_for_action, DriftAction};
 use crate::math::spot_balance::{get_strict_token_value, get_token_value};
 
 use crate::math::safe_math::SafeMath;
-use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+use crate::state::margin_calculation::{
+    MarginCalculation, MarginContext, MarginSimulationAction, MarketIdentifier,
+};
 use crate::state::oracle::{OraclePriceData, StrictOraclePrice};
 use crate::state::oracle_map::OracleMap;
 use crate::state::perp_market::{ContractTier, MarketStatus, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::{AssetTier, SpotBalanceType};
 use crate::state::spot_market_map::SpotMarketMap;
-use crate::state::user::{OrderFillSimulation, PerpPosition, User};
+use crate::state::user::{MarketType, OrderFillSimulation, PerpPosition, User};
+use anchor_lang::prelude::Pubkey;
 use num_integer::Roots;
 use solana_program::msg;
 use std::cmp::{max, min, Ordering};
@@ -209,6 +219,53 @@ pub fn calculate_perp_position_value_and_pnl(
     ))
 }
 
+/// The margin waived for a perp position hedged by opposite exposure in the spot market on the
+/// same underlying. Only the hedged size, the smaller of the two legs, is offset
+pub fn calculate_basis_margin_offset(
+    perp_base_asset_amount: i64,
+    spot_token_amount: i128,
+    spot_decimals: u32,
+    oracle_price: i64,
+    margin_ratio: u32,
+    basis_margin_offset: u16,
+) -> DriftResult<u128> {
+    let is_hedged = (perp_base_asset_amount > 0 && spot_token_amount < 0)
+        || (perp_base_asset_amount < 0 && spot_token_amount > 0);
+
+    if basis_margin_offset == 0 || !is_hedged {
+        return Ok(0);
+    }
+
+    let perp_base_asset_value =
+        calculate_base_asset_value_with_oracle_price(perp_base_asset_amount.cast()?, oracle_price)?;
+
+    let spot_token_value =
+        get_token_value(spot_token_amount, spot_decimals, oracle_price)?.unsigned_abs();
+
+    perp_base_asset_value
+        .min(spot_token_value)
+        .safe_mul(margin_ratio.cast()?)?
+        .safe_div(MARGIN_PRECISION_U128)?
+        .safe_mul(basis_margin_offset.cast()?)?
+        .safe_div(MARGIN_PRECISION_U128)
+}
+
+/// The spot token amount a hedged perp position uses up. Perp markets sharing a basis spot market
+/// split the spot position rather than each offsetting against all of it
+pub fn calculate_basis_spot_token_amount_hedged(
+    perp_base_asset_amount: i64,
+    spot_token_amount: i128,
+    spot_precision: u64,
+) -> DriftResult<u128> {
+    let perp_token_amount = perp_base_asset_amount
+        .unsigned_abs()
+        .cast::<u128>()?
+        .safe_mul(spot_precision.cast()?)?
+        .safe_div(BASE_PRECISION)?;
+
+    Ok(perp_token_amount.min(spot_token_amount.unsigned_abs()))
+}
+
 pub fn calculate_user_safest_position_tiers(
     user: &User,
     perp_market_map: &PerpMarketMap,
@@ -254,7 +311,8 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
     for spot_position in user.spot_positions.iter() {
         validation::position::validate_spot_position(spot_position)?;
 
-        if spot_position.is_available() {
+        // an isolated position is only backed by its own collateral
+        if spot_position.is_available() || context.isolated_perp_market_index.is_some() {
             continue;
         }
 
@@ -413,11 +471,21 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
         }
     }
 
+    // spot token amount already used to offset earlier perp positions, per basis spot market
+    let mut basis_spot_token_amounts_hedged: Vec<(u16, u128)> = vec![];
+
     for market_position in user.perp_positions.iter() {
         if market_position.is_available() {
             continue;
         }
 
+        match context.isolated_perp_market_index {
+            Some(market_index) if market_position.market_index != market_index => continue,
+            // isolated positions are margined against their own collateral
+            None if market_position.is_isolated() => continue,
+            _ => {}
+        }
+
         let market = &perp_market_map.get_ref(&market_position.market_index)?;
 
         let quote_spot_market = spot_market_map.get_ref(&market.quote_spot_market_index)?;
@@ -441,6 +509,18 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 .last_oracle_price_twap_5min,
             calculation.context.strict,
         );
+
+        let isolated_collateral = if market_position.is_isolated() {
+            get_strict_token_value(
+                market_position
+                    .get_isolated_token_amount(&quote_spot_market)?
+                    .cast()?,
+                quote_spot_market.decimals,
+                &strict_quote_price,
+            )?
+        } else {
+            0
+        };
         drop(quote_spot_market);
 
         let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
@@ -463,6 +543,65 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             calculation.track_open_orders_fraction(),
         )?;
 
+        let perp_margin_requirement = match user.get_spot_position(market.basis_spot_market_index) {
+            Ok(spot_position)
+                if market.has_basis_spot_market() && !market_position.is_isolated() =>
+            {
+                let spot_market = spot_market_map.get_ref(&market.basis_spot_market_index)?;
+
+                let margin_ratio = user_custom_margin_ratio.max(
+                    market.get_margin_ratio(
+                        market_position
+                            .worst_case_base_asset_amount()?
+                            .unsigned_abs(),
+                        context.margin_type,
+                    )?,
+                );
+
+                let spot_token_amount = spot_position.get_signed_token_amount(&spot_market)?;
+                let spot_token_amount_hedged = basis_spot_token_amounts_hedged
+                    .iter()
+                    .find(|(market_index, _)| *market_index == spot_market.market_index)
+                    .map_or(0, |(_, token_amount)| *token_amount);
+                let spot_token_amount_remaining = spot_token_amount
+                    .unsigned_abs()
+                    .saturating_sub(spot_token_amount_hedged)
+                    .cast::<i128>()?
+                    .safe_mul(spot_token_amount.signum())?;
+
+                let basis_margin_offset = calculate_basis_margin_offset(
+                    market_position.base_asset_amount,
+                    spot_token_amount_remaining,
+                    spot_market.decimals,
+                    oracle_price_data.price,
+                    margin_ratio,
+                    market.basis_margin_offset,
+                )?;
+
+                if basis_margin_offset != 0 {
+                    let token_amount_hedged = calculate_basis_spot_token_amount_hedged(
+                        market_position.base_asset_amount,
+                        spot_token_amount_remaining,
+                        spot_market.get_precision(),
+                    )?;
+
+                    match basis_spot_token_amounts_hedged
+                        .iter_mut()
+                        .find(|(market_index, _)| *market_index == spot_market.market_index)
+                    {
+                        Some((_, token_amount)) => {
+                            *token_amount = token_amount.safe_add(token_amount_hedged)?
+                        }
+                        None => basis_spot_token_amounts_hedged
+                            .push((spot_market.market_index, token_amount_hedged)),
+                    }
+                }
+
+                perp_margin_requirement.saturating_sub(basis_margin_offset)
+            }
+            _ => perp_margin_requirement,
+        };
+
         calculation.add_margin_requirement(
             perp_margin_requirement,
             worst_case_base_asset_value,
@@ -474,6 +613,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
         }
 
         calculation.add_total_collateral(weighted_pnl)?;
+        calculation.add_total_collateral(isolated_collateral)?;
 
         let has_perp_liability = market_position.base_asset_amount != 0
             || market_position.quote_asset_amount < 0
@@ -499,6 +639,337 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
     Ok(calculation)
 }
 
+/// Calculates margin for the user as if the actions had been taken, in order. Neither the user nor
+/// the markets are modified and oracle shocks are undone before returning
+pub fn simulate_margin_calculation(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    actions: &[MarginSimulationAction],
+    context: MarginContext,
+) -> DriftResult<MarginCalculation> {
+    let mut simulated_user = Box::new(*user);
+
+    for action in actions.iter() {
+        apply_margin_simulation_action(
+            &mut simulated_user,
+            perp_market_map,
+            spot_market_map,
+            action,
+        )?;
+    }
+
+    let mut original_price_data: Vec<(Pubkey, OraclePriceData)> = vec![];
+    let calculation =
+        apply_oracle_shocks(oracle_map, actions, &mut original_price_data).and_then(|_| {
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &simulated_user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                context,
+            )
+        });
+
+    for (oracle, price_data) in original_price_data.into_iter().rev() {
+        oracle_map.override_price_data(&oracle, price_data);
+    }
+
+    calculation
+}
+
+fn apply_margin_simulation_action(
+    user: &mut User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    action: &MarginSimulationAction,
+) -> DriftResult {
+    match *action {
+        MarginSimulationAction::PlaceOrder {
+            market,
+            direction,
+            base_asset_amount,
+        } => match market.market_type {
+            MarketType::Perp => {
+                let perp_position = user.force_get_perp_position_mut(market.market_index)?;
+                perp_position.open_orders = perp_position.open_orders.safe_add(1)?;
+                increase_open_bids_and_asks(perp_position, &direction, base_asset_amount)?;
+            }
+            MarketType::Spot => {
+                validate!(
+                    market.market_index != QUOTE_SPOT_MARKET_INDEX,
+                    ErrorCode::InvalidSpotMarketAccount,
+                    "cant place order in quote spot market"
+                )?;
+
+                let spot_position = user.force_get_spot_position_mut(market.market_index)?;
+                spot_position.open_orders = spot_position.open_orders.safe_add(1)?;
+                increase_spot_open_bids_and_asks(spot_position, &direction, base_asset_amount)?;
+            }
+        },
+        MarginSimulationAction::FillOrder {
+            market,
+            direction,
+            base_asset_amount,
+            price,
+        } => match market.market_type {
+            MarketType::Perp => {
+                let mut perp_market = *perp_market_map.get_ref(&market.market_index)?;
+                let quote_asset_amount = calculate_base_asset_value_with_oracle_price(
+                    base_asset_amount.cast()?,
+                    price.cast()?,
+                )?
+                .cast::<u64>()?;
+
+                let position_delta =
+                    get_position_delta_for_fill(base_asset_amount, quote_asset_amount, direction)?;
+                update_position_and_market(
+                    user.force_get_perp_position_mut(market.market_index)?,
+                    &mut perp_market,
+                    &position_delta,
+                )?;
+            }
+            MarketType::Spot => {
+                validate!(
+                    market.market_index != QUOTE_SPOT_MARKET_INDEX,
+                    ErrorCode::InvalidSpotMarketAccount,
+                    "cant fill order in quote spot market"
+                )?;
+
+                let mut base_spot_market = *spot_market_map.get_ref(&market.market_index)?;
+                let mut quote_spot_market = *spot_market_map.get_quote_spot_market()?;
+                let quote_asset_amount = base_asset_amount
+                    .cast::<u128>()?
+                    .safe_mul(price.cast()?)?
+                    .safe_div(base_spot_market.get_precision().cast()?)?;
+
+                let (base_update_direction, quote_update_direction) = match direction {
+                    PositionDirection::Long => (SpotBalanceType::Deposit, SpotBalanceType::Borrow),
+                    PositionDirection::Short => (SpotBalanceType::Borrow, SpotBalanceType::Deposit),
+                };
+
+                update_spot_balances(
+                    base_asset_amount.cast()?,
+                    &base_update_direction,
+                    &mut base_spot_market,
+                    user.force_get_spot_position_mut(market.market_index)?,
+                    false,
+                )?;
+
+                update_spot_balances(
+                    quote_asset_amount,
+                    &quote_update_direction,
+                    &mut quote_spot_market,
+                    user.get_quote_spot_position_mut(),
+                    false,
+                )?;
+            }
+        },
+        MarginSimulationAction::Deposit {
+            market_index,
+            amount,
+        } => {
+            let mut spot_market = *spot_market_map.get_ref(&market_index)?;
+            update_spot_balances(
+                amount.cast()?,
+                &SpotBalanceType::Deposit,
+                &mut spot_market,
+                user.force_get_spot_position_mut(market_index)?,
+                false,
+            )?;
+        }
+        MarginSimulationAction::Withdraw {
+            market_index,
+            amount,
+        } => {
+            let mut spot_market = *spot_market_map.get_ref(&market_index)?;
+            update_spot_balances(
+                amount.cast()?,
+                &SpotBalanceType::Borrow,
+                &mut spot_market,
+                user.force_get_spot_position_mut(market_index)?,
+                false,
+            )?;
+        }
+        MarginSimulationAction::OracleShock { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// Overrides the oracle map's price data for every oracle shock in the actions. The price data
+/// replaced is pushed to original_price_data so the caller can restore it, even if a later shock fails
+pub fn apply_oracle_shocks(
+    oracle_map: &mut OracleMap,
+    actions: &[MarginSimulationAction],
+    original_price_data: &mut Vec<(Pubkey, OraclePriceData)>,
+) -> DriftResult {
+    for action in actions.iter() {
+        if let MarginSimulationAction::OracleShock {
+            oracle,
+            price_change,
+            confidence_multiplier,
+        } = *action
+        {
+            let price_data = *oracle_map.get_price_data(&oracle)?;
+            let price = price_data
+                .price
+                .cast::<i128>()?
+                .safe_mul(PERCENTAGE_PRECISION_I128.safe_add(price_change.cast()?)?)?
+                .safe_div(PERCENTAGE_PRECISION_I128)?
+                .cast::<i64>()?;
+
+            validate!(
+                price > 0,
+                ErrorCode::InvalidOracle,
+                "oracle shock of {} leaves oracle {} without a positive price",
+                price_change,
+                oracle
+            )?;
+
+            let confidence = price_data
+                .confidence
+                .cast::<u128>()?
+                .safe_mul(confidence_multiplier.cast()?)?
+                .safe_div(PERCENTAGE_PRECISION)?
+                .cast::<u64>()?;
+
+            original_price_data.push((oracle, price_data));
+            oracle_map.override_price_data(
+                &oracle,
+                OraclePriceData {
+                    price,
+                    confidence,
+                    ..price_data
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the oracle price for the market at which the user stops meeting the maintenance margin
+/// requirement. Every position priced off that oracle moves with it, so cross margin, size premiums
+/// and pnl asset weights are all accounted for. Returns the current price if the user is already
+/// below maintenance and None if the requirement doesn't flip within 100x of the current price.
+/// Isolated perp positions are checked against their own collateral
+pub fn calculate_liquidation_price(
+    user: &User,
+    market_index: u16,
+    market_type: MarketType,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<Option<i64>> {
+    let (oracle, context) = match market_type {
+        MarketType::Perp => {
+            let oracle = perp_market_map.get_ref(&market_index)?.amm.oracle;
+
+            let context = MarginContext::liquidation(0).for_perp_position(user, market_index);
+
+            (oracle, context)
+        }
+        MarketType::Spot => (
+            spot_market_map.get_ref(&market_index)?.oracle,
+            MarginContext::liquidation(0),
+        ),
+    };
+
+    let oracle_price = oracle_map.get_price_data(&oracle)?.price;
+
+    if !meets_margin_requirement_at_oracle_price(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        context,
+        &oracle,
+        oracle_price,
+    )? {
+        return Ok(Some(oracle_price));
+    }
+
+    let mut liquidation_price: Option<i64> = None;
+    for bound in [1_i64, oracle_price.safe_mul(100)?].iter() {
+        if meets_margin_requirement_at_oracle_price(
+            user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            context,
+            &oracle,
+            *bound,
+        )? {
+            continue;
+        }
+
+        // bisect between a price that meets the requirement and one that doesn't
+        let mut meets_price = oracle_price;
+        let mut fails_price = *bound;
+        while meets_price.safe_sub(fails_price)?.abs() > 1 {
+            let price = meets_price.safe_add(fails_price)?.safe_div(2)?;
+            if meets_margin_requirement_at_oracle_price(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                context,
+                &oracle,
+                price,
+            )? {
+                meets_price = price;
+            } else {
+                fails_price = price;
+            }
+        }
+
+        liquidation_price = match liquidation_price {
+            Some(price)
+                if price.safe_sub(oracle_price)?.abs()
+                    <= fails_price.safe_sub(oracle_price)?.abs() =>
+            {
+                Some(price)
+            }
+            _ => Some(fails_price),
+        };
+    }
+
+    Ok(liquidation_price)
+}
+
+fn meets_margin_requirement_at_oracle_price(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    context: MarginContext,
+    oracle: &Pubkey,
+    price: i64,
+) -> DriftResult<bool> {
+    let price_data = *oracle_map.get_price_data(oracle)?;
+    oracle_map.override_price_data(
+        oracle,
+        OraclePriceData {
+            price,
+            ..price_data
+        },
+    );
+
+    let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        context,
+    );
+
+    oracle_map.override_price_data(oracle, price_data);
+
+    calculation.map(|calculation| calculation.meets_margin_requirement())
+}
+
 pub fn meets_withdraw_margin_requirement(
     user: &User,
     perp_market_map: &PerpMarketMap,
@@ -550,13 +1021,19 @@ pub fn meets_place_order_margin_requirement(
     spot_market_map: &SpotMarketMap,
     oracle_map: &mut OracleMap,
     risk_increasing: bool,
+    perp_market_index: Option<u16>,
 ) -> DriftResult {
     let margin_type = if risk_increasing {
         MarginRequirementType::Initial
     } else {
         MarginRequirementType::Maintenance
     };
-    let context = MarginContext::standard(margin_type).strict(true);
+    let mut context = MarginContext::standard(margin_type).strict(true);
+
+    // an order on an isolated perp position is backed by that position's collateral only
+    if let Some(perp_market_index) = perp_market_index {
+        context = context.for_perp_position(user, perp_market_index);
+    }
 
     let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
         user,
@@ -774,6 +1251,18 @@ pub fn calculate_user_equity(
             all_oracles_valid &=
                 is_oracle_valid_for_action(quote_oracle_validity, Some(DriftAction::MarginCalc))?;
 
+            if market_position.is_isolated() {
+                let isolated_value = get_token_value(
+                    market_position
+                        .get_isolated_token_amount(&quote_spot_market)?
+                        .cast()?,
+                    quote_spot_market.decimals,
+                    quote_oracle_price_data.price,
+                )?;
+
+                net_usd_value = net_usd_value.safe_add(isolated_value)?;
+            }
+
             quote_oracle_price_data.price
         };
 
@@ -785,31 +1274,12 @@ pub fn calculate_user_equity(
         all_oracles_valid &=
             is_oracle_valid_for_action(oracle_validity, Some(DriftAction::MarginCalc))?;
 
-        let valuation_price = if market.status == MarketStatus::Settlement {
-            market.expiry_price
-        } else {
-            oracle_price_data.price
-        };
-
-        let unrealized_funding = calculate_funding_payment(
-            if market_position.base_asset_amount > 0 {
-                market.amm.cumulative_funding_rate_long
-            } else {
-                market.amm.cumulative_funding_rate_short
-            },
+        let pnl = calculate_perp_position_unrealized_pnl(
             market_position,
+            market,
+            oracle_price_data.price,
         )?;
 
-        let market_position =
-            market_position.simulate_settled_lp_position(market, valuation_price)?;
-
-        let (_, unrealized_pnl) = calculate_base_asset_value_and_pnl_with_oracle_price(
-            &market_position,
-            valuation_price,
-        )?;
-
-        let pnl = unrealized_pnl.safe_add(unrealized_funding.cast()?)?;
-
         let pnl_value = pnl
             .safe_mul(quote_oracle_price.cast()?)?
             .safe_div(PRICE_PRECISION_I128)?;
@@ -819,3 +1289,33 @@ pub fn calculate_user_equity(
 
     Ok((net_usd_value, all_oracles_valid))
 }
+
+/// Unrealized pnl (including unsettled funding and lp shares) of a perp position valued at the
+/// oracle price, or the expiry price once the market is in settlement
+pub fn calculate_perp_position_unrealized_pnl(
+    market_position: &PerpPosition,
+    market: &PerpMarket,
+    oracle_price: i64,
+) -> DriftResult<i128> {
+    let valuation_price = if market.status == MarketStatus::Settlement {
+        market.expiry_price
+    } else {
+        oracle_price
+    };
+
+    let unrealized_funding = calculate_funding_payment(
+        if market_position.base_asset_amount > 0 {
+            market.amm.cumulative_funding_rate_long
+        } else {
+            market.amm.cumulative_funding_rate_short
+        },
+        market_position,
+    )?;
+
+    let market_position = market_position.simulate_settled_lp_position(market, valuation_price)?;
+
+    let (_, unrealized_pnl) =
+        calculate_base_asset_value_and_pnl_with_oracle_price(&market_position, valuation_price)?;
+
+    unrealized_pnl.safe_add(unrealized_funding.cast()?)
+}
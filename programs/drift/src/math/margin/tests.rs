@@ -3113,3 +3113,571 @@ mod calculate_user_equity {
         assert_eq!(net_usd_value, 1000000000);
     }
 }
+
+mod calculate_basis_margin_offset {
+    use crate::math::constants::{
+        BASE_PRECISION_I64, LAMPORTS_PER_SOL_I64, MARGIN_PRECISION, PRICE_PRECISION_I64,
+        QUOTE_PRECISION,
+    };
+    use crate::math::margin::{
+        calculate_basis_margin_offset, calculate_basis_spot_token_amount_hedged,
+    };
+
+    const ORACLE_PRICE: i64 = 100 * PRICE_PRECISION_I64;
+    const MARGIN_RATIO: u32 = MARGIN_PRECISION / 10;
+    const BASIS_MARGIN_OFFSET: u16 = (MARGIN_PRECISION / 2) as u16;
+
+    #[test]
+    fn short_perp_with_spot_deposit() {
+        let offset = calculate_basis_margin_offset(
+            -10 * BASE_PRECISION_I64,
+            10 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+
+        // $1000 hedged * 10% margin ratio * 50% offset
+        assert_eq!(offset, 50 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn long_perp_with_spot_borrow() {
+        let offset = calculate_basis_margin_offset(
+            10 * BASE_PRECISION_I64,
+            -10 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+
+        assert_eq!(offset, 50 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn only_hedged_size_is_offset() {
+        let offset = calculate_basis_margin_offset(
+            -10 * BASE_PRECISION_I64,
+            4 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+
+        assert_eq!(offset, 20 * QUOTE_PRECISION);
+
+        let offset = calculate_basis_margin_offset(
+            -4 * BASE_PRECISION_I64,
+            10 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+
+        assert_eq!(offset, 20 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn no_offset() {
+        // same direction isn't a hedge
+        let offset = calculate_basis_margin_offset(
+            10 * BASE_PRECISION_I64,
+            10 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+        assert_eq!(offset, 0);
+
+        // offset not configured
+        let offset = calculate_basis_margin_offset(
+            -10 * BASE_PRECISION_I64,
+            10 * LAMPORTS_PER_SOL_I64 as i128,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            0,
+        )
+        .unwrap();
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn spot_amount_shared_across_perp_markets() {
+        let spot_token_amount = 10 * LAMPORTS_PER_SOL_I64 as i128;
+
+        // first market hedges 6 of the 10 sol
+        let hedged = calculate_basis_spot_token_amount_hedged(
+            -6 * BASE_PRECISION_I64,
+            spot_token_amount,
+            LAMPORTS_PER_SOL_I64 as u64,
+        )
+        .unwrap();
+        assert_eq!(hedged, 6 * LAMPORTS_PER_SOL_I64 as u128);
+
+        // second market can only offset against the 4 sol left
+        let spot_token_amount_remaining = spot_token_amount - hedged as i128;
+        let offset = calculate_basis_margin_offset(
+            -10 * BASE_PRECISION_I64,
+            spot_token_amount_remaining,
+            9,
+            ORACLE_PRICE,
+            MARGIN_RATIO,
+            BASIS_MARGIN_OFFSET,
+        )
+        .unwrap();
+        assert_eq!(offset, 20 * QUOTE_PRECISION);
+
+        let hedged = calculate_basis_spot_token_amount_hedged(
+            -10 * BASE_PRECISION_I64,
+            spot_token_amount_remaining,
+            LAMPORTS_PER_SOL_I64 as u64,
+        )
+        .unwrap();
+        assert_eq!(hedged, 4 * LAMPORTS_PER_SOL_I64 as u128);
+    }
+}
+
+mod isolated_perp_position {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, PEG_PRECISION, QUOTE_PRECISION,
+        QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::{
+        calculate_margin_requirement_and_total_collateral_and_liability_info, MarginRequirementType,
+    };
+    use crate::state::margin_calculation::MarginContext;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{PerpPosition, PositionFlag, SpotPosition, User};
+    use crate::test_utils::get_pyth_price;
+    use crate::test_utils::*;
+    use crate::{create_account_info, create_anchor_account_info};
+
+    #[test]
+    fn isolated_position_doesnt_affect_cross_margin() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        // $1 backing a $100 position, below the $5 maintenance requirement
+        let user = User {
+            spot_positions,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                isolated_position_scaled_balance: SPOT_BALANCE_PRECISION_U64,
+                position_flag: PositionFlag::IsolatedPosition as u8,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let cross_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Maintenance),
+            )
+            .unwrap();
+
+        assert_eq!(
+            cross_calculation.total_collateral,
+            100 * QUOTE_PRECISION_I128
+        );
+        assert_eq!(cross_calculation.margin_requirement, 0);
+        assert!(cross_calculation.meets_margin_requirement());
+
+        let isolated_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Maintenance)
+                    .for_perp_position(&user, 0),
+            )
+            .unwrap();
+
+        assert_eq!(isolated_calculation.total_collateral, QUOTE_PRECISION_I128);
+        assert_eq!(isolated_calculation.margin_requirement, 5 * QUOTE_PRECISION);
+        assert!(!isolated_calculation.meets_margin_requirement());
+    }
+}
+
+mod simulate_margin_calculation {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_U64, PEG_PRECISION, PERCENTAGE_PRECISION_I128,
+        PERCENTAGE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION,
+        QUOTE_PRECISION_I128, QUOTE_PRECISION_U64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::{simulate_margin_calculation, MarginRequirementType};
+    use crate::state::margin_calculation::{
+        MarginContext, MarginSimulationAction, MarketIdentifier,
+    };
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{SpotPosition, User};
+    use crate::test_utils::get_pyth_price;
+    use crate::test_utils::*;
+    use crate::{create_account_info, create_anchor_account_info};
+
+    #[test]
+    fn fill_withdraw_and_oracle_shock() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            spot_positions,
+            ..User::default()
+        };
+
+        let calculation = simulate_margin_calculation(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[MarginSimulationAction::PlaceOrder {
+                market: MarketIdentifier::perp(0),
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+            }],
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        // 10% of the $100 order plus the open order margin
+        assert!(calculation.margin_requirement > 10 * QUOTE_PRECISION);
+        assert_eq!(calculation.total_collateral, 100 * QUOTE_PRECISION_I128);
+
+        let calculation = simulate_margin_calculation(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[
+                MarginSimulationAction::FillOrder {
+                    market: MarketIdentifier::perp(0),
+                    direction: PositionDirection::Long,
+                    base_asset_amount: BASE_PRECISION_U64,
+                    price: 100 * PRICE_PRECISION_U64,
+                },
+                MarginSimulationAction::Withdraw {
+                    market_index: 0,
+                    amount: 50 * QUOTE_PRECISION_U64,
+                },
+                MarginSimulationAction::OracleShock {
+                    oracle: sol_oracle_price_key,
+                    price_change: -(PERCENTAGE_PRECISION_I128 / 10) as i64,
+                    confidence_multiplier: PERCENTAGE_PRECISION_U64,
+                },
+            ],
+            MarginContext::standard(MarginRequirementType::Maintenance),
+        )
+        .unwrap();
+
+        // $50 left after the withdraw less $10 of pnl, 5% of $90 notional
+        assert_eq!(calculation.total_collateral, 40 * QUOTE_PRECISION_I128);
+        assert_eq!(calculation.margin_requirement, 45 * QUOTE_PRECISION / 10);
+
+        // simulation doesnt touch the user or the oracle map
+        assert_eq!(user.perp_positions[0].base_asset_amount, 0);
+        assert_eq!(
+            oracle_map
+                .get_price_data(&sol_oracle_price_key)
+                .unwrap()
+                .price,
+            100 * PRICE_PRECISION_I64
+        );
+    }
+}
+
+mod calculate_liquidation_price {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, PEG_PRECISION, QUOTE_PRECISION_I64,
+        SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+        SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::margin::calculate_liquidation_price;
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, PerpPosition, SpotPosition, User};
+    use crate::test_utils::get_pyth_price;
+    use crate::test_utils::*;
+    use crate::{create_account_info, create_anchor_account_info};
+
+    #[test]
+    fn long_and_short_perp_position() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let mut user = User {
+            spot_positions,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        // $10 + (price - $100) = 5% * price => price = $94.7368
+        let liquidation_price = calculate_liquidation_price(
+            &user,
+            0,
+            MarketType::Perp,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(liquidation_price > 94_736_000 && liquidation_price < 94_737_000);
+
+        user.perp_positions[0].base_asset_amount = -BASE_PRECISION_I64;
+        user.perp_positions[0].quote_asset_amount = 100 * QUOTE_PRECISION_I64;
+        user.perp_positions[0].quote_entry_amount = 100 * QUOTE_PRECISION_I64;
+        user.perp_positions[0].quote_break_even_amount = 100 * QUOTE_PRECISION_I64;
+
+        // $10 + ($100 - price) = 5% * price => price = $104.7619
+        let liquidation_price = calculate_liquidation_price(
+            &user,
+            0,
+            MarketType::Perp,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(liquidation_price > 104_761_000 && liquidation_price < 104_763_000);
+
+        // oracle map is left untouched
+        assert_eq!(
+            oracle_map
+                .get_price_data(&sol_oracle_price_key)
+                .unwrap()
+                .price,
+            100_000_000
+        );
+    }
+}
@@ -4,14 +4,18 @@ use crate::controller::position::PositionDirection;
 use crate::error::DriftResult;
 use crate::math::casting::Cast;
 use crate::math::constants::{BID_ASK_SPREAD_PRECISION_I128, TEN_BPS_I64};
-use crate::math::orders::calculate_quote_asset_amount_for_maker_order;
+use crate::math::orders::{
+    calculate_quote_asset_amount_for_maker_order, standardize_base_asset_amount,
+};
 use crate::math::safe_math::SafeMath;
 
-use crate::state::user::Order;
+use crate::state::user::{Order, SelfTradePreventionMode};
 
 #[cfg(test)]
 mod tests;
 
+/// Doesn't check for self trades. Preventing one cancels or shrinks orders, which needs the users,
+/// so the controller applies the self trade prevention mode once a maker is matched
 pub fn is_maker_for_taker(
     maker_order: &Order,
     taker_order: &Order,
@@ -39,6 +43,18 @@ pub fn is_maker_for_taker(
     }
 }
 
+/// The taker's self trade prevention mode takes precedence. If the taker doesn't set one, the maker's is used
+pub fn get_self_trade_prevention_mode(
+    maker_order: &Order,
+    taker_order: &Order,
+) -> SelfTradePreventionMode {
+    if taker_order.self_trade_prevention_mode != SelfTradePreventionMode::None {
+        taker_order.self_trade_prevention_mode
+    } else {
+        maker_order.self_trade_prevention_mode
+    }
+}
+
 pub fn are_orders_same_market_but_different_sides(
     maker_order: &Order,
     taker_order: &Order,
@@ -78,6 +94,124 @@ pub fn calculate_fill_for_matched_orders(
     Ok((base_asset_amount, quote_asset_amount))
 }
 
+/// Splits the taker's size across the makers resting at one price. Each maker is first given
+/// min_allocation (capped at its size), then the rest is split in proportion to what the makers
+/// have left. Whatever can't be split evenly goes to the makers in price-time order
+pub fn calculate_pro_rata_maker_allocations(
+    taker_base_asset_amount: u64,
+    maker_base_asset_amounts: &[u64],
+    min_allocation: u64,
+    step_size: u64,
+) -> DriftResult<Vec<u64>> {
+    let total_maker_base_asset_amount = maker_base_asset_amounts
+        .iter()
+        .try_fold(0_u64, |total, amount| total.safe_add(*amount))?;
+
+    if total_maker_base_asset_amount <= taker_base_asset_amount {
+        return Ok(maker_base_asset_amounts.to_vec());
+    }
+
+    let mut remaining_base_asset_amount = taker_base_asset_amount;
+    let mut allocations = Vec::with_capacity(maker_base_asset_amounts.len());
+    for maker_base_asset_amount in maker_base_asset_amounts.iter() {
+        let allocation = min_allocation
+            .min(*maker_base_asset_amount)
+            .min(remaining_base_asset_amount);
+
+        remaining_base_asset_amount = remaining_base_asset_amount.safe_sub(allocation)?;
+        allocations.push(allocation);
+    }
+
+    if remaining_base_asset_amount == 0 {
+        return Ok(allocations);
+    }
+
+    let base_asset_amount_to_split = remaining_base_asset_amount;
+    let total_maker_base_asset_amount_left = total_maker_base_asset_amount
+        .safe_sub(taker_base_asset_amount.safe_sub(remaining_base_asset_amount)?)?;
+    for (allocation, maker_base_asset_amount) in
+        allocations.iter_mut().zip(maker_base_asset_amounts.iter())
+    {
+        let pro_rata_base_asset_amount = base_asset_amount_to_split
+            .cast::<u128>()?
+            .safe_mul(maker_base_asset_amount.safe_sub(*allocation)?.cast()?)?
+            .safe_div(total_maker_base_asset_amount_left.cast()?)?
+            .cast::<u64>()?;
+
+        let extra = standardize_base_asset_amount(pro_rata_base_asset_amount, step_size)?
+            .min(remaining_base_asset_amount);
+
+        *allocation = allocation.safe_add(extra)?;
+        remaining_base_asset_amount = remaining_base_asset_amount.safe_sub(extra)?;
+    }
+
+    for (allocation, maker_base_asset_amount) in
+        allocations.iter_mut().zip(maker_base_asset_amounts.iter())
+    {
+        if remaining_base_asset_amount == 0 {
+            break;
+        }
+
+        let extra = maker_base_asset_amount
+            .safe_sub(*allocation)?
+            .min(remaining_base_asset_amount);
+
+        *allocation = allocation.safe_add(extra)?;
+        remaining_base_asset_amount = remaining_base_asset_amount.safe_sub(extra)?;
+    }
+
+    Ok(allocations)
+}
+
+/// Finds the single price that matches the most size between the bids and asks of a batch
+/// auction. Ties go to the price closest to the oracle. None if no bid and ask cross
+/// (clearing price, base asset amount matched)
+pub fn calculate_batch_auction_clearing_price(
+    bids: &[(u64, u64)],
+    asks: &[(u64, u64)],
+    oracle_price: u64,
+) -> DriftResult<Option<(u64, u64)>> {
+    let mut clearing_price_and_base_asset_amount: Option<(u64, u64)> = None;
+    for price in bids.iter().chain(asks.iter()).map(|(price, _)| *price) {
+        let bid_base_asset_amount = bids
+            .iter()
+            .filter(|(bid_price, _)| do_orders_cross(PositionDirection::Long, *bid_price, price))
+            .try_fold(0_u64, |total, (_, base_asset_amount)| {
+                total.safe_add(*base_asset_amount)
+            })?;
+
+        let ask_base_asset_amount = asks
+            .iter()
+            .filter(|(ask_price, _)| do_orders_cross(PositionDirection::Short, *ask_price, price))
+            .try_fold(0_u64, |total, (_, base_asset_amount)| {
+                total.safe_add(*base_asset_amount)
+            })?;
+
+        let base_asset_amount = bid_base_asset_amount.min(ask_base_asset_amount);
+        if base_asset_amount == 0 {
+            continue;
+        }
+
+        let is_better = match clearing_price_and_base_asset_amount {
+            None => true,
+            Some((clearing_price, clearing_base_asset_amount)) => {
+                base_asset_amount > clearing_base_asset_amount
+                    || (base_asset_amount == clearing_base_asset_amount
+                        && price.max(oracle_price).safe_sub(price.min(oracle_price))?
+                            < clearing_price
+                                .max(oracle_price)
+                                .safe_sub(clearing_price.min(oracle_price))?)
+            }
+        };
+
+        if is_better {
+            clearing_price_and_base_asset_amount = Some((price, base_asset_amount));
+        }
+    }
+
+    Ok(clearing_price_and_base_asset_amount)
+}
+
 pub fn calculate_filler_multiplier_for_matched_orders(
     maker_price: u64,
     maker_direction: PositionDirection,
@@ -255,3 +255,219 @@ fn filler_multiplier_maker_short() {
 
     assert_eq!(mult, 2100); // 2.1x
 }
+
+mod get_self_trade_prevention_mode {
+    use crate::math::matching::get_self_trade_prevention_mode;
+    use crate::state::user::{Order, SelfTradePreventionMode};
+
+    #[test]
+    fn taker_mode_takes_precedence() {
+        let taker = Order {
+            self_trade_prevention_mode: SelfTradePreventionMode::CancelTaker,
+            ..Order::default()
+        };
+        let maker = Order {
+            self_trade_prevention_mode: SelfTradePreventionMode::CancelMaker,
+            ..Order::default()
+        };
+
+        assert_eq!(
+            get_self_trade_prevention_mode(&maker, &taker),
+            SelfTradePreventionMode::CancelTaker
+        );
+    }
+
+    #[test]
+    fn falls_back_to_maker_mode() {
+        let taker = Order::default();
+        let maker = Order {
+            self_trade_prevention_mode: SelfTradePreventionMode::DecrementAndCancel,
+            ..Order::default()
+        };
+
+        assert_eq!(
+            get_self_trade_prevention_mode(&maker, &taker),
+            SelfTradePreventionMode::DecrementAndCancel
+        );
+
+        assert_eq!(
+            get_self_trade_prevention_mode(&Order::default(), &taker),
+            SelfTradePreventionMode::None
+        );
+    }
+}
+
+mod calculate_pro_rata_maker_allocations {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::matching::calculate_pro_rata_maker_allocations;
+
+    const STEP_SIZE: u64 = BASE_PRECISION_U64 / 10;
+
+    #[test]
+    fn makers_fill_in_full_when_taker_is_bigger() {
+        let allocations = calculate_pro_rata_maker_allocations(
+            10 * BASE_PRECISION_U64,
+            &[BASE_PRECISION_U64, 2 * BASE_PRECISION_U64],
+            0,
+            STEP_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![BASE_PRECISION_U64, 2 * BASE_PRECISION_U64]
+        );
+    }
+
+    #[test]
+    fn proportional_to_size() {
+        let allocations = calculate_pro_rata_maker_allocations(
+            4 * BASE_PRECISION_U64,
+            &[
+                2 * BASE_PRECISION_U64,
+                6 * BASE_PRECISION_U64,
+                8 * BASE_PRECISION_U64,
+            ],
+            0,
+            STEP_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![
+                BASE_PRECISION_U64 / 2,
+                3 * BASE_PRECISION_U64 / 2,
+                2 * BASE_PRECISION_U64
+            ]
+        );
+    }
+
+    #[test]
+    fn rounding_leftover_goes_to_first_maker() {
+        let allocations = calculate_pro_rata_maker_allocations(
+            BASE_PRECISION_U64,
+            &[BASE_PRECISION_U64, BASE_PRECISION_U64, BASE_PRECISION_U64],
+            0,
+            STEP_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![
+                4 * BASE_PRECISION_U64 / 10,
+                3 * BASE_PRECISION_U64 / 10,
+                3 * BASE_PRECISION_U64 / 10
+            ]
+        );
+        assert_eq!(allocations.iter().sum::<u64>(), BASE_PRECISION_U64);
+    }
+
+    #[test]
+    fn min_allocation() {
+        // the small maker's pro rata share would be under 0.1, the minimum gets it 0.5
+        let allocations = calculate_pro_rata_maker_allocations(
+            2 * BASE_PRECISION_U64,
+            &[10 * BASE_PRECISION_U64, BASE_PRECISION_U64 / 2],
+            BASE_PRECISION_U64 / 2,
+            STEP_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![3 * BASE_PRECISION_U64 / 2, BASE_PRECISION_U64 / 2]
+        );
+    }
+
+    #[test]
+    fn min_allocation_bigger_than_taker() {
+        let allocations = calculate_pro_rata_maker_allocations(
+            BASE_PRECISION_U64,
+            &[
+                10 * BASE_PRECISION_U64,
+                10 * BASE_PRECISION_U64,
+                10 * BASE_PRECISION_U64,
+            ],
+            BASE_PRECISION_U64 / 2,
+            STEP_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            allocations,
+            vec![BASE_PRECISION_U64 / 2, BASE_PRECISION_U64 / 2, 0]
+        );
+    }
+}
+
+mod calculate_batch_auction_clearing_price {
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::matching::calculate_batch_auction_clearing_price;
+
+    #[test]
+    fn no_crossing_orders() {
+        let clearing_price = calculate_batch_auction_clearing_price(
+            &[(99 * PRICE_PRECISION_U64, BASE_PRECISION_U64)],
+            &[(101 * PRICE_PRECISION_U64, BASE_PRECISION_U64)],
+            100 * PRICE_PRECISION_U64,
+        )
+        .unwrap();
+
+        assert_eq!(clearing_price, None);
+    }
+
+    #[test]
+    fn maximizes_volume() {
+        let clearing_price = calculate_batch_auction_clearing_price(
+            &[
+                (102 * PRICE_PRECISION_U64, BASE_PRECISION_U64),
+                (101 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64),
+                (100 * PRICE_PRECISION_U64, 3 * BASE_PRECISION_U64),
+            ],
+            &[
+                (99 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64),
+                (100 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64),
+                (101 * PRICE_PRECISION_U64, 3 * BASE_PRECISION_U64),
+            ],
+            105 * PRICE_PRECISION_U64,
+        )
+        .unwrap();
+
+        assert_eq!(
+            clearing_price,
+            Some((100 * PRICE_PRECISION_U64, 4 * BASE_PRECISION_U64))
+        );
+    }
+
+    #[test]
+    fn ties_go_to_price_closest_to_oracle() {
+        let bids = [(101 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64)];
+        let asks = [(99 * PRICE_PRECISION_U64, BASE_PRECISION_U64)];
+
+        let clearing_price = calculate_batch_auction_clearing_price(
+            &bids,
+            &asks,
+            99 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            clearing_price,
+            Some((99 * PRICE_PRECISION_U64, BASE_PRECISION_U64))
+        );
+
+        let clearing_price = calculate_batch_auction_clearing_price(
+            &bids,
+            &asks,
+            100 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            clearing_price,
+            Some((101 * PRICE_PRECISION_U64, BASE_PRECISION_U64))
+        );
+    }
+}
@@ -15,7 +15,7 @@ use crate::{
     PRICE_PRECISION_I128, QUOTE_PRECISION_I128, SPOT_WEIGHT_PRECISION, SPOT_WEIGHT_PRECISION_I128,
 };
 
-use crate::math::constants::MARGIN_PRECISION_U128;
+use crate::math::constants::{MARGIN_PRECISION_U128, ONE_BPS_DENOMINATOR};
 use crate::math::margin::{
     calculate_margin_requirement_and_total_collateral_and_liability_info, MarginRequirementType,
 };
@@ -33,7 +33,8 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::SpotMarket;
 use crate::state::spot_market_map::SpotMarketMap;
 use crate::state::user::{
-    MarketType, Order, OrderFillSimulation, OrderStatus, OrderTriggerCondition, PerpPosition, User,
+    MarketType, Order, OrderFillSimulation, OrderStatus, OrderTriggerCondition, PerpPosition,
+    TimeInForce, TrailingStopType, TriggerPriceSource, User,
 };
 use crate::state::user_map::UserMap;
 use crate::validate;
@@ -343,12 +344,13 @@ pub fn validate_perp_fill_possible(
 pub fn should_expire_order_before_fill(
     user: &User,
     order_index: usize,
+    slot: u64,
     now: i64,
 ) -> DriftResult<bool> {
-    let should_order_be_expired = should_expire_order(user, order_index, now)?;
+    let should_order_be_expired = should_expire_order(user, order_index, slot, now)?;
     if should_order_be_expired && user.orders[order_index].is_limit_order() {
         let now_plus_buffer = now.safe_add(15)?;
-        if !should_expire_order(user, order_index, now_plus_buffer)? {
+        if !should_expire_order(user, order_index, slot, now_plus_buffer)? {
             msg!("invalid fill. cant force expire limit order until 15s after max_ts. max ts {}, now {}, now plus buffer {}", user.orders[order_index].max_ts, now, now_plus_buffer);
             return Err(ErrorCode::ImpossibleFill);
         }
@@ -358,13 +360,45 @@ pub fn should_expire_order_before_fill(
 }
 
 #[inline(always)]
-pub fn should_expire_order(user: &User, user_order_index: usize, now: i64) -> DriftResult<bool> {
+pub fn should_expire_order(
+    user: &User,
+    user_order_index: usize,
+    slot: u64,
+    now: i64,
+) -> DriftResult<bool> {
     let order = &user.orders[user_order_index];
-    if order.status != OrderStatus::Open || order.max_ts == 0 || order.must_be_triggered() {
+    if order.status != OrderStatus::Open || order.must_be_triggered() {
         return Ok(false);
     }
 
-    Ok(now > order.max_ts)
+    let expired_by_slot = order.time_in_force == TimeInForce::GoodTilSlot
+        && order.max_slot != 0
+        && slot > order.max_slot;
+    let expired_by_ts = order.max_ts != 0 && now > order.max_ts;
+
+    Ok(expired_by_slot || expired_by_ts)
+}
+
+/// Sizes a close position order to a percentage of the existing position. Full closes round up
+/// so dust left by lp settlement is closed too, since reduce only fills are capped at the position
+pub fn calculate_close_position_base_asset_amount(
+    existing_base_asset_amount: i64,
+    close_position_percentage: u8,
+    step_size: u64,
+) -> DriftResult<u64> {
+    let existing_base_asset_amount = existing_base_asset_amount.unsigned_abs();
+
+    if close_position_percentage >= 100 {
+        return standardize_base_asset_amount_ceil(existing_base_asset_amount, step_size);
+    }
+
+    let base_asset_amount = existing_base_asset_amount
+        .cast::<u128>()?
+        .safe_mul(close_position_percentage.cast()?)?
+        .safe_div(100)?
+        .cast::<u64>()?;
+
+    standardize_base_asset_amount(base_asset_amount, step_size)
 }
 
 pub fn should_cancel_reduce_only_order(
@@ -574,14 +608,156 @@ pub fn is_oracle_too_divergent_with_twap_5min(
     Ok(too_divergent)
 }
 
-pub fn order_satisfies_trigger_condition(order: &Order, oracle_price: u64) -> DriftResult<bool> {
+pub fn order_satisfies_trigger_condition(
+    order: &Order,
+    trigger_source_price: u64,
+) -> DriftResult<bool> {
     match order.trigger_condition {
-        OrderTriggerCondition::Above => Ok(oracle_price > order.trigger_price),
-        OrderTriggerCondition::Below => Ok(oracle_price < order.trigger_price),
+        OrderTriggerCondition::Above => Ok(trigger_source_price > order.trigger_price),
+        OrderTriggerCondition::Below => Ok(trigger_source_price < order.trigger_price),
         _ => Err(print_error!(ErrorCode::InvalidTriggerOrderCondition)()),
     }
 }
 
+/// The price a perp trigger order compares against its trigger price
+pub fn get_perp_trigger_source_price(
+    trigger_price_source: TriggerPriceSource,
+    market: &PerpMarket,
+    oracle_price: i64,
+) -> DriftResult<u64> {
+    let trigger_source_price = match trigger_price_source {
+        TriggerPriceSource::Oracle => oracle_price.unsigned_abs(),
+        TriggerPriceSource::AmmReservePrice => market.amm.reserve_price()?,
+        TriggerPriceSource::LastFillPrice => market.amm.last_fill_price_twap,
+        TriggerPriceSource::MarkTwap5Min => market.amm.last_mark_price_twap_5min,
+    };
+
+    // a market that hasn't recorded the price yet can't trigger orders off it
+    validate!(
+        trigger_source_price != 0,
+        ErrorCode::InvalidTriggerPriceSource,
+        "{:?} price not available for perp market {}",
+        trigger_price_source,
+        market.market_index
+    )?;
+
+    Ok(trigger_source_price)
+}
+
+/// The price a spot trigger order compares against its trigger price
+pub fn get_spot_trigger_source_price(
+    trigger_price_source: TriggerPriceSource,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+) -> DriftResult<u64> {
+    let trigger_source_price = match trigger_price_source {
+        TriggerPriceSource::Oracle => oracle_price.unsigned_abs(),
+        TriggerPriceSource::MarkTwap5Min => {
+            spot_market.historical_index_data.last_index_price_twap_5min
+        }
+        TriggerPriceSource::AmmReservePrice | TriggerPriceSource::LastFillPrice => 0,
+    };
+
+    validate!(
+        trigger_source_price != 0,
+        ErrorCode::InvalidTriggerPriceSource,
+        "{:?} price not available for spot market {}",
+        trigger_price_source,
+        spot_market.market_index
+    )?;
+
+    Ok(trigger_source_price)
+}
+
+/// Tightens a taker's limit price so it never fills further than max_oracle_slippage_bps past the oracle.
+/// Orders without a limit price get the slippage price as their limit
+pub fn apply_max_oracle_slippage_to_limit_price(
+    limit_price: Option<u64>,
+    direction: PositionDirection,
+    max_oracle_slippage_bps: u16,
+    oracle_price: i64,
+    tick_size: u64,
+) -> DriftResult<Option<u64>> {
+    if max_oracle_slippage_bps == 0 {
+        return Ok(limit_price);
+    }
+
+    let oracle_price = oracle_price.unsigned_abs();
+    let max_oracle_slippage = oracle_price
+        .safe_mul(max_oracle_slippage_bps.cast()?)?
+        .safe_div(ONE_BPS_DENOMINATOR.cast()?)?;
+
+    let slippage_price = match direction {
+        PositionDirection::Long => oracle_price.safe_add(max_oracle_slippage)?,
+        PositionDirection::Short => oracle_price.saturating_sub(max_oracle_slippage),
+    };
+
+    // round towards the oracle so the fill never goes past the slippage price
+    let slippage_price = standardize_price(slippage_price, tick_size, direction)?.max(tick_size);
+
+    let limit_price = match (limit_price, direction) {
+        (Some(limit_price), PositionDirection::Long) => limit_price.min(slippage_price),
+        (Some(limit_price), PositionDirection::Short) => limit_price.max(slippage_price),
+        (None, _) => slippage_price,
+    };
+
+    Ok(Some(limit_price))
+}
+
+/// Moves a trailing stop's trigger price to follow the oracle. The trigger price only ever moves in
+/// the favorable direction: up for stops that trigger below, down for stops that trigger above.
+/// Returns whether the trigger price changed
+pub fn update_trailing_stop_trigger_price(
+    order: &mut Order,
+    oracle_price: u64,
+    tick_size: u64,
+) -> DriftResult<bool> {
+    let trailing_offset = match order.trailing_stop_type {
+        TrailingStopType::None => return Ok(false),
+        TrailingStopType::Fixed => order.trailing_stop_offset.cast::<u64>()?,
+        TrailingStopType::Percentage => oracle_price
+            .cast::<u128>()?
+            .safe_mul(order.trailing_stop_offset.cast()?)?
+            .safe_div(PERCENTAGE_PRECISION)?
+            .cast::<u64>()?,
+    };
+
+    let trigger_price = match order.trigger_condition {
+        OrderTriggerCondition::Below => {
+            let trigger_price = standardize_price(
+                oracle_price.saturating_sub(trailing_offset),
+                tick_size,
+                order.direction,
+            )?;
+
+            if trigger_price <= order.trigger_price {
+                return Ok(false);
+            }
+
+            trigger_price
+        }
+        OrderTriggerCondition::Above => {
+            let trigger_price = standardize_price(
+                oracle_price.safe_add(trailing_offset)?,
+                tick_size,
+                order.direction,
+            )?;
+
+            if order.trigger_price != 0 && trigger_price >= order.trigger_price {
+                return Ok(false);
+            }
+
+            trigger_price
+        }
+        // trigger price is fixed once the order has been triggered
+        _ => return Ok(false),
+    };
+
+    order.trigger_price = trigger_price;
+
+    Ok(true)
+}
+
 pub fn is_new_order_risk_increasing(
     order: &Order,
     position_base_asset_amount: i64,
@@ -327,7 +327,7 @@ mod order_breaches_oracle_price_limits {
 
 mod should_expire_order {
     use crate::math::orders::should_expire_order;
-    use crate::state::user::{Order, OrderStatus, OrderType, User};
+    use crate::state::user::{Order, OrderStatus, OrderType, TimeInForce, User};
     use crate::test_utils::get_orders;
 
     #[test]
@@ -344,7 +344,7 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(!is_expired);
     }
@@ -363,7 +363,7 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(!is_expired);
     }
@@ -382,11 +382,53 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(is_expired);
     }
 
+    #[test]
+    fn max_slot_is_less_than_slot() {
+        let user = User {
+            orders: get_orders(Order {
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                time_in_force: TimeInForce::GoodTilSlot,
+                max_slot: 99,
+                ..Order::default()
+            }),
+            ..User::default()
+        };
+
+        let now = 100;
+
+        let is_expired = should_expire_order(&user, 0, 99, now).unwrap();
+        assert!(!is_expired);
+
+        let is_expired = should_expire_order(&user, 0, 100, now).unwrap();
+        assert!(is_expired);
+    }
+
+    #[test]
+    fn max_slot_ignored_without_good_til_slot() {
+        let user = User {
+            orders: get_orders(Order {
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                time_in_force: TimeInForce::GoodTilCanceled,
+                max_slot: 99,
+                ..Order::default()
+            }),
+            ..User::default()
+        };
+
+        let now = 100;
+
+        let is_expired = should_expire_order(&user, 0, 100, now).unwrap();
+
+        assert!(!is_expired);
+    }
+
     #[test]
     fn order_is_not_open() {
         let user = User {
@@ -401,7 +443,7 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(!is_expired);
     }
@@ -420,7 +462,7 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(!is_expired);
     }
@@ -439,12 +481,53 @@ mod should_expire_order {
 
         let now = 100;
 
-        let is_expired = should_expire_order(&user, 0, now).unwrap();
+        let is_expired = should_expire_order(&user, 0, 0, now).unwrap();
 
         assert!(!is_expired);
     }
 }
 
+mod calculate_close_position_base_asset_amount {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::orders::calculate_close_position_base_asset_amount;
+
+    #[test]
+    fn full_close_rounds_up() {
+        let step_size = BASE_PRECISION_U64 / 10;
+
+        let base_asset_amount = calculate_close_position_base_asset_amount(
+            -(BASE_PRECISION_U64 as i64) - 1,
+            100,
+            step_size,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, BASE_PRECISION_U64 + step_size);
+    }
+
+    #[test]
+    fn partial_close_rounds_down() {
+        let step_size = BASE_PRECISION_U64 / 10;
+
+        let base_asset_amount = calculate_close_position_base_asset_amount(
+            3 * BASE_PRECISION_U64 as i64,
+            25,
+            step_size,
+        )
+        .unwrap();
+
+        assert_eq!(base_asset_amount, 7 * step_size);
+    }
+
+    #[test]
+    fn no_position() {
+        let base_asset_amount =
+            calculate_close_position_base_asset_amount(0, 100, BASE_PRECISION_U64 / 10).unwrap();
+
+        assert_eq!(base_asset_amount, 0);
+    }
+}
+
 mod get_max_fill_amounts {
     use crate::controller::position::PositionDirection;
     use crate::math::constants::{
@@ -3861,3 +3944,329 @@ mod fallback_price_logic {
         assert_eq!(result, 99500000);
     }
 }
+
+mod update_trailing_stop_trigger_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PERCENTAGE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::orders::update_trailing_stop_trigger_price;
+    use crate::state::user::{Order, OrderTriggerCondition, OrderType, TrailingStopType};
+
+    #[test]
+    fn fixed_offset_below() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Short,
+            trigger_condition: OrderTriggerCondition::Below,
+            trailing_stop_type: TrailingStopType::Fixed,
+            trailing_stop_offset: (5 * PRICE_PRECISION_U64) as u32,
+            ..Order::default()
+        };
+
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 100 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(updated);
+        assert_eq!(order.trigger_price, 95 * PRICE_PRECISION_U64);
+
+        // oracle moves up, trigger follows
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(updated);
+        assert_eq!(order.trigger_price, 105 * PRICE_PRECISION_U64);
+
+        // oracle moves down, trigger stays
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 107 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(!updated);
+        assert_eq!(order.trigger_price, 105 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn percentage_offset_above() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            direction: PositionDirection::Long,
+            trigger_condition: OrderTriggerCondition::Above,
+            trailing_stop_type: TrailingStopType::Percentage,
+            trailing_stop_offset: (PERCENTAGE_PRECISION_U64 / 10) as u32, // 10%
+            ..Order::default()
+        };
+
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 100 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(updated);
+        assert_eq!(order.trigger_price, 110 * PRICE_PRECISION_U64);
+
+        // oracle moves down, trigger follows
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 90 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(updated);
+        assert_eq!(order.trigger_price, 99 * PRICE_PRECISION_U64);
+
+        // oracle moves up, trigger stays
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 95 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(!updated);
+        assert_eq!(order.trigger_price, 99 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn not_trailing_or_triggered() {
+        let mut order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_condition: OrderTriggerCondition::Below,
+            trigger_price: 95 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(!updated);
+        assert_eq!(order.trigger_price, 95 * PRICE_PRECISION_U64);
+
+        order.trailing_stop_type = TrailingStopType::Fixed;
+        order.trailing_stop_offset = (5 * PRICE_PRECISION_U64) as u32;
+        order.trigger_condition = OrderTriggerCondition::TriggeredBelow;
+
+        let updated =
+            update_trailing_stop_trigger_price(&mut order, 110 * PRICE_PRECISION_U64, 1).unwrap();
+        assert!(!updated);
+        assert_eq!(order.trigger_price, 95 * PRICE_PRECISION_U64);
+    }
+}
+
+mod get_trigger_source_price {
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, PEG_PRECISION, PRICE_PRECISION_I64, PRICE_PRECISION_U64,
+    };
+    use crate::math::orders::{get_perp_trigger_source_price, get_spot_trigger_source_price};
+    use crate::state::oracle::HistoricalIndexData;
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::spot_market::SpotMarket;
+    use crate::state::user::TriggerPriceSource;
+
+    #[test]
+    fn perp() {
+        let market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 101 * PEG_PRECISION,
+                last_fill_price_twap: 102 * PRICE_PRECISION_U64,
+                last_mark_price_twap_5min: 103 * PRICE_PRECISION_U64,
+                ..AMM::default()
+            },
+            ..PerpMarket::default()
+        };
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        let price =
+            get_perp_trigger_source_price(TriggerPriceSource::Oracle, &market, oracle_price)
+                .unwrap();
+        assert_eq!(price, 100 * PRICE_PRECISION_U64);
+
+        let price = get_perp_trigger_source_price(
+            TriggerPriceSource::AmmReservePrice,
+            &market,
+            oracle_price,
+        )
+        .unwrap();
+        assert_eq!(price, 101 * PRICE_PRECISION_U64);
+
+        let price =
+            get_perp_trigger_source_price(TriggerPriceSource::LastFillPrice, &market, oracle_price)
+                .unwrap();
+        assert_eq!(price, 102 * PRICE_PRECISION_U64);
+
+        let price =
+            get_perp_trigger_source_price(TriggerPriceSource::MarkTwap5Min, &market, oracle_price)
+                .unwrap();
+        assert_eq!(price, 103 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn perp_no_last_fill() {
+        let market = PerpMarket::default();
+
+        let result = get_perp_trigger_source_price(
+            TriggerPriceSource::LastFillPrice,
+            &market,
+            100 * PRICE_PRECISION_I64,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn spot() {
+        let spot_market = SpotMarket {
+            historical_index_data: HistoricalIndexData {
+                last_index_price_twap_5min: 99 * PRICE_PRECISION_U64,
+                ..HistoricalIndexData::default()
+            },
+            ..SpotMarket::default()
+        };
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        let price =
+            get_spot_trigger_source_price(TriggerPriceSource::Oracle, &spot_market, oracle_price)
+                .unwrap();
+        assert_eq!(price, 100 * PRICE_PRECISION_U64);
+
+        let price = get_spot_trigger_source_price(
+            TriggerPriceSource::MarkTwap5Min,
+            &spot_market,
+            oracle_price,
+        )
+        .unwrap();
+        assert_eq!(price, 99 * PRICE_PRECISION_U64);
+
+        let result = get_spot_trigger_source_price(
+            TriggerPriceSource::AmmReservePrice,
+            &spot_market,
+            oracle_price,
+        );
+        assert!(result.is_err());
+
+        let result = get_spot_trigger_source_price(
+            TriggerPriceSource::LastFillPrice,
+            &spot_market,
+            oracle_price,
+        );
+        assert!(result.is_err());
+    }
+}
+
+mod apply_max_oracle_slippage_to_limit_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::orders::apply_max_oracle_slippage_to_limit_price;
+
+    const TICK_SIZE: u64 = 1;
+
+    #[test]
+    fn no_max_oracle_slippage() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            None,
+            PositionDirection::Long,
+            0,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, None);
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            Some(110 * PRICE_PRECISION_U64),
+            PositionDirection::Long,
+            0,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(110 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn long() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let slippage_price = 100 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2;
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            None,
+            PositionDirection::Long,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(slippage_price));
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            Some(101 * PRICE_PRECISION_U64),
+            PositionDirection::Long,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(slippage_price));
+
+        // limit price inside the slippage is untouched
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            Some(100 * PRICE_PRECISION_U64),
+            PositionDirection::Long,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(100 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn short() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let slippage_price = 99 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2;
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            None,
+            PositionDirection::Short,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(slippage_price));
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            Some(99 * PRICE_PRECISION_U64),
+            PositionDirection::Short,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(slippage_price));
+
+        // limit price inside the slippage is untouched
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            Some(100 * PRICE_PRECISION_U64),
+            PositionDirection::Short,
+            50,
+            oracle_price,
+            TICK_SIZE,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(100 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn rounds_towards_oracle() {
+        let tick_size = PRICE_PRECISION_U64 / 10;
+        let oracle_price = 100 * PRICE_PRECISION_I64 + PRICE_PRECISION_I64 * 3 / 100; // $100.03
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            None,
+            PositionDirection::Long,
+            10,
+            oracle_price,
+            tick_size,
+        )
+        .unwrap();
+        assert_eq!(
+            limit_price,
+            Some(100 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 10)
+        );
+
+        let limit_price = apply_max_oracle_slippage_to_limit_price(
+            None,
+            PositionDirection::Short,
+            10,
+            oracle_price,
+            tick_size,
+        )
+        .unwrap();
+        assert_eq!(limit_price, Some(100 * PRICE_PRECISION_U64));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::error::DriftResult;
+use crate::math::casting::Cast;
+use crate::math::constants::PERCENTAGE_PRECISION_U64;
+use crate::math::margin::{
+    apply_oracle_shocks, calculate_margin_requirement_and_total_collateral_and_liability_info,
+    calculate_perp_position_unrealized_pnl,
+};
+use crate::math::safe_math::SafeMath;
+use crate::math::spot_balance::{get_token_amount, get_token_value};
+use crate::state::margin_calculation::{MarginContext, MarginSimulationAction};
+use crate::state::oracle::OraclePriceData;
+use crate::state::oracle_map::OracleMap;
+use crate::state::perp_market_map::PerpMarketMap;
+use crate::state::spot_market::SpotBalance;
+use crate::state::spot_market_map::SpotMarketMap;
+use crate::state::user::{MarketType, User};
+use crate::PRICE_PRECISION_I128;
+use anchor_lang::prelude::Pubkey;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OracleStress {
+    pub oracle: Pubkey,
+    /// change applied to the oracle price
+    /// precision: PERCENTAGE_PRECISION
+    pub price_change: i64,
+    /// scales the oracle confidence, PERCENTAGE_PRECISION leaves it unchanged
+    /// precision: PERCENTAGE_PRECISION
+    pub confidence_multiplier: u64,
+}
+
+impl OracleStress {
+    pub fn price_shock(oracle: Pubkey, price_change: i64) -> Self {
+        OracleStress {
+            oracle,
+            price_change,
+            confidence_multiplier: PERCENTAGE_PRECISION_U64,
+        }
+    }
+
+    pub fn to_oracle_shock(&self) -> MarginSimulationAction {
+        MarginSimulationAction::OracleShock {
+            oracle: self.oracle,
+            price_change: self.price_change,
+            confidence_multiplier: self.confidence_multiplier,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InsuranceFundDraw {
+    pub market_type: MarketType,
+    pub market_index: u16,
+    /// loss left once the user's collateral is exhausted
+    /// precision: QUOTE_PRECISION
+    pub loss: u128,
+    /// portion of the loss covered by the insurance fund. None for spot markets, which draw on
+    /// their insurance fund vault and its balance isn't in the spot market account
+    /// precision: QUOTE_PRECISION
+    pub insurance_fund_draw: Option<u128>,
+    /// portion of the loss covered by the perp market's fee pool
+    /// precision: QUOTE_PRECISION
+    pub fee_pool_draw: u128,
+    /// portion of the loss socialized across the market. None when the insurance fund draw isn't known
+    /// precision: QUOTE_PRECISION
+    pub socialized_loss: Option<u128>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StressResult {
+    /// precision: QUOTE_PRECISION
+    pub total_collateral: i128,
+    /// precision: QUOTE_PRECISION
+    pub maintenance_margin_requirement: u128,
+    /// whether every oracle is still valid for margin calculations after the stress
+    pub all_oracles_valid: bool,
+    /// whether the user's cross margin account can be liquidated
+    pub is_liquidatable: bool,
+    /// isolated perp positions that can be liquidated against their own collateral
+    pub liquidatable_isolated_perp_markets: Vec<u16>,
+    /// whether the user's liabilities exceed their assets, leaving a loss once liquidated
+    pub is_bankrupt: bool,
+    /// expected draws for each position that would go through resolve_perp_bankruptcy or
+    /// resolve_spot_bankruptcy
+    pub insurance_fund_draws: Vec<InsuranceFundDraw>,
+}
+
+/// Stresses the user's positions with the oracle shocks and reports their post-shock health.
+/// Meant to be run off-chain against decoded accounts; neither the user nor the markets are
+/// modified and the oracle map is restored before returning.
+///
+/// The cross margin loss is split across the user's liabilities pro rata. Insurance fund vault
+/// balances aren't known from the user and market accounts, so perp draws are only capped by the
+/// perp market's insurance claim and spot draws are left unknown
+pub fn stress_user(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    stresses: &[OracleStress],
+) -> DriftResult<StressResult> {
+    let oracle_shocks: Vec<MarginSimulationAction> = stresses
+        .iter()
+        .map(|stress| stress.to_oracle_shock())
+        .collect();
+
+    let mut original_price_data: Vec<(Pubkey, OraclePriceData)> = vec![];
+    let result = apply_oracle_shocks(oracle_map, &oracle_shocks, &mut original_price_data)
+        .and_then(|_| calculate_stress_result(user, perp_market_map, spot_market_map, oracle_map));
+
+    for (oracle, price_data) in original_price_data.into_iter().rev() {
+        oracle_map.override_price_data(&oracle, price_data);
+    }
+
+    result
+}
+
+fn calculate_stress_result(
+    user: &User,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+) -> DriftResult<StressResult> {
+    let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+        user,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        MarginContext::liquidation(0),
+    )?;
+
+    let mut liquidatable_isolated_perp_markets = vec![];
+    for perp_position in user.perp_positions.iter() {
+        if perp_position.is_available() || !perp_position.is_isolated() {
+            continue;
+        }
+
+        let isolated_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+                MarginContext::liquidation(0).isolated_perp_position(perp_position.market_index),
+            )?;
+
+        if !isolated_calculation.meets_margin_requirement() {
+            liquidatable_isolated_perp_markets.push(perp_position.market_index);
+        }
+    }
+
+    let quote_spot_market = spot_market_map.get_quote_spot_market()?;
+    let quote_oracle_price = oracle_map.get_price_data(&quote_spot_market.oracle)?.price;
+
+    let mut losses: Vec<(MarketType, u16, u128)> = vec![];
+
+    // cross margin assets and liabilities at the stressed prices, unweighted
+    let mut assets: u128 = 0;
+    let mut liabilities: Vec<(MarketType, u16, u128)> = vec![];
+
+    for spot_position in user.spot_positions.iter() {
+        if spot_position.is_available() {
+            continue;
+        }
+
+        let spot_market = spot_market_map.get_ref(&spot_position.market_index)?;
+        let oracle_price = oracle_map.get_price_data(&spot_market.oracle)?.price;
+        let token_value = get_token_value(
+            spot_position.get_signed_token_amount(&spot_market)?,
+            spot_market.decimals,
+            oracle_price,
+        )?;
+
+        if token_value >= 0 {
+            assets = assets.safe_add(token_value.unsigned_abs())?;
+        } else {
+            liabilities.push((
+                MarketType::Spot,
+                spot_position.market_index,
+                token_value.unsigned_abs(),
+            ));
+        }
+    }
+
+    for perp_position in user.perp_positions.iter() {
+        if perp_position.is_available() {
+            continue;
+        }
+
+        let perp_market = perp_market_map.get_ref(&perp_position.market_index)?;
+        let oracle_price = oracle_map.get_price_data(&perp_market.amm.oracle)?.price;
+        let pnl_value =
+            calculate_perp_position_unrealized_pnl(perp_position, &perp_market, oracle_price)?
+                .safe_mul(quote_oracle_price.cast()?)?
+                .safe_div(PRICE_PRECISION_I128)?;
+
+        // isolated positions only lose what's in their own bucket
+        if perp_position.is_isolated() {
+            let isolated_value = get_token_value(
+                perp_position
+                    .get_isolated_token_amount(&quote_spot_market)?
+                    .cast()?,
+                quote_spot_market.decimals,
+                quote_oracle_price,
+            )?;
+
+            let net_value = isolated_value.safe_add(pnl_value)?;
+            if net_value < 0 {
+                losses.push((
+                    MarketType::Perp,
+                    perp_position.market_index,
+                    net_value.unsigned_abs(),
+                ));
+            }
+
+            continue;
+        }
+
+        if pnl_value >= 0 {
+            assets = assets.safe_add(pnl_value.unsigned_abs())?;
+        } else {
+            liabilities.push((
+                MarketType::Perp,
+                perp_position.market_index,
+                pnl_value.unsigned_abs(),
+            ));
+        }
+    }
+
+    let total_liabilities = liabilities
+        .iter()
+        .try_fold(0_u128, |total, (_, _, value)| total.safe_add(*value))?;
+
+    if total_liabilities > assets {
+        let cross_loss = total_liabilities.safe_sub(assets)?;
+        for (market_type, market_index, value) in liabilities.into_iter() {
+            let loss = cross_loss.safe_mul(value)?.safe_div(total_liabilities)?;
+            if loss > 0 {
+                losses.push((market_type, market_index, loss));
+            }
+        }
+    }
+
+    let is_bankrupt = !losses.is_empty();
+
+    let mut insurance_fund_draws = Vec::with_capacity(losses.len());
+    for (market_type, market_index, loss) in losses.into_iter() {
+        let (insurance_fund_draw, fee_pool_draw) = match market_type {
+            MarketType::Perp => {
+                let perp_market = perp_market_map.get_ref(&market_index)?;
+                let max_insurance_withdraw = perp_market
+                    .insurance_claim
+                    .quote_max_insurance
+                    .safe_sub(perp_market.insurance_claim.quote_settled_insurance)?
+                    .cast::<u128>()?;
+
+                let insurance_fund_draw = loss.min(max_insurance_withdraw);
+
+                let fee_pool_tokens = get_token_amount(
+                    perp_market.amm.fee_pool.balance(),
+                    &quote_spot_market,
+                    perp_market.amm.fee_pool.balance_type(),
+                )?;
+
+                let fee_pool_draw = loss.safe_sub(insurance_fund_draw)?.min(fee_pool_tokens);
+
+                (Some(insurance_fund_draw), fee_pool_draw)
+            }
+            MarketType::Spot => (None, 0),
+        };
+
+        let socialized_loss = match insurance_fund_draw {
+            Some(insurance_fund_draw) => Some(
+                loss.safe_sub(insurance_fund_draw)?
+                    .safe_sub(fee_pool_draw)?,
+            ),
+            None => None,
+        };
+
+        insurance_fund_draws.push(InsuranceFundDraw {
+            market_type,
+            market_index,
+            loss,
+            insurance_fund_draw,
+            fee_pool_draw,
+            socialized_loss,
+        });
+    }
+
+    Ok(StressResult {
+        total_collateral: calculation.total_collateral,
+        maintenance_margin_requirement: calculation.margin_requirement,
+        all_oracles_valid: calculation.all_oracles_valid,
+        is_liquidatable: !calculation.meets_margin_requirement(),
+        liquidatable_isolated_perp_markets,
+        is_bankrupt,
+        insurance_fund_draws,
+    })
+}
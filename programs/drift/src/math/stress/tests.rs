@@ -0,0 +1,280 @@
+mod stress_user {
+    use std::str::FromStr;
+
+    use anchor_lang::Owner;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::math::constants::{
+        AMM_RESERVE_PRECISION, BASE_PRECISION_I64, PEG_PRECISION, PERCENTAGE_PRECISION_I128,
+        PERCENTAGE_PRECISION_U64, PRICE_PRECISION_I64, QUOTE_PRECISION, QUOTE_PRECISION_I128,
+        QUOTE_PRECISION_I64, QUOTE_PRECISION_U64, SPOT_BALANCE_PRECISION,
+        SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
+    };
+    use crate::math::stress::{stress_user, InsuranceFundDraw, OracleStress};
+    use crate::state::oracle::{HistoricalOracleData, OracleSource};
+    use crate::state::oracle_map::OracleMap;
+    use crate::state::perp_market::{InsuranceClaim, MarketStatus, PerpMarket, AMM};
+    use crate::state::perp_market_map::PerpMarketMap;
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::spot_market_map::SpotMarketMap;
+    use crate::state::user::{MarketType, PerpPosition, SpotPosition, User};
+    use crate::test_utils::get_pyth_price;
+    use crate::test_utils::*;
+    use crate::{create_account_info, create_anchor_account_info};
+
+    #[test]
+    fn perp_price_shocks() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+        let original_price_data = *oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                order_step_size: 10000000,
+                oracle: sol_oracle_price_key,
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            insurance_claim: InsuranceClaim {
+                quote_max_insurance: 4 * QUOTE_PRECISION_U64,
+                ..InsuranceClaim::default()
+            },
+            status: MarketStatus::Initialized,
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let perp_market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            spot_positions,
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        // $10 - $5 of pnl covers 5% of $95
+        let result = stress_user(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[OracleStress::price_shock(
+                sol_oracle_price_key,
+                -(PERCENTAGE_PRECISION_I128 / 20) as i64,
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(result.total_collateral, 5 * QUOTE_PRECISION_I128);
+        assert_eq!(
+            result.maintenance_margin_requirement,
+            475 * QUOTE_PRECISION / 100
+        );
+        assert!(!result.is_liquidatable);
+        assert!(!result.is_bankrupt);
+        assert!(result.insurance_fund_draws.is_empty());
+
+        // $10 - $10 of pnl doesnt cover 5% of $90
+        let result = stress_user(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[OracleStress::price_shock(
+                sol_oracle_price_key,
+                -(PERCENTAGE_PRECISION_I128 / 10) as i64,
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(result.total_collateral, 0);
+        assert!(result.is_liquidatable);
+        assert!(!result.is_bankrupt);
+        assert!(result.insurance_fund_draws.is_empty());
+
+        // $10 - $20 of pnl leaves a $10 loss, $4 of which the insurance fund covers
+        let result = stress_user(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[OracleStress {
+                oracle: sol_oracle_price_key,
+                price_change: -(PERCENTAGE_PRECISION_I128 / 5) as i64,
+                confidence_multiplier: 10 * PERCENTAGE_PRECISION_U64,
+            }],
+        )
+        .unwrap();
+
+        assert_eq!(result.total_collateral, -10 * QUOTE_PRECISION_I128);
+        assert!(result.is_liquidatable);
+        assert!(result.is_bankrupt);
+        assert_eq!(
+            result.insurance_fund_draws,
+            vec![InsuranceFundDraw {
+                market_type: MarketType::Perp,
+                market_index: 0,
+                loss: 10 * QUOTE_PRECISION,
+                insurance_fund_draw: Some(4 * QUOTE_PRECISION),
+                fee_pool_draw: 0,
+                socialized_loss: Some(6 * QUOTE_PRECISION),
+            }]
+        );
+
+        // stress doesnt touch the oracle map
+        let price_data = *oracle_map.get_price_data(&sol_oracle_price_key).unwrap();
+        assert_eq!(price_data.price, 100 * PRICE_PRECISION_I64);
+        assert_eq!(price_data.confidence, original_price_data.confidence);
+    }
+
+    #[test]
+    fn spot_borrow_price_shock() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let perp_market_map = PerpMarketMap::empty();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: 10 * SPOT_BALANCE_PRECISION,
+            borrow_balance: SPOT_BALANCE_PRECISION,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 105 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Borrow,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        let user = User {
+            spot_positions,
+            ..User::default()
+        };
+
+        // $105 of usdc doesnt cover a $110 sol borrow. the spot insurance fund vault balance isn't
+        // known, so neither is how much of the loss it covers
+        let result = stress_user(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &[OracleStress::price_shock(
+                sol_oracle_price_key,
+                (PERCENTAGE_PRECISION_I128 / 10) as i64,
+            )],
+        )
+        .unwrap();
+
+        assert!(result.is_liquidatable);
+        assert!(result.is_bankrupt);
+        assert_eq!(
+            result.insurance_fund_draws,
+            vec![InsuranceFundDraw {
+                market_type: MarketType::Spot,
+                market_index: 1,
+                loss: 5 * QUOTE_PRECISION,
+                insurance_fund_draw: None,
+                fee_pool_draw: 0,
+                socialized_loss: None,
+            }]
+        );
+    }
+}
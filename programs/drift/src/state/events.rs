@@ -184,7 +184,7 @@ pub struct OrderRecord {
 }
 
 impl Size for OrderRecord {
-    const SIZE: usize = 192;
+    const SIZE: usize = 280;
 }
 
 #[event]
@@ -237,10 +237,13 @@ pub struct OrderActionRecord {
 
     /// precision: PRICE_PRECISION
     pub oracle_price: i64,
+
+    pub taker_client_order_id: Option<u64>,
+    pub maker_client_order_id: Option<u64>,
 }
 
 impl Size for OrderActionRecord {
-    const SIZE: usize = 384;
+    const SIZE: usize = 416;
 }
 
 pub fn get_order_action_record(
@@ -310,6 +313,12 @@ pub fn get_order_action_record(
         maker_order_cumulative_quote_asset_amount_filled: maker_order
             .map(|order| order.quote_asset_amount_filled),
         oracle_price,
+        taker_client_order_id: taker_order
+            .map(|order| order.client_order_id)
+            .filter(|client_order_id| *client_order_id != 0),
+        maker_client_order_id: maker_order
+            .map(|order| order.client_order_id)
+            .filter(|client_order_id| *client_order_id != 0),
     })
 }
 
@@ -343,6 +352,17 @@ pub enum OrderActionExplanation {
     OrderFilledWithAMMJitLPSplit,
     OrderFilledWithLPJit,
     DeriskLp,
+    OrderGroupSiblingFilled,
+    OrderGroupSiblingTriggered,
+    SelfTradePreventionCancelMaker,
+    SelfTradePreventionCancelTaker,
+    SelfTradePreventionCancelBoth,
+    SelfTradePreventionDecrementAndCancel,
+    MarketMakerProtectionTriggered,
+    MissedHeartbeat,
+    PositionTakeProfitTriggered,
+    PositionStopLossTriggered,
+    OrderFilledWithBatchAuction,
 }
 
 impl Default for OrderAction {
@@ -30,7 +30,9 @@ impl FillMode {
                 if order.has_auction() {
                     calculate_auction_price(
                         order,
-                        order.slot.safe_add(order.auction_duration.cast()?)?,
+                        order
+                            .get_auction_start_slot(slot)?
+                            .safe_add(order.auction_duration.cast()?)?,
                         tick_size,
                         valid_oracle_price,
                     )
@@ -1,7 +1,9 @@
+use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode};
 use crate::math::casting::Cast;
 use crate::math::margin::MarginRequirementType;
 use crate::math::safe_math::SafeMath;
+use crate::state::user::User;
 use crate::{validate, MarketType, MARGIN_PRECISION_U128};
 use anchor_lang::{prelude::*, solana_program::msg};
 
@@ -21,9 +23,11 @@ pub struct MarginContext {
     pub mode: MarginCalculationMode,
     pub strict: bool,
     pub margin_buffer: u128,
+    /// Only consider this isolated perp position and the collateral backing it
+    pub isolated_perp_market_index: Option<u16>,
 }
 
-#[derive(PartialEq, Eq, Copy, Clone, Debug, AnchorSerialize, AnchorDeserialize)]
+#[derive(Default, PartialEq, Eq, Copy, Clone, Debug, AnchorSerialize, AnchorDeserialize)]
 pub struct MarketIdentifier {
     pub market_type: MarketType,
     pub market_index: u16,
@@ -54,6 +58,7 @@ impl MarginContext {
             },
             strict: false,
             margin_buffer: 0,
+            isolated_perp_market_index: None,
         }
     }
 
@@ -90,6 +95,25 @@ impl MarginContext {
             },
             margin_buffer: margin_buffer as u128,
             strict: false,
+            isolated_perp_market_index: None,
+        }
+    }
+
+    pub fn isolated_perp_position(mut self, market_index: u16) -> Self {
+        self.isolated_perp_market_index = Some(market_index);
+        self
+    }
+
+    /// Scopes the context to the perp position's own collateral if the user holds it isolated
+    pub fn for_perp_position(self, user: &User, market_index: u16) -> Self {
+        let is_isolated_position = user
+            .get_perp_position(market_index)
+            .map_or(false, |perp_position| perp_position.is_isolated());
+
+        if is_isolated_position {
+            self.isolated_perp_position(market_index)
+        } else {
+            self
         }
     }
 
@@ -113,6 +137,41 @@ impl MarginContext {
     }
 }
 
+/// Hypothetical changes applied to a copy of a user before calculating margin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarginSimulationAction {
+    /// Adds an open order for base_asset_amount without filling it
+    PlaceOrder {
+        market: MarketIdentifier,
+        direction: PositionDirection,
+        base_asset_amount: u64,
+    },
+    /// Fills base_asset_amount at price, ignoring fees
+    /// precision: PRICE_PRECISION
+    FillOrder {
+        market: MarketIdentifier,
+        direction: PositionDirection,
+        base_asset_amount: u64,
+        price: u64,
+    },
+    Deposit {
+        market_index: u16,
+        amount: u64,
+    },
+    Withdraw {
+        market_index: u16,
+        amount: u64,
+    },
+    /// Moves an oracle price by a fraction of itself and scales its confidence.
+    /// A confidence_multiplier of PERCENTAGE_PRECISION leaves the confidence unchanged
+    /// precision: PERCENTAGE_PRECISION
+    OracleShock {
+        oracle: Pubkey,
+        price_change: i64,
+        confidence_multiplier: u64,
+    },
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct MarginCalculation {
     pub context: MarginContext,
@@ -295,7 +354,7 @@ impl MarginCalculation {
         }
     }
 
-    fn is_liquidation_mode(&self) -> bool {
+    pub fn is_liquidation_mode(&self) -> bool {
         matches!(self.context.mode, MarginCalculationMode::Liquidation { .. })
     }
 
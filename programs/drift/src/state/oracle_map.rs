@@ -156,6 +156,15 @@ impl<'a> OracleMap<'a> {
         Ok((oracle_price_data, validity_guard_rails))
     }
 
+    /// Replaces the cached price data for an oracle. Used to simulate price moves
+    pub fn override_price_data(&mut self, pubkey: &Pubkey, price_data: OraclePriceData) {
+        if self.should_get_quote_asset_price_data(pubkey) {
+            self.quote_asset_price_data = price_data;
+        } else {
+            self.price_data.insert(*pubkey, price_data);
+        }
+    }
+
     pub fn load<'c>(
         account_info_iter: &'c mut Peekable<Iter<AccountInfo<'a>>>,
         slot: u64,
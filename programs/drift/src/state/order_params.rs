@@ -1,11 +1,16 @@
 use crate::controller::position::PositionDirection;
 use crate::error::DriftResult;
 use crate::math::casting::Cast;
+use crate::math::orders::{standardize_base_asset_amount, standardize_price};
 use crate::math::safe_math::SafeMath;
 use crate::math::safe_unwrap::SafeUnwrap;
 use crate::state::events::OrderActionExplanation;
+use crate::state::margin_calculation::MarketIdentifier;
 use crate::state::perp_market::{ContractTier, PerpMarket};
-use crate::state::user::{MarketType, OrderTriggerCondition, OrderType};
+use crate::state::user::{
+    MarketType, OrderTriggerCondition, OrderType, SelfTradePreventionMode, TimeInForce,
+    TrailingStopType, TriggerPriceSource,
+};
 use crate::{PERCENTAGE_PRECISION_U64, PRICE_PRECISION_I64};
 use anchor_lang::prelude::*;
 use borsh::{BorshDeserialize, BorshSerialize};
@@ -33,9 +38,38 @@ pub struct OrderParams {
     pub auction_duration: Option<u8>,     // specified in slots
     pub auction_start_price: Option<i64>, // specified in price or oracle_price_offset
     pub auction_end_price: Option<i64>,   // specified in price or oracle_price_offset
+    pub twap_slices: Option<u8>,          // number of slices for twap orders
+    pub twap_slice_interval: Option<u16>, // specified in slots
+    pub trailing_stop_type: Option<TrailingStopType>,
+    pub trailing_stop_offset: Option<u32>, // PRICE_PRECISION for fixed, PERCENTAGE_PRECISION for percentage
+    pub self_trade_prevention_mode: Option<SelfTradePreventionMode>,
+    pub iceberg_display_size: Option<u64>, // visible size of each slice for iceberg orders
+    pub time_in_force: Option<TimeInForce>,
+    pub max_slot: Option<u64>, // last slot a good til slot order can fill
+    pub close_position_percentage: Option<u8>, // sizes the order to a percentage of the position (1-100)
+    pub trigger_price_source: Option<TriggerPriceSource>,
+    pub trigger_market: Option<MarketIdentifier>, // checks the trigger condition against another market's oracle
+    pub max_oracle_slippage_bps: Option<u16>, // furthest a taker fill can be from the oracle price
+    pub client_order_id: Option<u64>, // client-defined id, unique among the user's open orders
 }
 
 impl OrderParams {
+    /// Orders that don't set a time in force fall back to immediate_or_cancel and max_ts
+    pub fn get_time_in_force(&self) -> TimeInForce {
+        match self.time_in_force {
+            Some(time_in_force) => time_in_force,
+            None if self.immediate_or_cancel => TimeInForce::ImmediateOrCancel,
+            None if self.max_ts.map_or(false, |max_ts| max_ts != 0) => {
+                TimeInForce::GoodTilTimestamp
+            }
+            None => TimeInForce::GoodTilCanceled,
+        }
+    }
+
+    pub fn is_immediate_or_cancel(&self) -> bool {
+        self.get_time_in_force().is_immediate_or_cancel()
+    }
+
     pub fn update_perp_auction_params_limit_orders(
         &mut self,
         perp_market: &PerpMarket,
@@ -45,7 +79,7 @@ impl OrderParams {
             return Ok(());
         }
 
-        if self.immediate_or_cancel {
+        if self.is_immediate_or_cancel() {
             return Ok(());
         }
 
@@ -149,7 +183,7 @@ impl OrderParams {
     }
 
     pub fn get_auction_start_price_offset(self, oracle_price: i64) -> DriftResult<i64> {
-        let start_offset = if self.order_type == OrderType::Oracle {
+        let start_offset = if matches!(self.order_type, OrderType::Oracle | OrderType::Twap) {
             self.auction_start_price.unwrap_or(0)
         } else if let Some(auction_start_price) = self.auction_start_price {
             auction_start_price.safe_sub(oracle_price)?
@@ -362,7 +396,7 @@ impl OrderParams {
             OrderType::Limit => {
                 self.update_perp_auction_params_limit_orders(perp_market, oracle_price)?;
             }
-            OrderType::Market | OrderType::Oracle => {
+            OrderType::Market | OrderType::Oracle | OrderType::Twap => {
                 self.update_perp_auction_params_market_and_oracle_orders(
                     perp_market,
                     oracle_price,
@@ -529,6 +563,21 @@ impl OrderParams {
         let (auction_start_price, auction_end_price) =
             OrderParams::get_perp_baseline_start_end_price_offset(market, direction_to_close)?;
 
+        // batch auction markets don't take orders with their own auction, so the close rests as an
+        // oracle limit order at the price the auction would have ended at
+        if market.is_batch_auction_market() {
+            return Ok(OrderParams {
+                market_type: MarketType::Perp,
+                direction: direction_to_close,
+                order_type: OrderType::Limit,
+                market_index: market.market_index,
+                base_asset_amount,
+                reduce_only: true,
+                oracle_price_offset: Some(auction_end_price.cast()?),
+                ..OrderParams::default()
+            });
+        }
+
         let params = OrderParams {
             market_type: MarketType::Perp,
             direction: direction_to_close,
@@ -598,6 +647,128 @@ pub struct ModifyOrderParams {
     pub policy: Option<ModifyOrderPolicy>,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ModifyOrderByIdParams {
+    pub order_id: u32,
+    pub modify_order_params: ModifyOrderParams,
+}
+
+/// The message a taker signs off-chain so a maker can place and fill their order in one transaction
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Copy, Eq, PartialEq, Debug)]
+pub struct SignedOrderParamsMessage {
+    pub signed_order_params: OrderParams,
+    pub sub_account_id: u16,
+    pub nonce: u64,
+    pub expiry_slot: u64, // last slot the message can be placed in
+}
+
+/// A ladder of limit orders spread evenly between a start and end price
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default, Copy, Eq, PartialEq, Debug)]
+pub struct ScaleOrderParams {
+    pub market_index: u16,
+    pub direction: PositionDirection,
+    pub total_base_asset_amount: u64, // split across the levels by size_distribution
+    pub start_price: u64,
+    pub end_price: u64,
+    pub order_count: u8,
+    pub size_distribution: SizeDistribution,
+    pub reduce_only: bool,
+    pub post_only: PostOnlyParam,
+}
+
+impl ScaleOrderParams {
+    /// Expands the ladder into limit order params, rounding prices to the tick size and
+    /// sizes down to the step size. The last level takes whatever size rounding left over
+    pub fn get_order_params(
+        &self,
+        tick_size: u64,
+        step_size: u64,
+    ) -> DriftResult<Vec<OrderParams>> {
+        let order_count = self.order_count.cast::<usize>()?;
+
+        let weights = (0..order_count)
+            .map(|level| self.size_distribution.get_weight(level.cast()?))
+            .collect::<DriftResult<Vec<u64>>>()?;
+        let total_weight = weights
+            .iter()
+            .try_fold(0_u64, |total, weight| total.safe_add(*weight))?;
+
+        let price_range = self
+            .start_price
+            .max(self.end_price)
+            .safe_sub(self.start_price.min(self.end_price))?
+            .cast::<u128>()?;
+        let levels = order_count.saturating_sub(1).max(1).cast::<u128>()?;
+
+        let mut remaining_base_asset_amount = self.total_base_asset_amount;
+        let mut order_params = Vec::with_capacity(order_count);
+        for (level, weight) in weights.iter().enumerate() {
+            let base_asset_amount = if level == order_count - 1 {
+                remaining_base_asset_amount
+            } else {
+                self.total_base_asset_amount
+                    .cast::<u128>()?
+                    .safe_mul(weight.cast()?)?
+                    .safe_div(total_weight.cast()?)?
+                    .cast::<u64>()?
+            };
+            let base_asset_amount = standardize_base_asset_amount(base_asset_amount, step_size)?;
+            remaining_base_asset_amount =
+                remaining_base_asset_amount.safe_sub(base_asset_amount)?;
+
+            let price_delta = price_range
+                .safe_mul(level.cast()?)?
+                .safe_div(levels)?
+                .cast::<u64>()?;
+            let price = if self.end_price >= self.start_price {
+                self.start_price.safe_add(price_delta)?
+            } else {
+                self.start_price.safe_sub(price_delta)?
+            };
+
+            order_params.push(OrderParams {
+                order_type: OrderType::Limit,
+                market_type: MarketType::Perp,
+                direction: self.direction,
+                base_asset_amount,
+                price: standardize_price(price, tick_size, self.direction)?,
+                market_index: self.market_index,
+                reduce_only: self.reduce_only,
+                post_only: self.post_only,
+                ..OrderParams::default()
+            });
+        }
+
+        Ok(order_params)
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum SizeDistribution {
+    /// Every level gets the same size
+    Flat,
+    /// Level sizes grow linearly from the start price to the end price
+    Linear,
+    /// Each level is double the size of the one before it
+    Exponential,
+}
+
+impl Default for SizeDistribution {
+    fn default() -> Self {
+        SizeDistribution::Flat
+    }
+}
+
+impl SizeDistribution {
+    pub fn get_weight(&self, level: u32) -> DriftResult<u64> {
+        match self {
+            SizeDistribution::Flat => Ok(1),
+            SizeDistribution::Linear => level.safe_add(1)?.cast(),
+            SizeDistribution::Exponential => 2_u64.checked_pow(level).safe_unwrap(),
+        }
+    }
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Eq, PartialEq)]
 pub enum ModifyOrderPolicy {
     TryModify,
@@ -615,6 +786,7 @@ pub struct PlaceOrderOptions {
     pub enforce_margin_check: bool,
     pub risk_increasing: bool,
     pub explanation: OrderActionExplanation,
+    pub group_id: u32,
 }
 
 impl Default for PlaceOrderOptions {
@@ -624,6 +796,7 @@ impl Default for PlaceOrderOptions {
             enforce_margin_check: true,
             risk_increasing: false,
             explanation: OrderActionExplanation::None,
+            group_id: 0,
         }
     }
 }
@@ -637,4 +810,9 @@ impl PlaceOrderOptions {
         self.explanation = explanation;
         self
     }
+
+    pub fn group_id(mut self, group_id: u32) -> Self {
+        self.group_id = group_id;
+        self
+    }
 }
@@ -669,7 +669,9 @@ mod get_close_perp_params {
     use crate::state::oracle::HistoricalOracleData;
     use crate::state::order_params::PostOnlyParam;
     use crate::state::perp_market::{PerpMarket, AMM};
-    use crate::state::user::{Order, OrderStatus};
+    use crate::state::user::{
+        Order, OrderStatus, OrderType, SelfTradePreventionMode, TrailingStopType,
+    };
     use crate::test_utils::create_account_info;
     use crate::validation::order::validate_order;
     use crate::{
@@ -891,6 +893,49 @@ mod get_close_perp_params {
         validate_order(&order, &perp_market, Some(oracle_price), slot).unwrap();
     }
 
+    #[test]
+    fn batch_auction_market() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+        let slot = 1;
+        let amm = AMM {
+            last_ask_price_twap: 101 * PRICE_PRECISION_U64,
+            last_bid_price_twap: 99 * PRICE_PRECISION_U64,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap: 100 * PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            mark_std: PRICE_PRECISION_U64,
+            oracle_std: PRICE_PRECISION_U64,
+            ..AMM::default_test()
+        };
+        let perp_market = PerpMarket {
+            amm,
+            batch_auction_interval: 5,
+            ..PerpMarket::default()
+        };
+
+        let direction_to_close = PositionDirection::Long;
+        let base_asset_amount = BASE_PRECISION_U64;
+
+        let params =
+            OrderParams::get_close_perp_params(&perp_market, direction_to_close, base_asset_amount)
+                .unwrap();
+
+        assert_eq!(params.order_type, OrderType::Limit);
+        assert_eq!(params.auction_start_price, None);
+        assert_eq!(params.auction_end_price, None);
+        assert_eq!(params.auction_duration, None);
+        assert_eq!(
+            params.oracle_price_offset,
+            Some(2 * PRICE_PRECISION_I64 as i32)
+        );
+        assert!(params.reduce_only);
+
+        let order = get_order(&params, slot);
+
+        validate_order(&order, &perp_market, Some(oracle_price), slot).unwrap();
+    }
+
     #[test]
     fn btc() {
         let perp_market_str = String::from("Ct8MLGv1N/cV6vWLwJY+18dY2GsrmrNldgnISB7pmbcf7cn9S4FZ4OYt9si0qF/hpn20TcEt5dszD3rGa3LcZYr+3w9KQVtDd3+9kQoAAAAAAAAAAAAAAAEAAAAAAAAA2VkiggoAAAC/dZSICgAAACeqnmUAAAAAeCbW5P///////////////8J7Hv4BAAAAAAAAAAAAAAB7+rQtykoAAAAAAAAAAAAAAAAAAAAAAABlO/erzgEAAAAAAAAAAAAAVnP4srYEAAAAAAAAAAAAAJxiDwAAAAAAAAAAAAAAAAAy7nN6ywEAAAAAAAAAAAAA5ihcH9MBAAAAAAAAAAAAAK7izzLrAgAAAAAAAAAAAADs3G4NBAAAAAAAAAAAAAAAYIhJGrUEAAAAAAAAAAAAAKA0JMEnAAAAAAAAAAAAAADg/mJJ2f//////////////aJbnnAAAAAAAAAAAAAAAABidn20AAAAAAAAAAAAAAAAARCk1OgAAAAAAAAAAAAAA/U3ihP3//////////////0p/wecT+f////////////8elGWXkwYAAAAAAAAAAAAAbccyGPz4/////////////+ZmycPDBgAAAAAAAAAAAAAASI58awAAAAAAAAAAAAAArC2A7gAAAACsLYDuAAAAAKwtgO4AAAAApwxIKwEAAABrEoqhLAAAAAAAAAAAAAAAf+nRyBMAAAAAAAAAAAAAAIagdCkZAAAAAAAAAAAAAADQH9cHJgAAAAAAAAAAAAAAc132XBgAAAAAAAAAAAAAAATX1A4SAAAAAAAAAAAAAADSZHePVgcAAAAAAAAAAAAA99MFdFYHAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACE4MmozQEAAAAAAAAAAAAAHWZqWLkEAAAAAAAAAAAAACdJh8HOAQAAAAAAAAAAAADzKL56tgQAAAAAAAAAAAAAd3+9kQoAAAAAAAAAAAAAALJBWoMKAAAAJf9eiwoAAABroFyHCgAAAIv2go0KAAAAPT5dDgAAAAAEAgAAAAAAAAFRgdb/////MqOeZQAAAAAQDgAAAAAAAKCGAQAAAAAAoIYBAAAAAAAgoQcAAAAAAAAAAAAAAAAAscrx5+8FAACIP1dQJgAAAEGRyqEnAAAAJ6qeZQAAAABr7TAQAAAAAJ4lmw8AAAAAJ6qeZQAAAAAUAAAALEwAACARAABsAQAAKhoAAAAAAADcBTIAZMgAAYCLLeUAAAAAKHVdAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAFiluuwDJwEAAAAAAAAAAAAAAAAAAAAAAEJUQy1QRVJQICAgICAgICAgICAgICAgICAgICAgICAgWXIm/v////8AwusLAAAAAAB0O6QLAAAAvz8ZJAAAAACLqJ5lAAAAAADKmjsAAAAAAAAAAAAAAAAAAAAAAAAAAKcPDQAAAAAA8SQAAAAAAAC9AwAAAAAAAEAfAAAAAAAATB0AANQwAAD0AQAALAEAAAAAAAAQJwAApwUAABEJAAABAAEAAAAAALX/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==");
@@ -988,12 +1033,33 @@ mod get_close_perp_params {
             trigger_condition: params.trigger_condition,
             post_only: params.post_only != PostOnlyParam::None,
             oracle_price_offset: params.oracle_price_offset.unwrap_or(0),
-            immediate_or_cancel: params.immediate_or_cancel,
+            padding0: 0,
             auction_start_price: params.auction_start_price.unwrap_or(0),
             auction_end_price: params.auction_end_price.unwrap_or(0),
             auction_duration: params.auction_duration.unwrap_or(0),
             max_ts: 100,
-            padding: [0; 3],
+            twap_slices: params.twap_slices.unwrap_or(0),
+            twap_slice_interval: params.twap_slice_interval.unwrap_or(0),
+            group_id: 0,
+            trailing_stop_offset: params.trailing_stop_offset.unwrap_or(0),
+            trailing_stop_type: params.trailing_stop_type.unwrap_or(TrailingStopType::None),
+            self_trade_prevention_mode: params
+                .self_trade_prevention_mode
+                .unwrap_or(SelfTradePreventionMode::None),
+            time_in_force: params.get_time_in_force(),
+            close_position_percentage: params.close_position_percentage.unwrap_or(0),
+            trigger_price_source: params.trigger_price_source.unwrap_or_default(),
+            padding1: [0; 3],
+            iceberg_display_size: params.iceberg_display_size.unwrap_or(0),
+            iceberg_reserve_base_asset_amount: 0,
+            max_slot: params.max_slot.unwrap_or(0),
+            trigger_market_index: params.trigger_market.unwrap_or_default().market_index,
+            trigger_market_type: params.trigger_market.unwrap_or_default().market_type,
+            has_trigger_market: params.trigger_market.is_some(),
+            max_oracle_slippage_bps: params.max_oracle_slippage_bps.unwrap_or(0),
+            padding2: [0; 2],
+            client_order_id: params.client_order_id.unwrap_or(0),
+            padding: [0; 8],
         }
     }
 
@@ -1056,3 +1122,186 @@ mod get_close_perp_params {
         validate_order(&order, &perp_market, Some(oracle_price), slot).unwrap();
     }
 }
+
+mod get_time_in_force {
+    use crate::state::order_params::OrderParams;
+    use crate::state::user::TimeInForce;
+
+    #[test]
+    fn test() {
+        let params = OrderParams::default();
+        assert_eq!(params.get_time_in_force(), TimeInForce::GoodTilCanceled);
+
+        let params = OrderParams {
+            immediate_or_cancel: true,
+            ..OrderParams::default()
+        };
+        assert_eq!(params.get_time_in_force(), TimeInForce::ImmediateOrCancel);
+        assert!(params.is_immediate_or_cancel());
+
+        let params = OrderParams {
+            max_ts: Some(100),
+            ..OrderParams::default()
+        };
+        assert_eq!(params.get_time_in_force(), TimeInForce::GoodTilTimestamp);
+
+        let params = OrderParams {
+            max_ts: Some(0),
+            ..OrderParams::default()
+        };
+        assert_eq!(params.get_time_in_force(), TimeInForce::GoodTilCanceled);
+
+        let params = OrderParams {
+            time_in_force: Some(TimeInForce::FillOrKill),
+            ..OrderParams::default()
+        };
+        assert_eq!(params.get_time_in_force(), TimeInForce::FillOrKill);
+        assert!(params.is_immediate_or_cancel());
+    }
+}
+
+mod get_scale_order_params {
+    use crate::controller::position::PositionDirection;
+    use crate::state::order_params::{ScaleOrderParams, SizeDistribution};
+    use crate::state::user::OrderType;
+    use crate::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+    #[test]
+    fn flat() {
+        let params = ScaleOrderParams {
+            market_index: 0,
+            direction: PositionDirection::Long,
+            total_base_asset_amount: 10 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 90 * PRICE_PRECISION_U64,
+            order_count: 3,
+            size_distribution: SizeDistribution::Flat,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params
+            .get_order_params(PRICE_PRECISION_U64 / 100, BASE_PRECISION_U64 / 10)
+            .unwrap();
+
+        let prices: Vec<u64> = order_params.iter().map(|params| params.price).collect();
+        assert_eq!(
+            prices,
+            vec![
+                100 * PRICE_PRECISION_U64,
+                95 * PRICE_PRECISION_U64,
+                90 * PRICE_PRECISION_U64
+            ]
+        );
+
+        // rounding leftovers go to the last level
+        let sizes: Vec<u64> = order_params
+            .iter()
+            .map(|params| params.base_asset_amount)
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                33 * BASE_PRECISION_U64 / 10,
+                33 * BASE_PRECISION_U64 / 10,
+                34 * BASE_PRECISION_U64 / 10
+            ]
+        );
+
+        for params in order_params.iter() {
+            assert_eq!(params.order_type, OrderType::Limit);
+            assert_eq!(params.direction, PositionDirection::Long);
+        }
+    }
+
+    #[test]
+    fn linear() {
+        let params = ScaleOrderParams {
+            direction: PositionDirection::Short,
+            total_base_asset_amount: 10 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 103 * PRICE_PRECISION_U64,
+            order_count: 4,
+            size_distribution: SizeDistribution::Linear,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params
+            .get_order_params(PRICE_PRECISION_U64 / 100, BASE_PRECISION_U64 / 10)
+            .unwrap();
+
+        let prices: Vec<u64> = order_params.iter().map(|params| params.price).collect();
+        assert_eq!(
+            prices,
+            vec![
+                100 * PRICE_PRECISION_U64,
+                101 * PRICE_PRECISION_U64,
+                102 * PRICE_PRECISION_U64,
+                103 * PRICE_PRECISION_U64
+            ]
+        );
+
+        let sizes: Vec<u64> = order_params
+            .iter()
+            .map(|params| params.base_asset_amount)
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                BASE_PRECISION_U64,
+                2 * BASE_PRECISION_U64,
+                3 * BASE_PRECISION_U64,
+                4 * BASE_PRECISION_U64
+            ]
+        );
+    }
+
+    #[test]
+    fn exponential() {
+        let params = ScaleOrderParams {
+            direction: PositionDirection::Long,
+            total_base_asset_amount: 7 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 98 * PRICE_PRECISION_U64,
+            order_count: 3,
+            size_distribution: SizeDistribution::Exponential,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params
+            .get_order_params(PRICE_PRECISION_U64 / 100, BASE_PRECISION_U64 / 10)
+            .unwrap();
+
+        let sizes: Vec<u64> = order_params
+            .iter()
+            .map(|params| params.base_asset_amount)
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                BASE_PRECISION_U64,
+                2 * BASE_PRECISION_U64,
+                4 * BASE_PRECISION_U64
+            ]
+        );
+    }
+
+    #[test]
+    fn prices_rounded_to_tick_size() {
+        let params = ScaleOrderParams {
+            direction: PositionDirection::Long,
+            total_base_asset_amount: 2 * BASE_PRECISION_U64,
+            start_price: 100 * PRICE_PRECISION_U64,
+            end_price: 100 * PRICE_PRECISION_U64 - 15,
+            order_count: 2,
+            size_distribution: SizeDistribution::Flat,
+            ..ScaleOrderParams::default()
+        };
+
+        let order_params = params
+            .get_order_params(10, BASE_PRECISION_U64 / 10)
+            .unwrap();
+
+        // bids round down
+        assert_eq!(order_params[1].price, 100 * PRICE_PRECISION_U64 - 20);
+    }
+}
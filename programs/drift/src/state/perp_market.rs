@@ -13,8 +13,9 @@ use crate::math::constants::{
 use crate::math::constants::{
     AMM_RESERVE_PRECISION_I128, AMM_TO_QUOTE_PRECISION_RATIO, BID_ASK_SPREAD_PRECISION,
     BID_ASK_SPREAD_PRECISION_U128, DEFAULT_REVENUE_SINCE_LAST_FUNDING_SPREAD_RETREAT,
-    LP_FEE_SLICE_DENOMINATOR, LP_FEE_SLICE_NUMERATOR, MARGIN_PRECISION_U128, PERCENTAGE_PRECISION,
-    PERCENTAGE_PRECISION_I128, PRICE_PRECISION, SPOT_WEIGHT_PRECISION, TWENTY_FOUR_HOUR,
+    LP_FEE_SLICE_DENOMINATOR, LP_FEE_SLICE_NUMERATOR, MARGIN_PRECISION_U128, ONE_MINUTE,
+    PERCENTAGE_PRECISION, PERCENTAGE_PRECISION_I128, PRICE_PRECISION, SPOT_WEIGHT_PRECISION,
+    TWENTY_FOUR_HOUR,
 };
 use crate::math::helpers::get_proportion_i128;
 
@@ -95,6 +96,20 @@ impl Default for ContractType {
     }
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum MatchingPolicy {
+    /// makers are filled best price first, then in the order they're passed in
+    PriceTime,
+    /// makers at the same price split the fill in proportion to their size
+    ProRata,
+}
+
+impl Default for MatchingPolicy {
+    fn default() -> Self {
+        MatchingPolicy::PriceTime
+    }
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq, PartialOrd, Ord)]
 pub enum ContractTier {
     /// max insurance capped at A level
@@ -227,7 +242,24 @@ pub struct PerpMarket {
     /// E.g. if this is -50 and the fee is 5bps, the new fee will be 2.5bps
     /// if this is 50 and the fee is 5bps, the new fee will be 7.5bps
     pub fee_adjustment: i16,
-    pub padding: [u8; 46],
+    /// How a taker's fill is split between makers resting at the same price
+    pub matching_policy: MatchingPolicy,
+    pub padding1: [u8; 5],
+    /// The smallest size a maker is allocated under pro rata matching, if its order is at least that big
+    /// precision: BASE_PRECISION
+    pub pro_rata_min_allocation: u64,
+    /// The last slot a batch auction cleared. Only relevant for batch auction markets
+    pub last_batch_auction_slot: u64,
+    /// How many slots orders collect for before a batch auction can clear. Zero means the market
+    /// fills continuously
+    pub batch_auction_interval: u16,
+    /// The spot market on the same underlying whose opposite exposure offsets this market's margin
+    pub basis_spot_market_index: u16,
+    /// The fraction of the perp margin requirement waived for size hedged in the basis spot market.
+    /// Zero disables the offset
+    /// precision: MARGIN_PRECISION
+    pub basis_margin_offset: u16,
+    pub padding: [u8; 18],
 }
 
 impl Default for PerpMarket {
@@ -261,7 +293,14 @@ impl Default for PerpMarket {
             paused_operations: 0,
             quote_spot_market_index: 0,
             fee_adjustment: 0,
-            padding: [0; 46],
+            matching_policy: MatchingPolicy::default(),
+            padding1: [0; 5],
+            pro_rata_min_allocation: 0,
+            last_batch_auction_slot: 0,
+            batch_auction_interval: 0,
+            basis_spot_market_index: 0,
+            basis_margin_offset: 0,
+            padding: [0; 18],
         }
     }
 }
@@ -292,6 +331,19 @@ impl PerpMarket {
         PerpOperation::is_operation_paused(self.paused_operations, operation)
     }
 
+    pub fn is_batch_auction_market(&self) -> bool {
+        self.batch_auction_interval != 0
+    }
+
+    pub fn has_basis_spot_market(&self) -> bool {
+        self.basis_margin_offset != 0
+    }
+
+    pub fn get_next_batch_auction_slot(&self) -> DriftResult<u64> {
+        self.last_batch_auction_slot
+            .safe_add(self.batch_auction_interval.cast()?)
+    }
+
     pub fn has_too_much_drawdown(&self) -> DriftResult<bool> {
         let quote_drawdown_limit_breached = match self.contract_tier {
             ContractTier::A | ContractTier::B => {
@@ -802,7 +854,11 @@ pub struct AMM {
     pub net_unsettled_funding_pnl: i64,
     pub quote_asset_amount_with_unsettled_lp: i64,
     pub reference_price_offset: i32,
-    pub padding: [u8; 12],
+    pub padding: [u8; 4],
+    /// one minute twap of order and liquidation fill prices. fills in the same second as the last
+    /// trade don't move it, so a single small fill can't set the price
+    /// precision: PRICE_PRECISION
+    pub last_fill_price_twap: u64,
 }
 
 impl Default for AMM {
@@ -891,7 +947,8 @@ impl Default for AMM {
             net_unsettled_funding_pnl: 0,
             quote_asset_amount_with_unsettled_lp: 0,
             reference_price_offset: 0,
-            padding: [0; 12],
+            padding: [0; 4],
+            last_fill_price_twap: 0,
         }
     }
 }
@@ -1271,6 +1328,24 @@ impl AMM {
         Ok(())
     }
 
+    /// Must be called before update_volume_24h moves last_trade_ts
+    pub fn update_last_fill_price_twap(&mut self, fill_price: u64, now: i64) -> DriftResult {
+        self.last_fill_price_twap = if self.last_fill_price_twap == 0 {
+            fill_price
+        } else {
+            stats::calculate_new_twap(
+                fill_price.cast()?,
+                now,
+                self.last_fill_price_twap.cast()?,
+                self.last_trade_ts,
+                ONE_MINUTE.cast()?,
+            )?
+            .cast()?
+        };
+
+        Ok(())
+    }
+
     pub fn get_new_oracle_conf_pct(
         &self,
         confidence: u64,    // price precision
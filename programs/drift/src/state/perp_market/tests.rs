@@ -2,6 +2,7 @@ mod amm {
     use crate::state::perp_market::AMM;
     use crate::{
         AMM_RESERVE_PRECISION, BID_ASK_SPREAD_PRECISION, PEG_PRECISION, PRICE_PRECISION_I64,
+        PRICE_PRECISION_U64,
     };
 
     #[test]
@@ -39,4 +40,29 @@ mod amm {
 
         assert_eq!(discount, 10000000); // $1
     }
+
+    #[test]
+    fn update_last_fill_price_twap() {
+        let mut amm = AMM::default();
+
+        // first fill sets the price
+        amm.update_last_fill_price_twap(100 * PRICE_PRECISION_U64, 0)
+            .unwrap();
+        assert_eq!(amm.last_fill_price_twap, 100 * PRICE_PRECISION_U64);
+
+        // fills in the same second as the last trade don't move it
+        amm.update_last_fill_price_twap(50 * PRICE_PRECISION_U64, 0)
+            .unwrap();
+        assert_eq!(amm.last_fill_price_twap, 100 * PRICE_PRECISION_U64);
+
+        amm.update_last_fill_price_twap(70 * PRICE_PRECISION_U64, 30)
+            .unwrap();
+        assert_eq!(amm.last_fill_price_twap, 85 * PRICE_PRECISION_U64 + 1);
+
+        // a minute without trades all but replaces it
+        amm.last_trade_ts = 30;
+        amm.update_last_fill_price_twap(90 * PRICE_PRECISION_U64, 90)
+            .unwrap();
+        assert_eq!(amm.last_fill_price_twap, 89918032);
+    }
 }
@@ -19,6 +19,7 @@ use crate::math::spot_balance::{
     get_signed_token_amount, get_strict_token_value, get_token_amount, get_token_value,
 };
 use crate::math::stats::calculate_rolling_sum;
+use crate::state::margin_calculation::MarketIdentifier;
 use crate::state::oracle::StrictOraclePrice;
 use crate::state::perp_market::PerpMarket;
 use crate::state::spot_market::{SpotBalance, SpotBalanceType, SpotMarket};
@@ -46,9 +47,83 @@ pub enum UserStatus {
     AdvancedLp = 0b00001000,
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum PositionFlag {
+    IsolatedPosition = 0b00000001,
+    BeingLiquidated = 0b00000010,
+}
+
 // implement SIZE const for User
 impl Size for User {
-    const SIZE: usize = 4376;
+    const SIZE: usize = 7064;
+}
+
+/// Size of user accounts created before perp positions and orders grew
+pub const LEGACY_USER_SIZE: usize = 4376;
+const LEGACY_PERP_POSITION_SIZE: usize = 96;
+const LEGACY_ORDER_SIZE: usize = 96;
+/// discriminator, authority, delegate, name and spot positions are unchanged in the new layout
+const USER_HEADER_SIZE: usize = 424;
+/// last_add_perp_lp_shares_ts through has_open_auction
+const LEGACY_USER_STATE_SIZE: usize = 91;
+
+/// Rewrites a legacy user account's data into the current layout. Every field that didn't exist
+/// in the legacy layout starts zeroed
+pub fn migrate_legacy_user_data(legacy_data: &[u8], data: &mut [u8]) -> DriftResult {
+    validate!(
+        legacy_data.len() == LEGACY_USER_SIZE && data.len() == User::SIZE,
+        ErrorCode::InvalidUserMigration,
+        "can't migrate user data of size {} to size {}",
+        legacy_data.len(),
+        data.len()
+    )?;
+
+    let perp_positions_size = std::mem::size_of::<[PerpPosition; 8]>();
+    let orders_size = std::mem::size_of::<[Order; 32]>();
+
+    let legacy_orders_offset = USER_HEADER_SIZE + LEGACY_PERP_POSITION_SIZE * 8;
+    let legacy_state_offset = legacy_orders_offset + LEGACY_ORDER_SIZE * 32;
+    let orders_offset = USER_HEADER_SIZE + perp_positions_size;
+    let state_offset = orders_offset + orders_size;
+
+    data.fill(0);
+
+    data[..USER_HEADER_SIZE].copy_from_slice(&legacy_data[..USER_HEADER_SIZE]);
+
+    for (legacy_perp_position, perp_position) in legacy_data[USER_HEADER_SIZE..legacy_orders_offset]
+        .chunks_exact(LEGACY_PERP_POSITION_SIZE)
+        .zip(data[USER_HEADER_SIZE..orders_offset].chunks_exact_mut(perp_positions_size / 8))
+    {
+        perp_position[..LEGACY_PERP_POSITION_SIZE].copy_from_slice(legacy_perp_position);
+    }
+
+    for (legacy_order, order) in legacy_data[legacy_orders_offset..legacy_state_offset]
+        .chunks_exact(LEGACY_ORDER_SIZE)
+        .zip(data[orders_offset..state_offset].chunks_exact_mut(orders_size / 32))
+    {
+        let mut migrated_order: Order = bytemuck::Zeroable::zeroed();
+        bytemuck::bytes_of_mut(&mut migrated_order)[..LEGACY_ORDER_SIZE]
+            .copy_from_slice(legacy_order);
+
+        // legacy orders only had immediate_or_cancel and max_ts to say how long they rest. the
+        // immediate_or_cancel byte is padding in the current layout
+        migrated_order.time_in_force = if migrated_order.padding0 != 0 {
+            TimeInForce::ImmediateOrCancel
+        } else if migrated_order.max_ts != 0 {
+            TimeInForce::GoodTilTimestamp
+        } else {
+            TimeInForce::GoodTilCanceled
+        };
+        migrated_order.padding0 = 0;
+
+        order.copy_from_slice(bytemuck::bytes_of(&migrated_order));
+    }
+
+    data[state_offset..state_offset + LEGACY_USER_STATE_SIZE].copy_from_slice(
+        &legacy_data[legacy_state_offset..legacy_state_offset + LEGACY_USER_STATE_SIZE],
+    );
+
+    Ok(())
 }
 
 #[account(zero_copy(unsafe))]
@@ -116,7 +191,15 @@ pub struct User {
     pub open_auctions: u8,
     /// Whether or not user has open order with auction
     pub has_open_auction: bool,
-    pub padding: [u8; 21],
+    pub padding1: [u8; 5],
+    /// Once this slot passes without a heartbeat, any keeper can cancel all of the user's orders.
+    /// Zero means the heartbeat is disabled
+    pub cancel_after_slot: u64,
+    /// The nonce of the last off-chain signed order placed for the user.
+    /// Signed orders must use a greater nonce so they can't be replayed
+    pub last_signed_order_nonce: u64,
+    /// The user's market maker protection settings for perp markets
+    pub market_maker_protections: [MarketMakerProtection; 8],
 }
 
 impl User {
@@ -255,6 +338,15 @@ impl User {
             .ok_or(ErrorCode::OrderDoesNotExist)
     }
 
+    pub fn get_order_index_by_client_order_id(&self, client_order_id: u64) -> DriftResult<usize> {
+        self.orders
+            .iter()
+            .position(|order| {
+                order.client_order_id == client_order_id && order.status == OrderStatus::Open
+            })
+            .ok_or(ErrorCode::OrderDoesNotExist)
+    }
+
     pub fn get_order(&self, order_id: u32) -> Option<&Order> {
         self.orders.iter().find(|order| order.order_id == order_id)
     }
@@ -326,6 +418,40 @@ impl User {
         Ok(get_then_update_id!(self, next_liquidation_id))
     }
 
+    /// Isolated positions are liquidated on their own, leaving the rest of the account untouched
+    pub fn enter_isolated_perp_position_liquidation(
+        &mut self,
+        market_index: u16,
+        slot: u64,
+    ) -> DriftResult<u16> {
+        let perp_position = self.get_perp_position_mut(market_index)?;
+        if perp_position.is_being_liquidated() {
+            return self.next_liquidation_id.safe_sub(1);
+        }
+
+        perp_position.add_position_flag(PositionFlag::BeingLiquidated);
+        if !self.is_being_liquidated() {
+            self.liquidation_margin_freed = 0;
+            self.last_active_slot = slot;
+        }
+        Ok(get_then_update_id!(self, next_liquidation_id))
+    }
+
+    pub fn exit_isolated_perp_position_liquidation(&mut self, market_index: u16) {
+        if let Ok(perp_position) = self.get_perp_position_mut(market_index) {
+            perp_position.remove_position_flag(PositionFlag::BeingLiquidated);
+        }
+
+        if !self.is_being_liquidated() {
+            self.liquidation_margin_freed = 0;
+        }
+    }
+
+    pub fn is_isolated_perp_position_being_liquidated(&self, market_index: u16) -> bool {
+        self.get_perp_position(market_index)
+            .map_or(false, |perp_position| perp_position.is_being_liquidated())
+    }
+
     pub fn exit_liquidation(&mut self) {
         self.remove_user_status(UserStatus::BeingLiquidated);
         self.remove_user_status(UserStatus::Bankrupt);
@@ -419,6 +545,146 @@ impl User {
 
         false
     }
+
+    pub fn update_cancel_after_slot(&mut self, cancel_after_slots: u64, slot: u64) -> DriftResult {
+        self.cancel_after_slot = if cancel_after_slots == 0 {
+            0
+        } else {
+            slot.safe_add(cancel_after_slots)?
+        };
+
+        Ok(())
+    }
+
+    pub fn has_missed_heartbeat(&self, slot: u64) -> bool {
+        self.cancel_after_slot != 0 && slot > self.cancel_after_slot
+    }
+
+    pub fn get_market_maker_protection_index(&self, market_index: u16) -> Option<usize> {
+        self.market_maker_protections.iter().position(|protection| {
+            protection.is_enabled() && protection.market_index == market_index
+        })
+    }
+
+    pub fn update_market_maker_protection(
+        &mut self,
+        market_index: u16,
+        fill_limit_base_asset_amount: u64,
+        window_slots: u32,
+        cooldown_slots: u32,
+    ) -> DriftResult {
+        let protection_index = match self.get_market_maker_protection_index(market_index) {
+            Some(protection_index) => protection_index,
+            None => self
+                .market_maker_protections
+                .iter()
+                .position(|protection| !protection.is_enabled())
+                .ok_or(ErrorCode::MaxNumberOfMarketMakerProtections)?,
+        };
+
+        if fill_limit_base_asset_amount == 0 {
+            self.market_maker_protections[protection_index] = MarketMakerProtection::default();
+            return Ok(());
+        }
+
+        validate!(
+            window_slots > 0,
+            ErrorCode::DefaultError,
+            "market maker protection window must be greater than 0 slots"
+        )?;
+
+        validate!(
+            cooldown_slots > 0,
+            ErrorCode::DefaultError,
+            "market maker protection cooldown must be greater than 0 slots"
+        )?;
+
+        let protection = &mut self.market_maker_protections[protection_index];
+        protection.market_index = market_index;
+        protection.fill_limit_base_asset_amount = fill_limit_base_asset_amount;
+        protection.window_slots = window_slots;
+        protection.cooldown_slots = cooldown_slots;
+
+        Ok(())
+    }
+
+    /// Records a fill against a resting maker order. Returns whether the fill tripped the protection
+    pub fn record_market_maker_protection_fill(
+        &mut self,
+        market_index: u16,
+        base_asset_amount: u64,
+        slot: u64,
+    ) -> DriftResult<bool> {
+        match self.get_market_maker_protection_index(market_index) {
+            Some(protection_index) => {
+                self.market_maker_protections[protection_index].record_fill(base_asset_amount, slot)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn is_market_maker_protection_cooling_down(&self, market_index: u16, slot: u64) -> bool {
+        self.get_market_maker_protection_index(market_index)
+            .map_or(false, |protection_index| {
+                self.market_maker_protections[protection_index].is_cooling_down(slot)
+            })
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Default, Eq, PartialEq, Debug)]
+#[repr(C)]
+pub struct MarketMakerProtection {
+    /// The max base asset amount resting maker orders can be filled for within the window.
+    /// Zero means protection is disabled
+    /// precision: BASE_PRECISION
+    pub fill_limit_base_asset_amount: u64,
+    /// The base asset amount filled over the trailing window, decayed linearly with the slots
+    /// since the last fill
+    /// precision: BASE_PRECISION
+    pub window_base_asset_amount_filled: u64,
+    /// The slot of the last fill
+    pub last_fill_slot: u64,
+    /// Orders can't be placed in the market until this slot
+    pub cooldown_end_slot: u64,
+    /// How many slots the rolling fill window covers
+    pub window_slots: u32,
+    /// How many slots placement is frozen after the protection trips
+    pub cooldown_slots: u32,
+    /// The perp market index
+    pub market_index: u16,
+    pub padding: [u8; 6],
+}
+
+impl MarketMakerProtection {
+    pub fn is_enabled(&self) -> bool {
+        self.fill_limit_base_asset_amount != 0
+    }
+
+    pub fn is_cooling_down(&self, slot: u64) -> bool {
+        slot < self.cooldown_end_slot
+    }
+
+    pub fn record_fill(&mut self, base_asset_amount: u64, slot: u64) -> DriftResult<bool> {
+        let since_last = slot.safe_sub(self.last_fill_slot)?.cast::<i64>()?;
+
+        self.window_base_asset_amount_filled = calculate_rolling_sum(
+            self.window_base_asset_amount_filled,
+            base_asset_amount,
+            since_last,
+            self.window_slots.cast()?,
+        )?;
+        self.last_fill_slot = slot;
+
+        if self.window_base_asset_amount_filled <= self.fill_limit_base_asset_amount {
+            return Ok(false);
+        }
+
+        self.cooldown_end_slot = slot.safe_add(self.cooldown_slots.cast()?)?;
+        self.window_base_asset_amount_filled = 0;
+
+        Ok(true)
+    }
 }
 
 #[zero_copy(unsafe)]
@@ -756,6 +1022,18 @@ pub struct PerpPosition {
     /// The number of open orders
     pub open_orders: u8,
     pub per_lp_base: i8,
+    /// Keepers close the whole position once the oracle price reaches this price. Zero means unset
+    /// precision: PRICE_PRECISION
+    pub take_profit_price: u64,
+    /// Keepers close the whole position once the oracle price reaches this price. Zero means unset
+    /// precision: PRICE_PRECISION
+    pub stop_loss_price: u64,
+    /// The quote collateral backing an isolated position. Kept apart from the user's cross margin collateral
+    /// precision: SPOT_BALANCE_PRECISION
+    pub isolated_position_scaled_balance: u64,
+    /// Whether the position is isolated and whether it is being liquidated, see PositionFlag
+    pub position_flag: u8,
+    pub padding: [u8; 7],
 }
 
 impl PerpPosition {
@@ -768,6 +1046,7 @@ impl PerpPosition {
             && !self.has_open_order()
             && !self.has_unsettled_pnl()
             && !self.is_lp()
+            && self.isolated_position_scaled_balance == 0
     }
 
     pub fn is_open_position(&self) -> bool {
@@ -912,6 +1191,95 @@ impl PerpPosition {
         }
     }
 
+    pub fn is_isolated(&self) -> bool {
+        self.position_flag & (PositionFlag::IsolatedPosition as u8) > 0
+    }
+
+    pub fn is_being_liquidated(&self) -> bool {
+        self.position_flag & (PositionFlag::BeingLiquidated as u8) > 0
+    }
+
+    pub fn add_position_flag(&mut self, flag: PositionFlag) {
+        self.position_flag |= flag as u8;
+    }
+
+    pub fn remove_position_flag(&mut self, flag: PositionFlag) {
+        self.position_flag &= !(flag as u8);
+    }
+
+    pub fn get_isolated_token_amount(&self, spot_market: &SpotMarket) -> DriftResult<u128> {
+        get_token_amount(
+            self.isolated_position_scaled_balance.cast()?,
+            spot_market,
+            &SpotBalanceType::Deposit,
+        )
+    }
+
+    pub fn has_take_profit_stop_loss(&self) -> bool {
+        self.take_profit_price != 0 || self.stop_loss_price != 0
+    }
+
+    /// Zero clears the take profit or stop loss
+    pub fn update_take_profit_stop_loss(
+        &mut self,
+        take_profit_price: u64,
+        stop_loss_price: u64,
+    ) -> DriftResult {
+        validate!(
+            self.is_open_position() || (take_profit_price == 0 && stop_loss_price == 0),
+            ErrorCode::InvalidPositionTakeProfitStopLoss,
+            "Position must be open to set take profit or stop loss"
+        )?;
+
+        if take_profit_price != 0 && stop_loss_price != 0 {
+            let is_valid = match self.get_direction() {
+                PositionDirection::Long => take_profit_price > stop_loss_price,
+                PositionDirection::Short => take_profit_price < stop_loss_price,
+            };
+
+            validate!(
+                is_valid,
+                ErrorCode::InvalidPositionTakeProfitStopLoss,
+                "Take profit ({}) and stop loss ({}) on wrong sides for {:?} position",
+                take_profit_price,
+                stop_loss_price,
+                self.get_direction()
+            )?;
+        }
+
+        self.take_profit_price = take_profit_price;
+        self.stop_loss_price = stop_loss_price;
+
+        Ok(())
+    }
+
+    pub fn clear_take_profit_stop_loss(&mut self) {
+        self.take_profit_price = 0;
+        self.stop_loss_price = 0;
+    }
+
+    pub fn is_take_profit_triggered(&self, oracle_price: u64) -> bool {
+        if self.take_profit_price == 0 || !self.is_open_position() {
+            return false;
+        }
+
+        match self.get_direction() {
+            PositionDirection::Long => oracle_price >= self.take_profit_price,
+            PositionDirection::Short => oracle_price <= self.take_profit_price,
+        }
+    }
+
+    pub fn is_stop_loss_triggered(&self, oracle_price: u64) -> bool {
+        if self.stop_loss_price == 0 || !self.is_open_position() {
+            return false;
+        }
+
+        match self.get_direction() {
+            PositionDirection::Long => oracle_price <= self.stop_loss_price,
+            PositionDirection::Short => oracle_price >= self.stop_loss_price,
+        }
+    }
+
     pub fn get_unrealized_pnl(&self, oracle_price: i64) -> DriftResult<i128> {
         let (_, unrealized_pnl) =
             calculate_base_asset_value_and_pnl_with_oracle_price(self, oracle_price)?;
@@ -961,6 +1329,39 @@ impl PerpPosition {
     }
 }
 
+impl SpotBalance for PerpPosition {
+    fn market_index(&self) -> u16 {
+        QUOTE_SPOT_MARKET_INDEX
+    }
+
+    fn balance_type(&self) -> &SpotBalanceType {
+        &SpotBalanceType::Deposit
+    }
+
+    fn balance(&self) -> u128 {
+        self.isolated_position_scaled_balance as u128
+    }
+
+    fn increase_balance(&mut self, delta: u128) -> DriftResult {
+        self.isolated_position_scaled_balance = self
+            .isolated_position_scaled_balance
+            .safe_add(delta.cast()?)?;
+        Ok(())
+    }
+
+    fn decrease_balance(&mut self, delta: u128) -> DriftResult {
+        self.isolated_position_scaled_balance = self
+            .isolated_position_scaled_balance
+            .safe_sub(delta.cast()?)?;
+        Ok(())
+    }
+
+    fn update_balance_type(&mut self, _balance_type: SpotBalanceType) -> DriftResult {
+        msg!("isolated perp position collateral cannot be borrowed");
+        Err(ErrorCode::InvalidIsolatedPerpPosition)
+    }
+}
+
 #[cfg(test)]
 use crate::math::constants::{AMM_TO_QUOTE_PRECISION_RATIO_I128, PRICE_PRECISION_I128};
 #[cfg(test)]
@@ -1058,13 +1459,58 @@ pub struct Order {
     pub reduce_only: bool,
     /// Whether the order must be a maker
     pub post_only: bool,
-    /// Whether the order must be canceled the same slot it is placed
-    pub immediate_or_cancel: bool,
+    /// Deprecated in favor of time_in_force. Always zero
+    pub padding0: u8,
     /// Whether the order is triggered above or below the trigger price. Only relevant for trigger orders
     pub trigger_condition: OrderTriggerCondition,
     /// How many slots the auction lasts
     pub auction_duration: u8,
-    pub padding: [u8; 3],
+    /// The number of slices the order is split into. Only relevant for twap orders
+    pub twap_slices: u8,
+    /// How many slots between each slice being released. Only relevant for twap orders
+    pub twap_slice_interval: u16,
+    /// Orders sharing a non-zero group id are one-cancels-other. When one fills or triggers,
+    /// the other open orders in the group are canceled
+    pub group_id: u32,
+    /// How far the trigger price trails the oracle. Only relevant for trailing stops
+    /// precision: PRICE_PRECISION for fixed offsets, PERCENTAGE_PRECISION for percentage offsets
+    pub trailing_stop_offset: u32,
+    /// Whether the trigger price follows the oracle at a fixed or percentage offset
+    pub trailing_stop_type: TrailingStopType,
+    /// What happens when the order would match an order from the same authority
+    pub self_trade_prevention_mode: SelfTradePreventionMode,
+    /// How long the order rests on the book before it's canceled
+    pub time_in_force: TimeInForce,
+    /// The percentage of the user's position the order closes, sized at fill time. Zero means
+    /// the order uses its fixed base asset amount
+    pub close_position_percentage: u8,
+    /// The price trigger orders compare against their trigger price
+    pub trigger_price_source: TriggerPriceSource,
+    pub padding1: [u8; 3],
+    /// The size of each visible slice. Only relevant for iceberg orders
+    /// precision for perps: BASE_PRECISION
+    /// precision for spot: token mint precision
+    pub iceberg_display_size: u64,
+    /// The size left in reserve that hasn't been released into the order yet. Only relevant for iceberg orders
+    /// precision for perps: BASE_PRECISION
+    /// precision for spot: token mint precision
+    pub iceberg_reserve_base_asset_amount: u64,
+    /// The last slot the order can be filled. Only relevant for good til slot orders
+    pub max_slot: u64,
+    /// The market whose oracle the trigger condition is checked against. Only relevant for
+    /// cross market trigger orders
+    pub trigger_market_index: u16,
+    pub trigger_market_type: MarketType,
+    /// Whether the order triggers off another market's oracle instead of its own
+    pub has_trigger_market: bool,
+    /// The furthest the fill price can move past the oracle before the order stops filling as a taker.
+    /// Zero means only the market's price bands apply. Only relevant for perp orders
+    /// precision: basis points
+    pub max_oracle_slippage_bps: u16,
+    pub padding2: [u8; 2],
+    /// Client-defined id for the order. Zero means the order has no client order id
+    pub client_order_id: u64,
+    pub padding: [u8; 8],
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug)]
@@ -1089,7 +1535,11 @@ impl Order {
         slot: u64,
         tick_size: u64,
     ) -> DriftResult<Option<u64>> {
-        let price = if self.has_auction_price(self.slot, self.auction_duration, slot)? {
+        let price = if self.has_auction_price(
+            self.get_auction_start_slot(slot)?,
+            self.auction_duration,
+            slot,
+        )? {
             Some(calculate_auction_price(
                 self,
                 slot,
@@ -1152,11 +1602,143 @@ impl Order {
     pub fn has_limit_price(self, slot: u64) -> DriftResult<bool> {
         Ok(self.price > 0
             || self.has_oracle_price_offset()
-            || !is_auction_complete(self.slot, self.auction_duration, slot)?)
+            || !is_auction_complete(
+                self.get_auction_start_slot(slot)?,
+                self.auction_duration,
+                slot,
+            )?)
     }
 
     pub fn is_auction_complete(self, slot: u64) -> DriftResult<bool> {
-        is_auction_complete(self.slot, self.auction_duration, slot)
+        is_auction_complete(
+            self.get_auction_start_slot(slot)?,
+            self.auction_duration,
+            slot,
+        )
+    }
+
+    /// The slot the current auction started. Twap orders start a new auction for every slice
+    pub fn get_auction_start_slot(&self, slot: u64) -> DriftResult<u64> {
+        if self.order_type != OrderType::Twap {
+            return Ok(self.slot);
+        }
+
+        self.slot.safe_add(
+            self.get_twap_slice_index(slot)?
+                .safe_mul(self.twap_slice_interval.cast()?)?,
+        )
+    }
+
+    /// The index of the latest slice released for a twap order
+    pub fn get_twap_slice_index(&self, slot: u64) -> DriftResult<u64> {
+        let slots_elapsed = slot.saturating_sub(self.slot);
+
+        Ok(slots_elapsed
+            .safe_div(self.twap_slice_interval.cast()?)?
+            .min(self.twap_slices.saturating_sub(1).cast()?))
+    }
+
+    /// The cumulative base asset amount released by the twap order's slices
+    pub fn get_twap_base_asset_amount_released(
+        &self,
+        slot: u64,
+        step_size: u64,
+    ) -> DriftResult<u64> {
+        let slices_released = self.get_twap_slice_index(slot)?.safe_add(1)?;
+        let twap_slices = self.twap_slices.cast::<u64>()?;
+
+        if slices_released >= twap_slices {
+            return Ok(self.base_asset_amount);
+        }
+
+        let base_asset_amount_released = self
+            .base_asset_amount
+            .cast::<u128>()?
+            .safe_mul(slices_released.cast()?)?
+            .safe_div(twap_slices.cast()?)?
+            .cast::<u64>()?;
+
+        standardize_base_asset_amount(base_asset_amount_released, step_size)
+    }
+
+    /// Twap orders can only fill the slices that have been released
+    /// Returns None if the order isn't a twap order
+    pub fn get_twap_base_asset_amount_available(
+        &self,
+        slot: u64,
+        step_size: u64,
+    ) -> DriftResult<Option<u64>> {
+        if self.order_type != OrderType::Twap {
+            return Ok(None);
+        }
+
+        let base_asset_amount_released =
+            self.get_twap_base_asset_amount_released(slot, step_size)?;
+
+        Ok(Some(
+            base_asset_amount_released.saturating_sub(self.base_asset_amount_filled),
+        ))
+    }
+
+    pub fn is_fill_or_kill(&self) -> bool {
+        self.time_in_force == TimeInForce::FillOrKill
+    }
+
+    pub fn is_iceberg(&self) -> bool {
+        self.iceberg_display_size != 0
+    }
+
+    pub fn is_close_position_order(&self) -> bool {
+        self.close_position_percentage != 0
+    }
+
+    pub fn get_trigger_market(&self) -> Option<MarketIdentifier> {
+        if self.has_trigger_market {
+            Some(MarketIdentifier {
+                market_type: self.trigger_market_type,
+                market_index: self.trigger_market_index,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Moves everything past the first slice of an iceberg order into the reserve
+    pub fn hide_iceberg_reserve(&mut self) -> DriftResult {
+        if !self.is_iceberg() {
+            return Ok(());
+        }
+
+        let display_size = self.iceberg_display_size.min(self.base_asset_amount);
+        self.iceberg_reserve_base_asset_amount = self
+            .iceberg_reserve_base_asset_amount
+            .safe_add(self.base_asset_amount.safe_sub(display_size)?)?;
+        self.base_asset_amount = display_size;
+
+        Ok(())
+    }
+
+    /// Releases the next slice of an iceberg order from the reserve once the visible slice is filled.
+    /// The order's slot is reset so the new slice loses time priority
+    /// Returns whether a slice was released
+    pub fn release_iceberg_slice(&mut self, slot: u64) -> DriftResult<bool> {
+        if self.iceberg_reserve_base_asset_amount == 0
+            || self.get_base_asset_amount_unfilled(None)? != 0
+        {
+            return Ok(false);
+        }
+
+        let slice_base_asset_amount = self
+            .iceberg_display_size
+            .min(self.iceberg_reserve_base_asset_amount);
+
+        self.base_asset_amount = self.base_asset_amount.safe_add(slice_base_asset_amount)?;
+        self.iceberg_reserve_base_asset_amount = self
+            .iceberg_reserve_base_asset_amount
+            .safe_sub(slice_base_asset_amount)?;
+        self.slot = slot;
+
+        Ok(true)
     }
 
     pub fn has_auction(&self) -> bool {
@@ -1245,7 +1827,12 @@ impl Order {
     }
 
     pub fn is_jit_maker(&self) -> bool {
-        self.post_only && self.immediate_or_cancel
+        self.post_only && self.is_immediate_or_cancel()
+    }
+
+    /// Whether the order must be canceled the same slot it is placed
+    pub fn is_immediate_or_cancel(&self) -> bool {
+        self.time_in_force.is_immediate_or_cancel()
     }
 
     pub fn is_open_order_for_market(&self, market_index: u16, market_type: &MarketType) -> bool {
@@ -1266,7 +1853,7 @@ impl Order {
     pub fn is_market_order(&self) -> bool {
         matches!(
             self.order_type,
-            OrderType::Market | OrderType::TriggerMarket | OrderType::Oracle
+            OrderType::Market | OrderType::TriggerMarket | OrderType::Oracle | OrderType::Twap
         )
     }
 
@@ -1313,7 +1900,7 @@ impl Default for Order {
             direction: PositionDirection::Long,
             reduce_only: false,
             post_only: false,
-            immediate_or_cancel: false,
+            padding0: 0,
             trigger_price: 0,
             trigger_condition: OrderTriggerCondition::Above,
             oracle_price_offset: 0,
@@ -1321,7 +1908,26 @@ impl Default for Order {
             auction_end_price: 0,
             auction_duration: 0,
             max_ts: 0,
-            padding: [0; 3],
+            twap_slices: 0,
+            twap_slice_interval: 0,
+            group_id: 0,
+            trailing_stop_offset: 0,
+            trailing_stop_type: TrailingStopType::None,
+            self_trade_prevention_mode: SelfTradePreventionMode::None,
+            time_in_force: TimeInForce::GoodTilCanceled,
+            close_position_percentage: 0,
+            trigger_price_source: TriggerPriceSource::Oracle,
+            padding1: [0; 3],
+            iceberg_display_size: 0,
+            iceberg_reserve_base_asset_amount: 0,
+            max_slot: 0,
+            trigger_market_index: 0,
+            trigger_market_type: MarketType::Spot,
+            has_trigger_market: false,
+            max_oracle_slippage_bps: 0,
+            padding2: [0; 2],
+            client_order_id: 0,
+            padding: [0; 8],
         }
     }
 }
@@ -1346,6 +1952,9 @@ pub enum OrderType {
     TriggerLimit,
     /// Market order where the auction prices are oracle offsets
     Oracle,
+    /// Order split into slices that are released over time
+    /// Each slice is an auction where the prices are oracle offsets
+    Twap,
 }
 
 impl Default for OrderType {
@@ -1368,6 +1977,89 @@ impl Default for OrderTriggerCondition {
     }
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum TrailingStopType {
+    None,
+    /// Trigger price trails the trigger price source by a fixed price offset
+    Fixed,
+    /// Trigger price trails the trigger price source by a percentage of its price
+    Percentage,
+}
+
+impl Default for TrailingStopType {
+    fn default() -> Self {
+        TrailingStopType::None
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum TriggerPriceSource {
+    /// The market's oracle price
+    Oracle,
+    /// The amm's reserve price. Only supported for perp orders
+    AmmReservePrice,
+    /// The one minute twap of fill prices. Only supported for perp orders
+    LastFillPrice,
+    /// The 5 minute mark twap for perps, the 5 minute index twap for spot
+    MarkTwap5Min,
+}
+
+impl Default for TriggerPriceSource {
+    fn default() -> Self {
+        TriggerPriceSource::Oracle
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum SelfTradePreventionMode {
+    /// Orders from the same authority are allowed to match
+    None,
+    /// Cancel the resting maker order
+    CancelMaker,
+    /// Cancel the incoming taker order
+    CancelTaker,
+    /// Cancel both orders
+    CancelBoth,
+    /// Decrement the larger order by the size of the smaller order and cancel the smaller order
+    DecrementAndCancel,
+}
+
+impl Default for SelfTradePreventionMode {
+    fn default() -> Self {
+        SelfTradePreventionMode::None
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum TimeInForce {
+    /// Rests until filled or canceled. A max_ts still expires the order, market and oracle orders
+    /// get one by default
+    GoodTilCanceled,
+    /// Whatever can't be filled immediately is canceled
+    ImmediateOrCancel,
+    /// Must be filled in full immediately or the transaction fails
+    FillOrKill,
+    /// Expires after max_ts
+    GoodTilTimestamp,
+    /// Expires after max_slot
+    GoodTilSlot,
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        TimeInForce::GoodTilCanceled
+    }
+}
+
+impl TimeInForce {
+    pub fn is_immediate_or_cancel(&self) -> bool {
+        matches!(
+            self,
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+        )
+    }
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
 pub enum MarketType {
     Spot,
@@ -1940,3 +1940,558 @@ mod get_user_stats_age_ts {
         assert_eq!(age, 0);
     }
 }
+
+mod twap {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::state::user::{Order, OrderType};
+
+    #[test]
+    fn auction_start_slot() {
+        let order = Order {
+            order_type: OrderType::Twap,
+            slot: 10,
+            twap_slices: 4,
+            twap_slice_interval: 100,
+            ..Order::default()
+        };
+
+        assert_eq!(order.get_auction_start_slot(10).unwrap(), 10);
+        assert_eq!(order.get_auction_start_slot(109).unwrap(), 10);
+        assert_eq!(order.get_auction_start_slot(110).unwrap(), 110);
+        assert_eq!(order.get_auction_start_slot(350).unwrap(), 310);
+        // last slice doesnt restart auction
+        assert_eq!(order.get_auction_start_slot(1000).unwrap(), 310);
+
+        let order = Order {
+            order_type: OrderType::Oracle,
+            slot: 10,
+            ..Order::default()
+        };
+
+        assert_eq!(order.get_auction_start_slot(1000).unwrap(), 10);
+    }
+
+    #[test]
+    fn base_asset_amount_available() {
+        let step_size = BASE_PRECISION_U64 / 10;
+        let mut order = Order {
+            order_type: OrderType::Twap,
+            slot: 10,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            twap_slices: 3,
+            twap_slice_interval: 100,
+            ..Order::default()
+        };
+
+        assert_eq!(
+            order
+                .get_twap_base_asset_amount_available(10, step_size)
+                .unwrap(),
+            Some(3300000000)
+        );
+
+        order.base_asset_amount_filled = 3300000000;
+
+        assert_eq!(
+            order
+                .get_twap_base_asset_amount_available(109, step_size)
+                .unwrap(),
+            Some(0)
+        );
+
+        assert_eq!(
+            order
+                .get_twap_base_asset_amount_available(110, step_size)
+                .unwrap(),
+            Some(3300000000)
+        );
+
+        // last slice releases the remainder
+        assert_eq!(
+            order
+                .get_twap_base_asset_amount_available(210, step_size)
+                .unwrap(),
+            Some(6700000000)
+        );
+
+        let order = Order {
+            order_type: OrderType::Market,
+            base_asset_amount: 10 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert_eq!(
+            order
+                .get_twap_base_asset_amount_available(210, step_size)
+                .unwrap(),
+            None
+        );
+    }
+}
+
+mod migrate_legacy_user_data {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{BASE_PRECISION_I64, BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::state::traits::Size;
+    use crate::state::user::{
+        migrate_legacy_user_data, Order, OrderStatus, OrderType, PerpPosition, SpotPosition,
+        TimeInForce, User, LEGACY_USER_SIZE,
+    };
+    use crate::test_utils::get_anchor_account_bytes;
+    use anchor_lang::prelude::Pubkey;
+
+    #[test]
+    fn copies_legacy_fields() {
+        let mut user = User {
+            authority: Pubkey::new_unique(),
+            delegate: Pubkey::new_unique(),
+            name: [1; 32],
+            ..User::default()
+        };
+        user.spot_positions[0] = SpotPosition {
+            scaled_balance: 100,
+            open_orders: 1,
+            ..SpotPosition::default()
+        };
+        user.perp_positions[7] = PerpPosition {
+            market_index: 3,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -100,
+            open_orders: 1,
+            open_bids: BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+        user.orders[31] = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Long,
+            market_index: 3,
+            order_id: 9,
+            user_order_id: 2,
+            price: 100 * PRICE_PRECISION_U64,
+            base_asset_amount: BASE_PRECISION_U64,
+            auction_duration: 10,
+            ..Order::default()
+        };
+        // legacy immediate_or_cancel and max_ts map to a time in force
+        user.orders[29] = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Market,
+            market_index: 3,
+            order_id: 7,
+            base_asset_amount: BASE_PRECISION_U64,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            ..Order::default()
+        };
+        user.orders[30] = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            market_index: 3,
+            order_id: 8,
+            price: 100 * PRICE_PRECISION_U64,
+            base_asset_amount: BASE_PRECISION_U64,
+            max_ts: 100,
+            time_in_force: TimeInForce::GoodTilTimestamp,
+            ..Order::default()
+        };
+        user.next_order_id = 10;
+        user.last_active_slot = 7;
+        user.open_orders = 3;
+        user.has_open_order = true;
+        user.has_open_auction = true;
+
+        let expected_data = get_anchor_account_bytes(&mut user).to_vec();
+
+        // the legacy layout had 96 byte perp positions and orders and 21 bytes of padding at the end
+        let mut legacy_data = expected_data[..424].to_vec();
+        for i in 0..8 {
+            let offset = 424 + i * 128;
+            legacy_data.extend_from_slice(&expected_data[offset..offset + 96]);
+        }
+        for i in 0..32 {
+            let offset = 1448 + i * 160;
+            legacy_data.extend_from_slice(&expected_data[offset..offset + 96]);
+        }
+        legacy_data.extend_from_slice(&expected_data[6568..6659]);
+        legacy_data.extend_from_slice(&[0; 21]);
+        assert_eq!(legacy_data.len(), LEGACY_USER_SIZE);
+
+        // set the legacy immediate_or_cancel flag, 90 bytes into the order. it's padding now
+        legacy_data[424 + 8 * 96 + 29 * 96 + 90] = 1;
+
+        let mut data = vec![u8::MAX; User::SIZE];
+        migrate_legacy_user_data(&legacy_data, &mut data).unwrap();
+
+        assert_eq!(data, expected_data);
+    }
+
+    #[test]
+    fn invalid_size() {
+        let mut data = vec![0; User::SIZE];
+
+        let result = migrate_legacy_user_data(&vec![0; User::SIZE], &mut data);
+        assert_eq!(result, Err(ErrorCode::InvalidUserMigration));
+
+        let result = migrate_legacy_user_data(&vec![0; LEGACY_USER_SIZE], &mut data[..100]);
+        assert_eq!(result, Err(ErrorCode::InvalidUserMigration));
+    }
+}
+
+mod iceberg {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::state::user::{Order, OrderStatus, OrderType};
+
+    #[test]
+    fn hide_and_release_slices() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            slot: 1,
+            base_asset_amount: 5 * BASE_PRECISION_U64,
+            iceberg_display_size: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        order.hide_iceberg_reserve().unwrap();
+        assert_eq!(order.base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(
+            order.iceberg_reserve_base_asset_amount,
+            3 * BASE_PRECISION_U64
+        );
+
+        // visible slice partially filled, nothing released
+        order.base_asset_amount_filled = BASE_PRECISION_U64;
+        assert!(!order.release_iceberg_slice(10).unwrap());
+        assert_eq!(order.base_asset_amount, 2 * BASE_PRECISION_U64);
+        assert_eq!(order.slot, 1);
+
+        // visible slice filled, next slice released and loses time priority
+        order.base_asset_amount_filled = 2 * BASE_PRECISION_U64;
+        assert!(order.release_iceberg_slice(10).unwrap());
+        assert_eq!(order.base_asset_amount, 4 * BASE_PRECISION_U64);
+        assert_eq!(order.iceberg_reserve_base_asset_amount, BASE_PRECISION_U64);
+        assert_eq!(
+            order.get_base_asset_amount_unfilled(None).unwrap(),
+            2 * BASE_PRECISION_U64
+        );
+        assert_eq!(order.slot, 10);
+
+        // last slice is whatever is left in reserve
+        order.base_asset_amount_filled = 4 * BASE_PRECISION_U64;
+        assert!(order.release_iceberg_slice(20).unwrap());
+        assert_eq!(order.base_asset_amount, 5 * BASE_PRECISION_U64);
+        assert_eq!(order.iceberg_reserve_base_asset_amount, 0);
+
+        order.base_asset_amount_filled = 5 * BASE_PRECISION_U64;
+        assert!(!order.release_iceberg_slice(30).unwrap());
+        assert_eq!(order.get_base_asset_amount_unfilled(None).unwrap(), 0);
+    }
+
+    #[test]
+    fn not_iceberg() {
+        let mut order = Order {
+            status: OrderStatus::Open,
+            order_type: OrderType::Limit,
+            base_asset_amount: 5 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        order.hide_iceberg_reserve().unwrap();
+        assert_eq!(order.base_asset_amount, 5 * BASE_PRECISION_U64);
+        assert_eq!(order.iceberg_reserve_base_asset_amount, 0);
+
+        order.base_asset_amount_filled = 5 * BASE_PRECISION_U64;
+        assert!(!order.release_iceberg_slice(10).unwrap());
+    }
+}
+
+mod market_maker_protection {
+    use crate::error::ErrorCode;
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::state::user::{MarketMakerProtection, User};
+
+    #[test]
+    fn record_fill() {
+        let mut user = User::default();
+        user.update_market_maker_protection(1, 10 * BASE_PRECISION_U64, 10, 100)
+            .unwrap();
+
+        // no protection for other markets
+        assert!(!user
+            .record_market_maker_protection_fill(0, 100 * BASE_PRECISION_U64, 1)
+            .unwrap());
+
+        assert!(!user
+            .record_market_maker_protection_fill(1, 6 * BASE_PRECISION_U64, 1)
+            .unwrap());
+
+        // a full window later the earlier fill has decayed away
+        assert!(!user
+            .record_market_maker_protection_fill(1, 6 * BASE_PRECISION_U64, 11)
+            .unwrap());
+        assert_eq!(
+            user.market_maker_protections[0].window_base_asset_amount_filled,
+            6 * BASE_PRECISION_U64
+        );
+        assert!(!user.is_market_maker_protection_cooling_down(1, 12));
+
+        assert!(!user
+            .record_market_maker_protection_fill(1, 4 * BASE_PRECISION_U64, 20)
+            .unwrap());
+
+        // a fixed window starting at slot 11 would reset here, the rolling one still counts most
+        // of the fill at slot 20
+        assert!(user
+            .record_market_maker_protection_fill(1, 6 * BASE_PRECISION_U64, 21)
+            .unwrap());
+        assert!(user.is_market_maker_protection_cooling_down(1, 21));
+        assert!(user.is_market_maker_protection_cooling_down(1, 120));
+        assert!(!user.is_market_maker_protection_cooling_down(1, 121));
+        assert!(!user.is_market_maker_protection_cooling_down(0, 21));
+        assert_eq!(
+            user.market_maker_protections[0].window_base_asset_amount_filled,
+            0
+        );
+
+        // disabling clears the protection
+        user.update_market_maker_protection(1, 0, 0, 0).unwrap();
+        assert!(!user.is_market_maker_protection_cooling_down(1, 15));
+        assert_eq!(
+            user.market_maker_protections[0],
+            MarketMakerProtection::default()
+        );
+    }
+
+    #[test]
+    fn max_markets() {
+        let mut user = User::default();
+        for market_index in 0..8 {
+            user.update_market_maker_protection(market_index, BASE_PRECISION_U64, 10, 10)
+                .unwrap();
+        }
+
+        // updating an existing market doesn't need a new slot
+        user.update_market_maker_protection(3, 2 * BASE_PRECISION_U64, 10, 10)
+            .unwrap();
+        assert_eq!(
+            user.market_maker_protections[3].fill_limit_base_asset_amount,
+            2 * BASE_PRECISION_U64
+        );
+
+        assert_eq!(
+            user.update_market_maker_protection(8, BASE_PRECISION_U64, 10, 10),
+            Err(ErrorCode::MaxNumberOfMarketMakerProtections)
+        );
+    }
+
+    #[test]
+    fn invalid_window_or_cooldown() {
+        let mut user = User::default();
+
+        assert_eq!(
+            user.update_market_maker_protection(0, BASE_PRECISION_U64, 0, 10),
+            Err(ErrorCode::DefaultError)
+        );
+
+        assert_eq!(
+            user.update_market_maker_protection(0, BASE_PRECISION_U64, 10, 0),
+            Err(ErrorCode::DefaultError)
+        );
+
+        // disabling doesn't need a window or cooldown
+        user.update_market_maker_protection(0, 0, 0, 0).unwrap();
+    }
+}
+
+mod heartbeat {
+    use crate::state::user::User;
+
+    #[test]
+    fn missed_heartbeat() {
+        let mut user = User::default();
+        assert!(!user.has_missed_heartbeat(100));
+
+        user.update_cancel_after_slot(10, 100).unwrap();
+        assert_eq!(user.cancel_after_slot, 110);
+        assert!(!user.has_missed_heartbeat(110));
+        assert!(user.has_missed_heartbeat(111));
+
+        // heartbeat pushes the deadline back
+        user.update_cancel_after_slot(10, 105).unwrap();
+        assert!(!user.has_missed_heartbeat(111));
+
+        // zero disables
+        user.update_cancel_after_slot(0, 105).unwrap();
+        assert_eq!(user.cancel_after_slot, 0);
+        assert!(!user.has_missed_heartbeat(1000));
+    }
+}
+
+mod take_profit_stop_loss {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{BASE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::state::user::PerpPosition;
+
+    #[test]
+    fn long_position() {
+        let mut position = PerpPosition {
+            base_asset_amount: BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        let result = position
+            .update_take_profit_stop_loss(90 * PRICE_PRECISION_U64, 110 * PRICE_PRECISION_U64);
+        assert_eq!(result, Err(ErrorCode::InvalidPositionTakeProfitStopLoss));
+
+        position
+            .update_take_profit_stop_loss(110 * PRICE_PRECISION_U64, 90 * PRICE_PRECISION_U64)
+            .unwrap();
+
+        assert!(!position.is_take_profit_triggered(100 * PRICE_PRECISION_U64));
+        assert!(!position.is_stop_loss_triggered(100 * PRICE_PRECISION_U64));
+        assert!(position.is_take_profit_triggered(110 * PRICE_PRECISION_U64));
+        assert!(position.is_stop_loss_triggered(90 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn short_position() {
+        let mut position = PerpPosition {
+            base_asset_amount: -BASE_PRECISION_I64,
+            ..PerpPosition::default()
+        };
+
+        position
+            .update_take_profit_stop_loss(90 * PRICE_PRECISION_U64, 0)
+            .unwrap();
+
+        assert!(position.is_take_profit_triggered(89 * PRICE_PRECISION_U64));
+        assert!(!position.is_take_profit_triggered(91 * PRICE_PRECISION_U64));
+        assert!(!position.is_stop_loss_triggered(u64::MAX));
+    }
+
+    #[test]
+    fn no_position() {
+        let mut position = PerpPosition::default();
+
+        let result = position.update_take_profit_stop_loss(110 * PRICE_PRECISION_U64, 0);
+        assert_eq!(result, Err(ErrorCode::InvalidPositionTakeProfitStopLoss));
+
+        position.update_take_profit_stop_loss(0, 0).unwrap();
+    }
+}
+
+mod client_order_id {
+    use crate::state::user::{Order, OrderStatus, User};
+
+    #[test]
+    fn get_order_index_by_client_order_id() {
+        let mut user = User::default();
+        user.orders[1] = Order {
+            status: OrderStatus::Open,
+            client_order_id: u64::MAX,
+            ..Order::default()
+        };
+        user.orders[2] = Order {
+            status: OrderStatus::Canceled,
+            client_order_id: 5,
+            ..Order::default()
+        };
+
+        assert_eq!(user.get_order_index_by_client_order_id(u64::MAX), Ok(1));
+        assert!(user.get_order_index_by_client_order_id(5).is_err());
+    }
+}
+
+mod isolated_perp_position {
+    use crate::controller::spot_balance::update_spot_balances;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        QUOTE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
+    };
+    use crate::state::spot_market::{SpotBalanceType, SpotMarket};
+    use crate::state::user::{PerpPosition, PositionFlag, User};
+
+    #[test]
+    fn collateral_keeps_position_open() {
+        let mut user = User::default();
+        user.perp_positions[0] = PerpPosition {
+            market_index: 1,
+            isolated_position_scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            position_flag: PositionFlag::IsolatedPosition as u8,
+            ..PerpPosition::default()
+        };
+
+        assert!(!user.perp_positions[0].is_available());
+        assert!(user.get_perp_position(1).unwrap().is_isolated());
+    }
+
+    #[test]
+    fn collateral_cant_be_borrowed() {
+        let mut spot_market = SpotMarket {
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION_U64 as u128,
+            ..SpotMarket::default()
+        };
+        let mut perp_position = PerpPosition {
+            isolated_position_scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            position_flag: PositionFlag::IsolatedPosition as u8,
+            ..PerpPosition::default()
+        };
+
+        update_spot_balances(
+            40 * QUOTE_PRECISION,
+            &SpotBalanceType::Borrow,
+            &mut spot_market,
+            &mut perp_position,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            perp_position
+                .get_isolated_token_amount(&spot_market)
+                .unwrap(),
+            60 * QUOTE_PRECISION
+        );
+
+        let result = update_spot_balances(
+            61 * QUOTE_PRECISION,
+            &SpotBalanceType::Borrow,
+            &mut spot_market,
+            &mut perp_position,
+            false,
+        );
+        assert_eq!(result, Err(ErrorCode::InvalidIsolatedPerpPosition));
+    }
+
+    #[test]
+    fn isolated_liquidation_leaves_user_status() {
+        let mut user = User::default();
+        user.perp_positions[0] = PerpPosition {
+            market_index: 1,
+            base_asset_amount: 1,
+            position_flag: PositionFlag::IsolatedPosition as u8,
+            ..PerpPosition::default()
+        };
+        user.next_liquidation_id = 1;
+
+        let liquidation_id = user
+            .enter_isolated_perp_position_liquidation(1, 10)
+            .unwrap();
+        assert_eq!(liquidation_id, 1);
+        assert!(user.is_isolated_perp_position_being_liquidated(1));
+        assert!(!user.is_being_liquidated());
+        assert_eq!(user.last_active_slot, 10);
+
+        let liquidation_id = user
+            .enter_isolated_perp_position_liquidation(1, 20)
+            .unwrap();
+        assert_eq!(liquidation_id, 1);
+        assert_eq!(user.last_active_slot, 10);
+
+        user.exit_isolated_perp_position_liquidation(1);
+        assert!(!user.is_isolated_perp_position_being_liquidated(1));
+    }
+}
@@ -4,11 +4,17 @@ use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode};
 
 use crate::math::casting::Cast;
+use crate::math::constants::{MAX_OPEN_ORDERS, ONE_BPS_DENOMINATOR, PERCENTAGE_PRECISION_U64};
 use crate::math::orders::{
     calculate_base_asset_amount_to_fill_up_to_limit_price, is_multiple_of_step_size,
 };
+use crate::math::safe_math::SafeMath;
+use crate::state::order_params::{OrderParams, ScaleOrderParams};
 use crate::state::perp_market::PerpMarket;
-use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+use crate::state::user::{
+    MarketType, Order, OrderTriggerCondition, OrderType, TimeInForce, TrailingStopType,
+    TriggerPriceSource,
+};
 use crate::validate;
 
 pub fn validate_order(
@@ -35,8 +41,26 @@ pub fn validate_order(
         OrderType::Oracle => {
             validate_oracle_order(order, market.amm.order_step_size, market.amm.min_order_size)?
         }
+        OrderType::Twap => {
+            validate_twap_order(order, market.amm.order_step_size, market.amm.min_order_size)?
+        }
     }
 
+    validate_twap_params(order)?;
+    validate_trailing_stop_params(order)?;
+    validate_trigger_price_source(order)?;
+    validate_trigger_market(order)?;
+    validate_iceberg_params(order, market.amm.order_step_size, market.amm.min_order_size)?;
+    validate_time_in_force(order)?;
+    validate_close_position_params(order)?;
+
+    validate!(
+        order.max_oracle_slippage_bps.cast::<u32>()? < ONE_BPS_DENOMINATOR,
+        ErrorCode::InvalidMaxOracleSlippage,
+        "Max oracle slippage {} bps must be less than 100%",
+        order.max_oracle_slippage_bps
+    )?;
+
     Ok(())
 }
 
@@ -66,7 +90,7 @@ fn validate_market_order(order: &Order, step_size: u64, min_order_size: u64) ->
         return Err(ErrorCode::InvalidOrderOracleOffset);
     }
 
-    if order.immediate_or_cancel {
+    if order.is_immediate_or_cancel() {
         msg!("Market order can not be immediate or cancel");
         return Err(ErrorCode::InvalidOrderIOC);
     }
@@ -137,7 +161,7 @@ fn validate_oracle_order(order: &Order, step_size: u64, min_order_size: u64) ->
         return Err(ErrorCode::InvalidOrderLimitPrice);
     }
 
-    if order.immediate_or_cancel {
+    if order.is_immediate_or_cancel() {
         msg!("Oracle order can not be immediate or cancel");
         return Err(ErrorCode::InvalidOrderIOC);
     }
@@ -145,6 +169,246 @@ fn validate_oracle_order(order: &Order, step_size: u64, min_order_size: u64) ->
     Ok(())
 }
 
+fn validate_twap_order(order: &Order, step_size: u64, min_order_size: u64) -> DriftResult {
+    validate_oracle_order(order, step_size, min_order_size)?;
+
+    validate!(
+        order.twap_slices >= 2,
+        ErrorCode::InvalidTwapOrder,
+        "Twap order must have at least 2 slices"
+    )?;
+
+    validate!(
+        order.twap_slice_interval > order.auction_duration.cast()?,
+        ErrorCode::InvalidTwapOrder,
+        "Twap slice interval ({}) must be greater than auction duration ({})",
+        order.twap_slice_interval,
+        order.auction_duration
+    )?;
+
+    let slice_base_asset_amount = order
+        .base_asset_amount
+        .safe_div(order.twap_slices.cast()?)?;
+
+    validate!(
+        slice_base_asset_amount >= step_size,
+        ErrorCode::InvalidTwapOrder,
+        "Twap slice base asset amount ({}) < step size ({})",
+        slice_base_asset_amount,
+        step_size
+    )?;
+
+    validate!(
+        order.reduce_only || slice_base_asset_amount >= min_order_size,
+        ErrorCode::InvalidOrderMinOrderSize,
+        "Twap slice base asset amount ({}) < min_order_size ({})",
+        slice_base_asset_amount,
+        min_order_size
+    )?;
+
+    Ok(())
+}
+
+fn validate_twap_params(order: &Order) -> DriftResult {
+    if order.order_type != OrderType::Twap {
+        validate!(
+            order.twap_slices == 0 && order.twap_slice_interval == 0,
+            ErrorCode::InvalidTwapOrder,
+            "Only twap orders can have twap slices or slice interval"
+        )?;
+    }
+
+    Ok(())
+}
+
+fn validate_trailing_stop_params(order: &Order) -> DriftResult {
+    match order.trailing_stop_type {
+        TrailingStopType::None => {
+            validate!(
+                order.trailing_stop_offset == 0,
+                ErrorCode::InvalidTrailingStopOrder,
+                "Only trailing stops can have a trailing stop offset"
+            )?;
+        }
+        TrailingStopType::Fixed | TrailingStopType::Percentage => {
+            validate!(
+                order.must_be_triggered(),
+                ErrorCode::InvalidTrailingStopOrder,
+                "Trailing stop must be a trigger market or trigger limit order"
+            )?;
+
+            validate!(
+                order.trailing_stop_offset > 0,
+                ErrorCode::InvalidTrailingStopOrder,
+                "Trailing stop offset must be greater than 0"
+            )?;
+
+            if order.trailing_stop_type == TrailingStopType::Percentage {
+                validate!(
+                    order.trailing_stop_offset.cast::<u64>()? < PERCENTAGE_PRECISION_U64,
+                    ErrorCode::InvalidTrailingStopOrder,
+                    "Trailing stop percentage offset ({}) must be less than 100%",
+                    order.trailing_stop_offset
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_trigger_price_source(order: &Order) -> DriftResult {
+    validate!(
+        order.trigger_price_source == TriggerPriceSource::Oracle || order.must_be_triggered(),
+        ErrorCode::InvalidTriggerPriceSource,
+        "Only trigger orders can set a trigger price source"
+    )?;
+
+    Ok(())
+}
+
+fn validate_trigger_market(order: &Order) -> DriftResult {
+    let trigger_market = match order.get_trigger_market() {
+        Some(trigger_market) => trigger_market,
+        None => return Ok(()),
+    };
+
+    validate!(
+        order.must_be_triggered(),
+        ErrorCode::InvalidTriggerMarket,
+        "Only trigger orders can set a trigger market"
+    )?;
+
+    validate!(
+        trigger_market.market_type != order.market_type
+            || trigger_market.market_index != order.market_index,
+        ErrorCode::InvalidTriggerMarket,
+        "Trigger market must be different from the order's market"
+    )?;
+
+    // the trigger market's oracle is the only price checked, so the source and trailing
+    // options that depend on the order's own market don't apply
+    validate!(
+        order.trigger_price_source == TriggerPriceSource::Oracle,
+        ErrorCode::InvalidTriggerMarket,
+        "Cross market trigger orders must use the oracle trigger price source"
+    )?;
+
+    validate!(
+        order.trailing_stop_type == TrailingStopType::None,
+        ErrorCode::InvalidTriggerMarket,
+        "Cross market trigger orders can not be trailing stops"
+    )?;
+
+    Ok(())
+}
+
+fn validate_time_in_force(order: &Order) -> DriftResult {
+    match order.time_in_force {
+        TimeInForce::GoodTilTimestamp => {
+            validate!(
+                order.max_ts != 0,
+                ErrorCode::InvalidTimeInForce,
+                "Good til timestamp order must have a max ts"
+            )?;
+        }
+        TimeInForce::GoodTilSlot => {
+            validate!(
+                order.max_slot != 0,
+                ErrorCode::InvalidTimeInForce,
+                "Good til slot order must have a max slot"
+            )?;
+        }
+        TimeInForce::FillOrKill => {
+            validate!(
+                !order.post_only,
+                ErrorCode::InvalidTimeInForce,
+                "Fill or kill order can not be post only"
+            )?;
+        }
+        TimeInForce::GoodTilCanceled | TimeInForce::ImmediateOrCancel => {}
+    }
+
+    validate!(
+        order.time_in_force == TimeInForce::GoodTilSlot || order.max_slot == 0,
+        ErrorCode::InvalidTimeInForce,
+        "Only good til slot orders can have a max slot"
+    )?;
+
+    Ok(())
+}
+
+fn validate_close_position_params(order: &Order) -> DriftResult {
+    if !order.is_close_position_order() {
+        return Ok(());
+    }
+
+    validate!(
+        order.close_position_percentage <= 100,
+        ErrorCode::InvalidClosePositionOrder,
+        "Close position percentage ({}) must be at most 100",
+        order.close_position_percentage
+    )?;
+
+    validate!(
+        order.reduce_only,
+        ErrorCode::InvalidClosePositionOrder,
+        "Close position order must be reduce only"
+    )?;
+
+    validate!(
+        !order.is_iceberg() && order.order_type != OrderType::Twap,
+        ErrorCode::InvalidClosePositionOrder,
+        "Close position order can not be an iceberg or twap order"
+    )?;
+
+    Ok(())
+}
+
+fn validate_iceberg_params(order: &Order, step_size: u64, min_order_size: u64) -> DriftResult {
+    if !order.is_iceberg() {
+        return Ok(());
+    }
+
+    validate!(
+        order.order_type == OrderType::Limit,
+        ErrorCode::InvalidIcebergOrder,
+        "Iceberg order must be a limit order"
+    )?;
+
+    validate!(
+        !order.is_immediate_or_cancel(),
+        ErrorCode::InvalidIcebergOrder,
+        "Iceberg order can not be immediate or cancel"
+    )?;
+
+    validate!(
+        order.iceberg_display_size < order.base_asset_amount,
+        ErrorCode::InvalidIcebergOrder,
+        "Iceberg display size ({}) must be less than base asset amount ({})",
+        order.iceberg_display_size,
+        order.base_asset_amount
+    )?;
+
+    validate!(
+        is_multiple_of_step_size(order.iceberg_display_size, step_size)?,
+        ErrorCode::InvalidIcebergOrder,
+        "Iceberg display size ({}) not a multiple of step size ({})",
+        order.iceberg_display_size,
+        step_size
+    )?;
+
+    validate!(
+        order.reduce_only || order.iceberg_display_size >= min_order_size,
+        ErrorCode::InvalidOrderMinOrderSize,
+        "Iceberg display size ({}) < min_order_size ({})",
+        order.iceberg_display_size,
+        min_order_size
+    )?;
+
+    Ok(())
+}
+
 fn validate_limit_order(
     order: &Order,
     market: &PerpMarket,
@@ -436,8 +700,43 @@ pub fn validate_spot_order(order: &Order, step_size: u64, min_order_size: u64) -
         }
         OrderType::TriggerLimit => validate_trigger_limit_order(order, step_size, min_order_size)?,
         OrderType::Oracle => validate_oracle_order(order, step_size, min_order_size)?,
+        OrderType::Twap => validate_twap_order(order, step_size, min_order_size)?,
     }
 
+    validate_twap_params(order)?;
+    validate_trailing_stop_params(order)?;
+    validate_trigger_price_source(order)?;
+    validate_trigger_market(order)?;
+    validate_iceberg_params(order, step_size, min_order_size)?;
+    validate_time_in_force(order)?;
+
+    validate!(
+        order.max_oracle_slippage_bps == 0,
+        ErrorCode::InvalidMaxOracleSlippage,
+        "Spot orders can not set a max oracle slippage"
+    )?;
+
+    validate!(
+        matches!(
+            order.trigger_price_source,
+            TriggerPriceSource::Oracle | TriggerPriceSource::MarkTwap5Min
+        ),
+        ErrorCode::InvalidTriggerPriceSource,
+        "Spot trigger orders can only use the oracle or 5min mark twap"
+    )?;
+
+    validate!(
+        !order.is_fill_or_kill(),
+        ErrorCode::InvalidTimeInForce,
+        "Fill or kill is only supported for perp orders"
+    )?;
+
+    validate!(
+        !order.is_close_position_order(),
+        ErrorCode::InvalidClosePositionOrder,
+        "Close position orders are only supported for perp orders"
+    )?;
+
     Ok(())
 }
 
@@ -499,3 +798,84 @@ pub fn validate_order_for_force_reduce_only(order: &Order, existing_position: i6
 
     Ok(())
 }
+
+pub fn validate_bracket_order_params(
+    entry_params: Option<&OrderParams>,
+    take_profit_params: &OrderParams,
+    stop_loss_params: &OrderParams,
+) -> DriftResult {
+    let market_index = take_profit_params.market_index;
+    let exit_direction = take_profit_params.direction;
+
+    for params in entry_params
+        .into_iter()
+        .chain([take_profit_params, stop_loss_params])
+    {
+        validate!(
+            params.market_type == MarketType::Perp && params.market_index == market_index,
+            ErrorCode::InvalidBracketOrder,
+            "Bracket orders must all be for perp market {}",
+            market_index
+        )?;
+    }
+
+    validate!(
+        take_profit_params.reduce_only && stop_loss_params.reduce_only,
+        ErrorCode::InvalidBracketOrder,
+        "Take profit and stop loss must be reduce only"
+    )?;
+
+    validate!(
+        stop_loss_params.direction == exit_direction,
+        ErrorCode::InvalidBracketOrder,
+        "Take profit and stop loss must be in the same direction"
+    )?;
+
+    validate!(
+        matches!(
+            stop_loss_params.order_type,
+            OrderType::TriggerMarket | OrderType::TriggerLimit
+        ),
+        ErrorCode::InvalidBracketOrder,
+        "Stop loss must be a trigger order"
+    )?;
+
+    if let Some(entry_params) = entry_params {
+        validate!(
+            entry_params.direction == exit_direction.opposite(),
+            ErrorCode::InvalidBracketOrder,
+            "Take profit and stop loss must be opposite the entry direction"
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn validate_scale_order_params(params: &ScaleOrderParams) -> DriftResult {
+    validate!(
+        params.order_count >= 2 && params.order_count <= MAX_OPEN_ORDERS,
+        ErrorCode::InvalidScaleOrder,
+        "Scale orders must have between 2 and {} orders",
+        MAX_OPEN_ORDERS
+    )?;
+
+    validate!(
+        params.start_price != 0 && params.end_price != 0,
+        ErrorCode::InvalidScaleOrder,
+        "Scale order start and end price must be greater than 0"
+    )?;
+
+    validate!(
+        params.start_price != params.end_price,
+        ErrorCode::InvalidScaleOrder,
+        "Scale order start and end price must be different"
+    )?;
+
+    validate!(
+        params.total_base_asset_amount != 0,
+        ErrorCode::InvalidScaleOrder,
+        "Scale order total base asset amount must be greater than 0"
+    )?;
+
+    Ok(())
+}
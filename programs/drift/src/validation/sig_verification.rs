@@ -0,0 +1,125 @@
+use solana_program::ed25519_program;
+use solana_program::instruction::Instruction;
+use solana_program::msg;
+
+use crate::error::{DriftResult, ErrorCode};
+use crate::validate;
+
+#[cfg(test)]
+mod tests;
+
+const SIGNATURE_OFFSETS_START: usize = 2;
+const SIGNATURE_OFFSETS_SERIALIZED_SIZE: usize = 14;
+const PUBKEY_SERIALIZED_SIZE: usize = 32;
+const SIGNATURE_SERIALIZED_SIZE: usize = 64;
+
+/// Offsets into the ed25519 program instruction data, as laid out by the native program
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ed25519SignatureOffsets {
+    pub signature_offset: u16,
+    pub signature_instruction_index: u16,
+    pub public_key_offset: u16,
+    pub public_key_instruction_index: u16,
+    pub message_data_offset: u16,
+    pub message_data_size: u16,
+    pub message_instruction_index: u16,
+}
+
+impl Ed25519SignatureOffsets {
+    pub fn try_from_slice(data: &[u8]) -> DriftResult<Self> {
+        validate!(
+            data.len() >= SIGNATURE_OFFSETS_SERIALIZED_SIZE,
+            ErrorCode::SigVerificationFailed,
+            "ed25519 signature offsets too short"
+        )?;
+
+        let read_u16 = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+
+        Ok(Ed25519SignatureOffsets {
+            signature_offset: read_u16(0),
+            signature_instruction_index: read_u16(2),
+            public_key_offset: read_u16(4),
+            public_key_instruction_index: read_u16(6),
+            message_data_offset: read_u16(8),
+            message_data_size: read_u16(10),
+            message_instruction_index: read_u16(12),
+        })
+    }
+}
+
+/// Checks that the ed25519 program instruction verified a single signature by `pubkey`
+/// and returns the signed message.
+///
+/// The signature, public key and message must all live in the ed25519 instruction itself,
+/// otherwise the native program could have verified data other than what is read here
+pub fn get_ed25519_verified_message<'a>(
+    ix: &'a Instruction,
+    pubkey: &[u8; 32],
+) -> DriftResult<&'a [u8]> {
+    validate!(
+        ix.program_id == ed25519_program::id(),
+        ErrorCode::SigVerificationFailed,
+        "instruction is not for the ed25519 program"
+    )?;
+
+    validate!(
+        ix.accounts.is_empty(),
+        ErrorCode::SigVerificationFailed,
+        "ed25519 instruction must not have accounts"
+    )?;
+
+    let data = &ix.data;
+    validate!(
+        data.len() >= SIGNATURE_OFFSETS_START + SIGNATURE_OFFSETS_SERIALIZED_SIZE,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 instruction data too short"
+    )?;
+
+    validate!(
+        data[0] == 1,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 instruction must verify exactly one signature"
+    )?;
+
+    let offsets = Ed25519SignatureOffsets::try_from_slice(
+        &data[SIGNATURE_OFFSETS_START..SIGNATURE_OFFSETS_START + SIGNATURE_OFFSETS_SERIALIZED_SIZE],
+    )?;
+
+    validate!(
+        offsets.signature_instruction_index == u16::MAX
+            && offsets.public_key_instruction_index == u16::MAX
+            && offsets.message_instruction_index == u16::MAX,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 signature, public key and message must be in the ed25519 instruction"
+    )?;
+
+    let signature_offset = offsets.signature_offset as usize;
+    validate!(
+        data.len() >= signature_offset + SIGNATURE_SERIALIZED_SIZE,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 signature out of bounds"
+    )?;
+
+    let public_key_offset = offsets.public_key_offset as usize;
+    validate!(
+        data.len() >= public_key_offset + PUBKEY_SERIALIZED_SIZE,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 public key out of bounds"
+    )?;
+
+    validate!(
+        &data[public_key_offset..public_key_offset + PUBKEY_SERIALIZED_SIZE] == pubkey,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 public key does not match signer"
+    )?;
+
+    let message_data_offset = offsets.message_data_offset as usize;
+    let message_data_end = message_data_offset + offsets.message_data_size as usize;
+    validate!(
+        data.len() >= message_data_end,
+        ErrorCode::SigVerificationFailed,
+        "ed25519 message out of bounds"
+    )?;
+
+    Ok(&data[message_data_offset..message_data_end])
+}
@@ -0,0 +1,94 @@
+mod get_ed25519_verified_message {
+    use solana_program::ed25519_program;
+    use solana_program::instruction::Instruction;
+    use solana_program::pubkey::Pubkey;
+
+    use crate::error::ErrorCode;
+    use crate::validation::sig_verification::get_ed25519_verified_message;
+
+    fn get_ed25519_ix(pubkey: &[u8; 32], message: &[u8], instruction_index: u16) -> Instruction {
+        let public_key_offset: u16 = 16;
+        let signature_offset: u16 = public_key_offset + 32;
+        let message_data_offset: u16 = signature_offset + 64;
+
+        let mut data = vec![1_u8, 0];
+        for value in [
+            signature_offset,
+            instruction_index,
+            public_key_offset,
+            instruction_index,
+            message_data_offset,
+            message.len() as u16,
+            instruction_index,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(pubkey);
+        data.extend_from_slice(&[7; 64]);
+        data.extend_from_slice(message);
+
+        Instruction {
+            program_id: ed25519_program::id(),
+            accounts: vec![],
+            data,
+        }
+    }
+
+    #[test]
+    fn valid_message() {
+        let pubkey = Pubkey::new_unique().to_bytes();
+        let message = [1_u8, 2, 3, 4];
+        let ix = get_ed25519_ix(&pubkey, &message, u16::MAX);
+
+        let verified_message = get_ed25519_verified_message(&ix, &pubkey).unwrap();
+
+        assert_eq!(verified_message, &message);
+    }
+
+    #[test]
+    fn wrong_pubkey() {
+        let pubkey = Pubkey::new_unique().to_bytes();
+        let message = [1_u8, 2, 3, 4];
+        let ix = get_ed25519_ix(&pubkey, &message, u16::MAX);
+
+        let other_pubkey = Pubkey::new_unique().to_bytes();
+        let result = get_ed25519_verified_message(&ix, &other_pubkey);
+
+        assert_eq!(result, Err(ErrorCode::SigVerificationFailed));
+    }
+
+    #[test]
+    fn data_in_other_instruction() {
+        let pubkey = Pubkey::new_unique().to_bytes();
+        let message = [1_u8, 2, 3, 4];
+        let ix = get_ed25519_ix(&pubkey, &message, 0);
+
+        let result = get_ed25519_verified_message(&ix, &pubkey);
+
+        assert_eq!(result, Err(ErrorCode::SigVerificationFailed));
+    }
+
+    #[test]
+    fn wrong_program() {
+        let pubkey = Pubkey::new_unique().to_bytes();
+        let message = [1_u8, 2, 3, 4];
+        let mut ix = get_ed25519_ix(&pubkey, &message, u16::MAX);
+        ix.program_id = Pubkey::new_unique();
+
+        let result = get_ed25519_verified_message(&ix, &pubkey);
+
+        assert_eq!(result, Err(ErrorCode::SigVerificationFailed));
+    }
+
+    #[test]
+    fn message_out_of_bounds() {
+        let pubkey = Pubkey::new_unique().to_bytes();
+        let message = [1_u8, 2, 3, 4];
+        let mut ix = get_ed25519_ix(&pubkey, &message, u16::MAX);
+        ix.data.truncate(ix.data.len() - 1);
+
+        let result = get_ed25519_verified_message(&ix, &pubkey);
+
+        assert_eq!(result, Err(ErrorCode::SigVerificationFailed));
+    }
+}